@@ -0,0 +1,348 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::channel::oneshot;
+use log::{info, warn};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{queue::QueueOrder, relative_to, send_message, Broker, InternalToExternal};
+use crate::{control::{ControlCommand, ControlResult}, folder::{FolderMode, SharedFolder}, io::hash::HashAlgorithm, limit::limiter, metrics::metrics, peer::{Command, Peer, PeerMessage}};
+
+impl Broker {
+    ///
+    /// Answers a request of the control API
+    /// # Arguments
+    /// * `command` - The request
+    /// * `reply` - Gets the answer, once the work is done for requests that run in a task
+    ///
+    pub(super) async fn handle_control(&self, command: ControlCommand, reply: oneshot::Sender<ControlResult>, peers: &mut HashMap<String, Peer>) {
+        let result = match command {
+            ControlCommand::Test { message } => self.send_test(message, peers).await,
+            ControlCommand::Status => Ok(json!({
+                "id": self.my_peer_id,
+                "paused": *self.paused.lock().await,
+                "folders": self.folders.read().await.len(),
+                "peers": peers.len(),
+                "transfers": self.transfers.lock().await.len(),
+                "queued": self.queue.lock().await.len(),
+                "conflicts": self.conflicts.lock().await.len(),
+                "drift": self.drift.lock().await.len(),
+                "errors": self.errors.lock().await.len(),
+                "compression": metrics().compression(),
+            })),
+            ControlCommand::Id => Ok(json!({ "id": self.my_peer_id })),
+            ControlCommand::Peers => {
+                let rates = self.rates.lock().await;
+                let peers: Vec<_> = peers
+                    .values()
+                    .map(|peer| {
+                        json!({
+                            "peer_id": peer.peer_id,
+                            "address": peer.address,
+                            "port": peer.port,
+                            "download_rate": rates.get(&peer.peer_id).map(|rate| rate as u64),
+                        })
+                    })
+                    .collect();
+                Ok(json!(peers))
+            }
+            ControlCommand::Folders => {
+                let paused = *self.paused.lock().await;
+                let mut folders = vec![];
+                for folder_id in self.folder_ids().await {
+                    let folder = match self.folder(&folder_id).await {
+                        Some(folder) => folder,
+                        None => continue,
+                    };
+                    let transfers = self.transfers.lock().await.keys().filter(|(transfer_folder, _)| *transfer_folder == folder_id).count();
+                    folders.push(json!({
+                        "id": folder_id,
+                        "path": folder.root(),
+                        "peers": folder.config.peers,
+                        "mode": folder.mode(),
+                        "hash": folder.config.hash,
+                        "selection": folder.selection.read().await.clone(),
+                        "placeholders": folder.config.placeholders,
+                        "files": folder.index.lock().await.len(),
+                        "paused": paused,
+                        "transfers": transfers,
+                    }));
+                }
+                Ok(json!(folders))
+            }
+            ControlCommand::Transfers => {
+                let downloads = self.downloads.lock().await;
+                let transfers: Vec<_> = self
+                    .transfers
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|(key, transfer)| {
+                        let sources = match downloads.get(key) {
+                            Some(download) => download.sources(),
+                            None => vec![transfer.peer_id.clone()],
+                        };
+                        let mut transfer = json!(transfer);
+                        transfer["sources"] = json!(sources);
+                        transfer
+                    })
+                    .collect();
+                Ok(json!(transfers))
+            }
+            ControlCommand::Conflicts => Ok(json!(*self.conflicts.lock().await)),
+            ControlCommand::Drift => Ok(json!(self.drift.lock().await.values().collect::<Vec<_>>())),
+            ControlCommand::Revert { folder } => self.revert_folders(folder, peers).await,
+            ControlCommand::Errors => Ok(json!(*self.errors.lock().await)),
+            // Answered once the folders are hashed, without holding up the broker
+            ControlCommand::Rescan { folder } => return self.rescan_folders(folder, reply).await,
+            ControlCommand::Pause => {
+                *self.paused.lock().await = true;
+                info!("Sync paused");
+                Ok(json!({ "paused": true }))
+            }
+            ControlCommand::Resume => {
+                *self.paused.lock().await = false;
+                info!("Sync resumed");
+                Ok(json!({ "paused": false }))
+            }
+            ControlCommand::Limits => Ok(json!(limiter().limits())),
+            ControlCommand::SetLimits { peer, upload, download, lan_unlimited } => self.set_limits(peer, upload, download, lan_unlimited).await,
+            ControlCommand::Queue => {
+                let queue_config = self.config.lock().await.queue;
+                let queue = self.queue.lock().await;
+                Ok(json!({
+                    "max_concurrent": queue_config.max_concurrent,
+                    "order": queue_config.order,
+                    "files": queue.files(queue_config.order),
+                }))
+            }
+            ControlCommand::BumpTransfer { folder, path } => self.bump(folder, path).await,
+            ControlCommand::SetQueue { max_concurrent, order } => self.set_queue(max_concurrent, order).await,
+            ControlCommand::Versions { folder, path } => self.versions(folder, path).await,
+            ControlCommand::RestoreVersion { folder, version } => self.restore_version(folder, version, peers).await,
+            ControlCommand::AddFolder { path, id, peers: folder_peers, mode, hash, placeholders } => {
+                self.add_folder(path, id, folder_peers, mode, hash, placeholders, peers).await
+            }
+            ControlCommand::SelectPaths { folder, include, exclude } => self.select(folder, include, exclude, peers).await,
+            ControlCommand::Fetch { folder, path } => self.fetch(folder, path, peers).await,
+            ControlCommand::RemoveFolder { folder } => self.remove_folder(folder).await,
+            ControlCommand::DisconnectPeer { peer_id } => self.disconnect(peer_id, peers).await,
+        };
+        let _ = reply.send(result);
+    }
+
+    ///
+    /// Shares a new folder, saves the config and starts watching it. The startup rescan of the
+    /// watcher announces the files already in it.
+    /// # Arguments
+    /// * `path` - Folder to share
+    /// * `id` - Id the peers know the folder by, the name of the directory when None
+    /// * `peers` - Peers to share the folder with, every peer when empty
+    /// * `mode` - Which way changes flow
+    /// * `hash` - How files are hashed
+    /// * `placeholders` - Files of the peers are created empty and downloaded once fetched
+    /// * `connected` - The connected peers, told how the new folder is hashed
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn add_folder(
+        &self,
+        path: String,
+        id: Option<String>,
+        peers: Vec<String>,
+        mode: FolderMode,
+        hash: HashAlgorithm,
+        placeholders: bool,
+        connected: &HashMap<String, Peer>,
+    ) -> ControlResult {
+        let mut config = self.config.lock().await;
+        let folder_config = config.add_folder(&path, id, peers, mode, hash, placeholders).await?;
+        if let Err(err) = config.save(&self.config_path).await {
+            config.folders.pop();
+            return Err(format!("Cannot save {} {}", self.config_path, err));
+        }
+        drop(config);
+        info!("Sharing {} as {} with {:?}, {:?}", folder_config.path, folder_config.id, folder_config.peers, folder_config.mode);
+        let folder = Arc::new(SharedFolder::open(folder_config.clone()).await);
+        self.folders.write().await.insert(folder_config.id.clone(), folder.clone());
+        for peer in connected.values().filter(|peer| folder_config.is_shared_with(&peer.peer_id)) {
+            self.send_hashes(peer).await;
+        }
+        self.start_watching(folder).await;
+        Ok(json!(folder_config))
+    }
+
+    ///
+    /// Stops sharing a folder, the files in it are left alone
+    /// # Arguments
+    /// * `folder` - Id or path of the folder
+    ///
+    async fn remove_folder(&self, folder: String) -> ControlResult {
+        let mut config = self.config.lock().await;
+        let folder_config = config.folder(&folder).cloned().ok_or(format!("Unknown folder {}", folder))?;
+        config.folders.retain(|folder| folder.id != folder_config.id);
+        if let Err(err) = config.save(&self.config_path).await {
+            config.folders.push(folder_config);
+            return Err(format!("Cannot save {} {}", self.config_path, err));
+        }
+        drop(config);
+        let folder_id = folder_config.id;
+        self.watchers.lock().await.remove(&folder_id);
+        if let Some(folder) = self.folders.write().await.remove(&folder_id) {
+            if let Err(err) = folder.save_state().await {
+                warn!("Cannot save the state of {} {}", folder.root(), err);
+            }
+        }
+        self.transfers.lock().await.retain(|(transfer_folder, _), _| *transfer_folder != folder_id);
+        self.downloads.lock().await.retain(|(download_folder, _), _| *download_folder != folder_id);
+        self.queue.lock().await.remove_folder(&folder_id);
+        self.files_in_update.lock().await.retain(|(update_folder, _), _| *update_folder != folder_id);
+        self.drift.lock().await.retain(|(drift_folder, _), _| *drift_folder != folder_id);
+        self.sources.lock().await.retain(|(source_folder, _), _| *source_folder != folder_id);
+        self.placeholder_peers.lock().await.retain(|(holder_folder, _), _| *holder_folder != folder_id);
+        info!("Stopped sharing {} ({})", folder_id, folder_config.path);
+        Ok(json!({ "id": folder_id, "path": folder_config.path }))
+    }
+
+    /// Broadcasts a `Command::Test` to every connected peer
+    async fn send_test(&self, message: String, peers: &HashMap<String, Peer>) -> ControlResult {
+        let command = PeerMessage::PeerCommand {
+            command: Command::Test {
+                id: Uuid::new_v4(),
+                peer_id: self.my_peer_id.clone(),
+                message,
+            },
+        };
+        let command_json = serde_json::to_string(&command).map_err(|err| err.to_string())?;
+        for peer in peers.values() {
+            send_message(peer, command_json.clone()).await;
+        }
+        Ok(json!({ "peers": peers.len() }))
+    }
+
+    /// Reverts the local changes of a receive only folder, or of every one when `folder` is None
+    async fn revert_folders(&self, folder: Option<String>, peers: &HashMap<String, Peer>) -> ControlResult {
+        if *self.paused.lock().await {
+            return Err(String::from("Sync is paused"));
+        }
+        let reverted = self.revert(folder, peers).await?;
+        Ok(json!({ "reverted": reverted }))
+    }
+
+    ///
+    /// Changes bandwidth limits and saves them in the config
+    /// # Arguments
+    /// * `peer` - Peer to limit, `*` for every peer without its own limits, the global limits when None
+    /// * `upload` - Bytes per second, unchanged when None, 0 lifts the limit
+    /// * `download` - Bytes per second, unchanged when None, 0 lifts the limit
+    /// * `lan_unlimited` - Lifts every limit for peers on a private network, unchanged when None
+    ///
+    async fn set_limits(&self, peer: Option<String>, upload: Option<u64>, download: Option<u64>, lan_unlimited: Option<bool>) -> ControlResult {
+        let mut config = self.config.lock().await;
+        let mut limits = config.limits.clone();
+        if upload.is_some() || download.is_some() {
+            match peer {
+                // A peer limited by `*` so far keeps that limit in the direction not given
+                Some(peer) => {
+                    let rates = limits.peer(&peer).changed(upload, download);
+                    limits.peers.insert(peer, rates);
+                }
+                None => limits.global = limits.global.changed(upload, download),
+            }
+        }
+        if let Some(lan_unlimited) = lan_unlimited {
+            limits.lan_unlimited = lan_unlimited;
+        }
+        let previous = std::mem::replace(&mut config.limits, limits.clone());
+        if let Err(err) = config.save(&self.config_path).await {
+            config.limits = previous;
+            return Err(format!("Cannot save {} {}", self.config_path, err));
+        }
+        info!("Bandwidth limits changed to {:?}", limits);
+        limiter().set_limits(limits.clone());
+        Ok(json!(limits))
+    }
+
+    /// Downloads a queued file next, looked up in every folder when `folder` is None
+    async fn bump(&self, folder: Option<String>, path: String) -> ControlResult {
+        let mut bumped = vec![];
+        for folder_id in self.resolve_folders(folder).await? {
+            let folder = match self.folder(&folder_id).await {
+                Some(folder) => folder,
+                None => continue,
+            };
+            let key = (folder_id, relative_to(&folder, &path));
+            if self.queue.lock().await.bump(&key) {
+                info!("Downloading {} in {} next", key.1, key.0);
+                bumped.push(json!({ "folder_id": key.0, "file_path": key.1 }));
+            } else if self.transfers.lock().await.contains_key(&key) {
+                return Err(format!("{} in {} is already being downloaded", key.1, key.0));
+            }
+        }
+        if bumped.is_empty() {
+            return Err(format!("{} is not queued", path));
+        }
+        Ok(json!(bumped))
+    }
+
+    /// Changes how many files are downloaded at once and in which order, saved in the config
+    async fn set_queue(&self, max_concurrent: Option<usize>, order: Option<QueueOrder>) -> ControlResult {
+        let mut config = self.config.lock().await;
+        let previous = config.queue;
+        if let Some(max_concurrent) = max_concurrent {
+            config.queue.max_concurrent = max_concurrent.max(1);
+        }
+        if let Some(order) = order {
+            config.queue.order = order;
+        }
+        if let Err(err) = config.save(&self.config_path).await {
+            config.queue = previous;
+            return Err(format!("Cannot save {} {}", self.config_path, err));
+        }
+        info!("Transfer queue changed to {:?}", config.queue);
+        Ok(json!(config.queue))
+    }
+
+    /// Kept versions of the files of a folder, or of every folder when `folder` is None
+    async fn versions(&self, folder: Option<String>, path: Option<String>) -> ControlResult {
+        let mut versions = vec![];
+        for folder_id in self.resolve_folders(folder).await? {
+            let folder = match self.folder(&folder_id).await {
+                Some(folder) => folder,
+                None => continue,
+            };
+            let file = path.as_deref().map(|path| relative_to(&folder, path));
+            let folder_versions = folder.file_handler.versions().list(file.as_deref()).await.map_err(|err| err.to_string())?;
+            versions.extend(folder_versions.into_iter().map(|version| {
+                let mut version = json!(version);
+                version["folder_id"] = json!(folder_id);
+                version
+            }));
+        }
+        Ok(json!(versions))
+    }
+
+    /// Puts a kept version back in place and announces it like a local change
+    async fn restore_version(&self, folder: String, version: String, peers: &mut HashMap<String, Peer>) -> ControlResult {
+        let folder_id = self.resolve_folders(Some(folder)).await?.remove(0);
+        let folder = self.folder(&folder_id).await.ok_or(format!("Unknown folder {}", folder_id))?;
+        let file_path = folder.file_handler.versions().restore(&version).await.map_err(|err| err.to_string())?;
+        // Renamed into place, the watcher does not report it, announced like a local change
+        let sha = folder.file_sha(&file_path).await.ok_or(format!("Cannot read {}", file_path))?;
+        let size = folder.file_handler.metadata(&file_path).await.map(|metadata| metadata.len()).unwrap_or_default();
+        let id = Uuid::new_v4();
+        let message = match folder.index.lock().await.get(&file_path) {
+            Some(_) => InternalToExternal::FileModified { id, file: file_path.clone(), sha, size },
+            None => InternalToExternal::FileCreated { id, file: file_path.clone(), sha, size },
+        };
+        self.handle_internal_to_external(&folder_id, message, peers).await.map_err(|err| err.to_string())?;
+        Ok(json!({ "folder_id": folder_id, "file_path": file_path, "version": version }))
+    }
+
+    /// Sends `Command::Leave` to a peer and forgets it
+    async fn disconnect(&self, peer_id: String, peers: &mut HashMap<String, Peer>) -> ControlResult {
+        let peer = peers.remove(&peer_id).ok_or(format!("Unknown peer {}", peer_id))?;
+        self.send_leave(&Uuid::new_v4(), &peer).await;
+        self.source_left(&peer_id, peers).await;
+        Ok(json!({ "peer_id": peer_id }))
+    }
+}
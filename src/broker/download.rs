@@ -0,0 +1,368 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::io::chunk::Chunk;
+
+/// Chunks requested from one peer at a time, a fast peer gets its next chunk while sending one
+const MAX_IN_FLIGHT: usize = 4;
+/// A peer with requested chunks that sent nothing this long loses its chunks to other peers
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeouts after which a peer is no longer used for a download
+const MAX_FAILURES: u32 = 3;
+/// A peer this many times slower than the fastest one only gets one chunk at a time
+const SLOW_FACTOR: f64 = 4.0;
+/// Weight of the newest sample in the smoothed rate
+const RATE_WEIGHT: f64 = 0.3;
+
+/// Download rate of every peer in bytes per second, smoothed over the chunks it sent
+#[derive(Debug, Default)]
+pub struct PeerRates {
+    rates: HashMap<String, f64>,
+}
+
+impl PeerRates {
+    pub fn get(&self, peer_id: &str) -> Option<f64> {
+        self.rates.get(peer_id).copied()
+    }
+
+    pub fn record(&mut self, peer_id: &str, bytes: u64, elapsed: Duration) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let rate = match self.rates.get(peer_id) {
+            Some(rate) => rate + RATE_WEIGHT * (sample - rate),
+            None => sample,
+        };
+        self.rates.insert(String::from(peer_id), rate);
+    }
+}
+
+/// What a piece of received data meant for the download
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// Not requested from this peer, or already received from another one
+    Ignored,
+    /// Part of a chunk, more is coming
+    Partial,
+    /// Completed this chunk
+    ChunkDone(Chunk),
+}
+
+#[derive(Debug)]
+struct Request {
+    chunk: Chunk,
+    received: u64,
+    last_progress: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Source {
+    requests: Vec<Request>,
+    failures: u32,
+    /// Since when the peer is sending the chunk at the head of its requests
+    busy_since: Option<Instant>,
+    /// When the peer last sent data, or was asked for chunks while it had none to send. Chunks
+    /// queued behind the one it is sending do not time out while it keeps sending.
+    last_progress: Option<Instant>,
+}
+
+/// The chunks of a file still to download and the peers they are requested from
+#[derive(Debug)]
+pub struct Download {
+    pending: VecDeque<Chunk>,
+    sources: HashMap<String, Source>,
+}
+
+impl Download {
+    pub fn new(chunks: Vec<Chunk>) -> Self {
+        Download { pending: chunks.into(), sources: HashMap::new() }
+    }
+
+    pub fn add_source(&mut self, peer_id: &str) {
+        self.sources.entry(String::from(peer_id)).or_default();
+    }
+
+    /// Peers the file is downloaded from, sorted
+    pub fn sources(&self) -> Vec<String> {
+        let mut sources: Vec<String> = self.sources.keys().cloned().collect();
+        sources.sort();
+        sources
+    }
+
+    pub fn has_sources(&self) -> bool {
+        !self.sources.is_empty()
+    }
+
+    /// Every chunk was received
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty() && self.sources.values().all(|source| source.requests.is_empty())
+    }
+
+    /// Stops using a peer, the chunks it still had to send go back to the queue
+    pub fn remove_source(&mut self, peer_id: &str) -> bool {
+        match self.sources.remove(peer_id) {
+            Some(source) => {
+                self.requeue(source.requests);
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///
+    /// Hands out queued chunks, the fastest peers first, up to the number of chunks every peer
+    /// may have in flight. Once the queue is empty, idle peers also ask for chunks another peer
+    /// is still sending, whichever answers first wins.
+    /// # Arguments
+    /// * `rates` - Measured rate of the peers, a peer without one is treated like the fastest
+    ///
+    /// # Returns
+    /// * `Vec<(String, Vec<Chunk>)>` - Chunks to request from every peer
+    ///
+    pub fn schedule(&mut self, rates: &PeerRates) -> Vec<(String, Vec<Chunk>)> {
+        let best = self.sources.keys().filter_map(|peer_id| rates.get(peer_id)).fold(0.0, f64::max);
+        let rate = |peer_id: &str| rates.get(peer_id).unwrap_or(best);
+        let mut peer_ids: Vec<String> = self.sources.keys().cloned().collect();
+        peer_ids.sort_by(|a, b| rate(b).total_cmp(&rate(a)).then_with(|| a.cmp(b)));
+
+        let now = Instant::now();
+        let mut scheduled = vec![];
+        for peer_id in peer_ids {
+            let limit = if rate(&peer_id) * SLOW_FACTOR < best { 1 } else { MAX_IN_FLIGHT };
+            let mut chunks = vec![];
+            while self.sources[&peer_id].requests.len() < limit {
+                let chunk = match self.pending.pop_front().or_else(|| self.endgame_chunk(&peer_id)) {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                let source = self.sources.get_mut(&peer_id).expect("source exists");
+                if source.requests.is_empty() {
+                    source.busy_since = Some(now);
+                    source.last_progress = Some(now);
+                }
+                source.requests.push(Request { chunk: chunk.clone(), received: 0, last_progress: now });
+                chunks.push(chunk);
+            }
+            if !chunks.is_empty() {
+                scheduled.push((peer_id, chunks));
+            }
+        }
+        scheduled
+    }
+
+    ///
+    /// Counts data received from a peer
+    /// # Arguments
+    /// * `offset` - Where the data starts in the file
+    /// * `length` - Bytes received
+    ///
+    pub fn received(&mut self, peer_id: &str, offset: u64, length: u64, rates: &mut PeerRates) -> Progress {
+        let now = Instant::now();
+        let source = match self.sources.get_mut(peer_id) {
+            Some(source) => source,
+            None => return Progress::Ignored,
+        };
+        source.last_progress = Some(now);
+        let position = match source
+            .requests
+            .iter()
+            .position(|request| request.chunk.offset <= offset && offset < request.chunk.offset + request.chunk.size)
+        {
+            Some(position) => position,
+            None => return Progress::Ignored,
+        };
+        let request = &mut source.requests[position];
+        request.received += length;
+        request.last_progress = now;
+        if request.received < request.chunk.size {
+            return Progress::Partial;
+        }
+        let request = source.requests.remove(position);
+        let started = source.busy_since.unwrap_or(now);
+        rates.record(peer_id, request.chunk.size, now - started);
+        source.busy_since = if source.requests.is_empty() { None } else { Some(now) };
+        // Also asked from another peer near the end, its copy is no longer needed
+        for source in self.sources.values_mut() {
+            source.requests.retain(|other| other.chunk.offset != request.chunk.offset);
+        }
+        Progress::ChunkDone(request.chunk)
+    }
+
+    /// Queues a chunk again whose data did not have the expected hash
+    pub fn retry(&mut self, chunk: Chunk) {
+        self.pending.push_front(chunk);
+    }
+
+    ///
+    /// Takes their chunks away from peers that stopped sending, a peer that timed out too often
+    /// is no longer used
+    /// # Returns
+    /// * `Vec<String>` - Peers that timed out
+    ///
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut timed_out = vec![];
+        for (peer_id, source) in self.sources.iter_mut() {
+            let stalled = source.last_progress.is_some_and(|last_progress| now.saturating_duration_since(last_progress) > CHUNK_TIMEOUT);
+            if stalled && !source.requests.is_empty() {
+                source.failures += 1;
+                source.busy_since = None;
+                timed_out.push((peer_id.clone(), std::mem::take(&mut source.requests)));
+            }
+        }
+        let mut peer_ids = vec![];
+        for (peer_id, requests) in timed_out {
+            self.requeue(requests);
+            if self.sources[&peer_id].failures >= MAX_FAILURES {
+                self.sources.remove(&peer_id);
+            }
+            peer_ids.push(peer_id);
+        }
+        peer_ids
+    }
+
+    /// Puts chunks back at the front of the queue unless another peer is sending them or they are
+    /// queued already, peers that time out together may have been sending the same chunk
+    fn requeue(&mut self, requests: Vec<Request>) {
+        for request in requests.into_iter().rev() {
+            let in_flight = self
+                .sources
+                .values()
+                .any(|source| source.requests.iter().any(|other| other.chunk.offset == request.chunk.offset));
+            let queued = self.pending.iter().any(|chunk| chunk.offset == request.chunk.offset);
+            if !in_flight && !queued {
+                self.pending.push_front(request.chunk);
+            }
+        }
+    }
+
+    /// The chunk waited for the longest that only another peer is sending
+    fn endgame_chunk(&self, peer_id: &str) -> Option<Chunk> {
+        let mut candidates: HashMap<u64, (usize, &Request)> = HashMap::new();
+        for (source_id, source) in &self.sources {
+            for request in &source.requests {
+                let entry = candidates.entry(request.chunk.offset).or_insert((0, request));
+                entry.0 += if source_id == peer_id { 2 } else { 1 };
+            }
+        }
+        candidates
+            .into_values()
+            .filter(|(senders, _)| *senders == 1)
+            .min_by_key(|(_, request)| (request.last_progress, request.chunk.offset))
+            .map(|(_, request)| request.chunk.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 100;
+
+    fn chunks(count: u64) -> Vec<Chunk> {
+        (0..count).map(|index| Chunk { offset: index * SIZE, size: SIZE, hash: format!("{}", index) }).collect()
+    }
+
+    fn offsets(scheduled: &[(String, Vec<Chunk>)], peer_id: &str) -> Vec<u64> {
+        scheduled
+            .iter()
+            .filter(|(scheduled_peer, _)| scheduled_peer == peer_id)
+            .flat_map(|(_, chunks)| chunks.iter().map(|chunk| chunk.offset))
+            .collect()
+    }
+
+    fn download(count: u64, peer_ids: &[&str]) -> Download {
+        let mut download = Download::new(chunks(count));
+        for peer_id in peer_ids {
+            download.add_source(peer_id);
+        }
+        download
+    }
+
+    #[test]
+    fn fastest_peers_get_chunks_first() {
+        let mut rates = PeerRates::default();
+        rates.record("fast", 1000, Duration::from_secs(1));
+        rates.record("slow", 100, Duration::from_secs(1));
+        let mut download = download(20, &["slow", "fast", "new"]);
+        let scheduled = download.schedule(&rates);
+        assert_eq!(scheduled[0].0, "fast");
+        assert_eq!(offsets(&scheduled, "fast"), [0, 100, 200, 300]);
+        // Without a rate a peer counts as fast as the fastest one
+        assert_eq!(offsets(&scheduled, "new").len(), MAX_IN_FLIGHT);
+        assert_eq!(offsets(&scheduled, "slow"), [800]);
+        // Every peer is busy
+        assert!(download.schedule(&rates).is_empty());
+    }
+
+    #[test]
+    fn received_data_completes_chunks() {
+        let mut rates = PeerRates::default();
+        let mut download = download(2, &["peer"]);
+        download.schedule(&rates);
+        assert_eq!(download.received("other", 0, SIZE, &mut rates), Progress::Ignored);
+        assert_eq!(download.received("peer", 500, SIZE, &mut rates), Progress::Ignored);
+        assert_eq!(download.received("peer", 0, 40, &mut rates), Progress::Partial);
+        assert_eq!(download.received("peer", 40, 60, &mut rates), Progress::ChunkDone(chunks(1).remove(0)));
+        assert!(rates.get("peer").is_some());
+        assert!(!download.is_complete());
+        assert!(matches!(download.received("peer", 100, SIZE, &mut rates), Progress::ChunkDone(_)));
+        assert!(download.is_complete());
+    }
+
+    #[test]
+    fn queued_requests_wait_for_a_sending_peer() {
+        let mut rates = PeerRates::default();
+        let mut download = download(4, &["peer"]);
+        download.schedule(&rates);
+        let start = Instant::now();
+        // A slow peer that keeps sending its first chunk
+        assert!(download.expire(start + CHUNK_TIMEOUT / 2).is_empty());
+        download.received("peer", 0, 10, &mut rates);
+        assert!(download.expire(Instant::now() + CHUNK_TIMEOUT / 2).is_empty());
+        assert!(download.expire(Instant::now() + CHUNK_TIMEOUT * 2).contains(&String::from("peer")));
+        // Its chunks are queued again in order
+        assert_eq!(offsets(&download.schedule(&rates), "peer"), [0, 100, 200, 300]);
+    }
+
+    #[test]
+    fn peers_that_time_out_too_often_are_dropped() {
+        let rates = PeerRates::default();
+        let mut download = download(1, &["peer", "other"]);
+        for _ in 0..MAX_FAILURES {
+            let scheduled = download.schedule(&rates);
+            assert!(!offsets(&scheduled, "peer").is_empty() || !offsets(&scheduled, "other").is_empty());
+            download.expire(Instant::now() + CHUNK_TIMEOUT * 2);
+        }
+        assert!(!download.has_sources());
+        // Sent by both, queued once
+        download.add_source("new");
+        assert_eq!(offsets(&download.schedule(&rates), "new"), [0]);
+    }
+
+    #[test]
+    fn idle_peers_also_ask_for_chunks_in_flight() {
+        let mut rates = PeerRates::default();
+        let mut download = download(1, &["busy"]);
+        assert_eq!(offsets(&download.schedule(&rates), "busy"), [0]);
+        download.add_source("idle");
+        assert_eq!(download.endgame_chunk("busy"), None);
+        assert_eq!(offsets(&download.schedule(&rates), "idle"), [0]);
+        // Sent by both now, no third copy
+        download.add_source("third");
+        assert!(download.schedule(&rates).is_empty());
+        assert!(matches!(download.received("idle", 0, SIZE, &mut rates), Progress::ChunkDone(_)));
+        assert_eq!(download.received("busy", 0, SIZE, &mut rates), Progress::Ignored);
+        assert!(download.is_complete());
+    }
+
+    #[test]
+    fn chunks_of_a_peer_that_left_are_queued_again() {
+        let rates = PeerRates::default();
+        let mut download = download(2, &["gone"]);
+        download.schedule(&rates);
+        assert!(download.remove_source("gone"));
+        assert!(!download.remove_source("gone"));
+        download.add_source("other");
+        assert_eq!(offsets(&download.schedule(&rates), "other"), [0, 100]);
+    }
+}
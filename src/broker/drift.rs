@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use log::{info, warn};
+use uuid::Uuid;
+
+use super::{now, Broker, Drift};
+use crate::{folder::{FolderMode, SharedFolder}, peer::Peer};
+
+impl Broker {
+    /// Remembers how a local file of a receive only folder differs from the cluster, the index
+    /// keeps the cluster version. Forgets the file once both match again.
+    pub(super) async fn record_local_drift(&self, folder: &SharedFolder, file: &str) {
+        let key = (String::from(folder.id()), String::from(file));
+        if self.transfers.lock().await.contains_key(&key) {
+            return;
+        }
+        if folder.file_handler.metadata(file).await.is_some_and(|metadata| metadata.is_dir()) {
+            return;
+        }
+        let local_sha = folder.file_sha(file).await;
+        let cluster_sha = folder.index.lock().await.get(file).map(|entry| entry.sha.clone());
+        let mut drift = self.drift.lock().await;
+        if local_sha == cluster_sha {
+            drift.remove(&key);
+            return;
+        }
+        info!("Not announcing local change to {} in receive only folder {}", file, folder.id());
+        drift.insert(key, Drift {
+            folder_id: String::from(folder.id()),
+            file_path: String::from(file),
+            peer_id: None,
+            local_sha,
+            remote_sha: cluster_sha,
+            detected_at: now(),
+        });
+    }
+
+    /// Remembers a version a peer sent to a send only folder instead of applying it
+    pub(super) async fn record_remote_drift(&self, folder: &SharedFolder, peer_id: &str, file: &str, sha: &str) {
+        let key = (String::from(folder.id()), String::from(file));
+        let local_sha = folder.file_sha(file).await;
+        let mut drift = self.drift.lock().await;
+        if local_sha.as_deref() == Some(sha) {
+            drift.remove(&key);
+            return;
+        }
+        warn!("Not applying {} from {} to send only folder {}", file, peer_id, folder.id());
+        drift.insert(key, Drift {
+            folder_id: String::from(folder.id()),
+            file_path: String::from(file),
+            peer_id: Some(String::from(peer_id)),
+            local_sha,
+            remote_sha: Some(String::from(sha)),
+            detected_at: now(),
+        });
+    }
+
+    ///
+    /// Undoes the local changes of receive only folders: new files are deleted, changed and
+    /// deleted ones downloaded again from a connected peer
+    /// # Arguments
+    /// * `folder` - Id or path of the folder, every receive only folder when None
+    ///
+    /// # Returns
+    /// * `usize` - Files deleted or requested again
+    ///
+    pub(super) async fn revert(&self, folder: Option<String>, peers: &HashMap<String, Peer>) -> std::result::Result<usize, String> {
+        let explicit = folder.is_some();
+        let mut reverted = 0;
+        for folder_id in self.resolve_folders(folder).await? {
+            let folder = match self.folder(&folder_id).await {
+                Some(folder) => folder,
+                None => continue,
+            };
+            if folder.mode() != FolderMode::ReceiveOnly {
+                if explicit {
+                    return Err(format!("Folder {} is not receive only", folder_id));
+                }
+                continue;
+            }
+            let drifted: Vec<(String, Option<String>)> = self
+                .drift
+                .lock()
+                .await
+                .values()
+                .filter(|drift| drift.folder_id == folder_id)
+                .map(|drift| (drift.file_path.clone(), drift.remote_sha.clone()))
+                .collect();
+            for (file, remote_sha) in drifted {
+                match remote_sha {
+                    None => folder.file_handler.delete_file(file.clone()).await.map_err(|err| err.to_string())?,
+                    Some(sha) => self.restore(&folder, &file, &sha, peers).await?,
+                }
+                info!("Reverted {} in {}", file, folder_id);
+                self.drift.lock().await.remove(&(folder_id.clone(), file));
+                reverted += 1;
+            }
+        }
+        Ok(reverted)
+    }
+
+    /// Downloads the indexed version of a file again, from the peer it came from when connected
+    pub(super) async fn restore(&self, folder: &SharedFolder, file: &str, sha: &str, peers: &HashMap<String, Peer>) -> std::result::Result<(), String> {
+        let peer = self.source(folder, file, peers).await.ok_or(format!("No peer of {} is connected to restore {}", folder.id(), file))?;
+        let size = folder.index.lock().await.get(file).map(|entry| entry.size).unwrap_or_default();
+        self.request_chunks(folder, Uuid::new_v4(), &peer.peer_id, file, String::from(sha), size, peers)
+            .await
+            .map_err(|err| err.to_string())
+    }
+}
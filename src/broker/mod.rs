@@ -1,12 +1,20 @@
-use std::{sync::Arc, collections::{HashMap, hash_map::Entry}};
+mod control;
+pub mod download;
+mod drift;
+pub mod queue;
+mod rescan;
+mod selection;
 
-use async_std::{net::TcpStream, sync::Mutex, stream::StreamExt, task, io::WriteExt};
-use futures::{select, FutureExt};
+use std::{path::Path, sync::Arc, collections::{BTreeSet, HashMap, HashSet, hash_map::Entry, VecDeque}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use async_std::{net::TcpStream, sync::Mutex, stream::StreamExt, task, io::{prelude::SeekExt, ReadExt, SeekFrom, WriteExt}};
+use futures::{channel::oneshot, select, select_biased, FutureExt};
 use log::{debug, info, warn};
-use log4rs::append::file;
+use serde::Serialize;
 use uuid::Uuid;
 
-use crate::{io::file_handler::FileHandler, Receiver, Result, peer::{Peer, PeerMessage, Command}};
+use self::{download::{Download, PeerRates, Progress}, queue::{QueuedFile, TransferQueue}};
+use crate::{config::NodeConfig, control::{ControlCommand, ControlResult}, folder::{Folders, SharedFolder}, io::{chunk::{check_manifest, Chunk}, transfer_state::TransferState, delta::{block_size_for, fits_block_count, literal_size, BlockSignature, DeltaInstruction, MAX_LITERAL_SIZE}, hash::HashAlgorithm, index::{modified_nanos, Presence}, metadata::{is_link_inside, mode, FileMetadata}, names::{check_wire_path, to_wire_path}, watch::{async_watch, WatchConfig}}, limit::{limiter, Direction}, metrics::metrics, shutdown::{shutdown_channel, ShutdownTrigger}, spawn_and_log_error, Receiver, Result, Sender, peer::{compression::{compress_message, Compression}, Outgoing, Peer, PeerMessage, Command}};
 
 
 //This is internal, within same process
@@ -17,13 +25,55 @@ pub enum InternalMessage {
         address: String,
         port: i32,
         stream: Arc<TcpStream>,
+        compression: Option<Compression>,
+    },
+    /// The peer answered the handshake of a connection this node opened
+    PeerCompression {
+        peer_id: String,
+        compression: Option<Compression>,
+    },
+    /// How the peer hashes the folders it shares with this node
+    PeerHashes {
+        peer_id: String,
+        hashes: HashMap<String, HashAlgorithm>,
     },
     LeavePeer {
         id: Uuid,
         peer_id: String,
     },
-    InternalToExternal { message: InternalToExternal},
-    ExternalToInternal { message: ExternalToInternal},
+    InternalToExternal { folder_id: String, message: InternalToExternal },
+    ExternalToInternal { folder_id: String, message: ExternalToInternal },
+    Control {
+        command: ControlCommand,
+        reply: oneshot::Sender<ControlResult>,
+    },
+    /// Compare a folder with its index and announce what the watcher missed
+    Rescan {
+        id: Uuid,
+        folder_id: String,
+    },
+    /// What a rescan found, hashed in its own task so that the broker keeps handling messages
+    Rescanned {
+        id: Uuid,
+        folder_id: String,
+        result: std::result::Result<Rescanned, String>,
+    },
+    /// Reschedule the chunks of peers that stopped sending, sent periodically by the broker itself
+    CheckDownloads,
+    /// Remove the versions of replaced files the retention policy no longer keeps, sent
+    /// periodically by the broker itself
+    PruneVersions,
+    Shutdown {
+        id: Uuid,
+    },
+}
+
+/// The changes a rescan found in a folder, announced by the broker
+pub struct Rescanned {
+    scanned: usize,
+    changes: Vec<InternalToExternal>,
+    /// Files that cannot be synced with the reason
+    problems: Vec<String>,
 }
 
 pub enum InternalToExternal{
@@ -31,6 +81,7 @@ pub enum InternalToExternal{
         id: Uuid,
         file: String,
         sha: String,
+        size: u64,
     },
     FolderCreated {
         id: Uuid,
@@ -41,12 +92,18 @@ pub enum InternalToExternal{
         id: Uuid,
         file: String,
         sha: String,
+        size: u64,
     },
     FolderModified {
         id: Uuid,
         folder: String,
         sha: String,
     },
+    /// Permissions or modification time changed, announced when the content did not
+    MetadataModified {
+        id: Uuid,
+        file: String,
+    },
     FileDeleted {
         id: Uuid,
         folder: String,
@@ -65,6 +122,22 @@ pub enum InternalToExternal{
     }
 }
 
+impl InternalToExternal {
+    /// Path of the file or folder relative to the root
+    pub fn path(&self) -> &str {
+        match self {
+            InternalToExternal::FileCreated { file, .. }
+            | InternalToExternal::FileModified { file, .. }
+            | InternalToExternal::MetadataModified { file, .. }
+            | InternalToExternal::FolderDeleted { file, .. }
+            | InternalToExternal::RequestData { file, .. } => file,
+            InternalToExternal::FolderCreated { folder, .. }
+            | InternalToExternal::FolderModified { folder, .. }
+            | InternalToExternal::FileDeleted { folder, .. } => folder,
+        }
+    }
+}
+
 pub enum ExternalToInternal{
     DataRequest {
         id: Uuid,
@@ -83,20 +156,263 @@ pub enum ExternalToInternal{
         peer_id: String,
         file_path: String,
         sha: String,
+        size: u64,
+        metadata: FileMetadata,
+    },
+    FileModify {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        sha: String,
+        size: u64,
+        metadata: FileMetadata,
+    },
+    MetadataModify {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        sha: String,
+        metadata: FileMetadata,
+    },
+    /// `sha` is the version the peer had before deleting it
+    FileDelete {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        sha: String,
+    },
+    Signatures {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        block_size: usize,
+        signatures: Vec<BlockSignature>,
+    },
+    Delta {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        sha: String,
+        block_size: usize,
+        instructions: Vec<DeltaInstruction>,
+        last: bool,
+    },
+    ManifestRequest {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+    },
+    Manifest {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        sha: String,
+        size: u64,
+        chunks: Vec<Chunk>,
+    },
+    ChunkRequest {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
+        chunks: Vec<Chunk>,
+    },
+    /// The peer only has a placeholder of a file it was asked for
+    ContentUnavailable {
+        id: Uuid,
+        peer_id: String,
+        file_path: String,
     },
 }
 
+impl ExternalToInternal {
+    /// Path of the file relative to the root
+    pub fn file_path(&self) -> &str {
+        match self {
+            ExternalToInternal::DataRequest { file_path, .. }
+            | ExternalToInternal::DataWrite { file_path, .. }
+            | ExternalToInternal::NewFileCreate { file_path, .. }
+            | ExternalToInternal::FileModify { file_path, .. }
+            | ExternalToInternal::MetadataModify { file_path, .. }
+            | ExternalToInternal::FileDelete { file_path, .. }
+            | ExternalToInternal::Signatures { file_path, .. }
+            | ExternalToInternal::Delta { file_path, .. }
+            | ExternalToInternal::ManifestRequest { file_path, .. }
+            | ExternalToInternal::Manifest { file_path, .. }
+            | ExternalToInternal::ChunkRequest { file_path, .. }
+            | ExternalToInternal::ContentUnavailable { file_path, .. } => file_path,
+        }
+    }
+
+    /// Peer the message came from
+    pub fn peer_id(&self) -> &str {
+        match self {
+            ExternalToInternal::DataRequest { peer_id, .. }
+            | ExternalToInternal::DataWrite { peer_id, .. }
+            | ExternalToInternal::NewFileCreate { peer_id, .. }
+            | ExternalToInternal::FileModify { peer_id, .. }
+            | ExternalToInternal::MetadataModify { peer_id, .. }
+            | ExternalToInternal::FileDelete { peer_id, .. }
+            | ExternalToInternal::Signatures { peer_id, .. }
+            | ExternalToInternal::Delta { peer_id, .. }
+            | ExternalToInternal::ManifestRequest { peer_id, .. }
+            | ExternalToInternal::Manifest { peer_id, .. }
+            | ExternalToInternal::ChunkRequest { peer_id, .. }
+            | ExternalToInternal::ContentUnavailable { peer_id, .. } => peer_id,
+        }
+    }
+}
+
+/// How a file is being downloaded
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    /// Every byte is downloaded
+    Whole,
+    /// Rebuilt from the local copy and a delta
+    Delta,
+    /// Built from chunks of local files, only the missing chunks are downloaded
+    Chunks,
+}
+
+/// A file being downloaded from a peer
+#[derive(Serialize, Debug)]
+pub struct Transfer {
+    pub folder_id: String,
+    pub file_path: String,
+    pub peer_id: String,
+    pub sha: String,
+    pub size: u64,
+    /// Bytes written so far, chunks copied from local files included
+    pub received: u64,
+    pub kind: TransferKind,
+    pub started_at: u64,
+    /// When data last arrived, the start until then
+    pub updated_at: u64,
+}
+
+/// A local file that was different from the version a peer announced
+#[derive(Serialize, Debug)]
+pub struct Conflict {
+    pub folder_id: String,
+    pub file_path: String,
+    pub peer_id: String,
+    pub local_sha: String,
+    pub remote_sha: String,
+    /// Where the local version was copied before the version of the peer replaced it, None when
+    /// it could not be copied
+    pub copy: Option<String>,
+    pub detected_at: u64,
+}
+
+/// A file that differs from the cluster because the mode of its folder does not let it sync
+#[derive(Serialize, Debug)]
+pub struct Drift {
+    pub folder_id: String,
+    pub file_path: String,
+    /// The peer that sent a different version to a send only folder, None for local changes
+    pub peer_id: Option<String>,
+    /// None when the file does not exist locally
+    pub local_sha: Option<String>,
+    /// None when the file does not exist in the cluster
+    pub remote_sha: Option<String>,
+    pub detected_at: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SyncError {
+    pub message: String,
+    pub occurred_at: u64,
+}
+
+const DATA_CHUNK_SIZE: usize = 1024;
+/// File data messages waiting for the writer task of a peer before the senders wait
+const DATA_QUEUE_SIZE: usize = 64;
+const DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// Seconds a download not scheduled by chunks may go without data before it is given up
+const TRANSFER_TIMEOUT: u64 = 60;
+/// Verified chunks survive a crash, at worst the chunks of the last seconds are downloaded again
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_RECENT_ERRORS: usize = 100;
+/// Versions only expire by age when they are pruned, pruning on every replaced file only
+/// covers that file
+const VERSIONS_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Folder id and the path of a file relative to the root of that folder
+type FileKey = (String, String);
+
 pub struct Broker {
     my_peer_id: String,
-    files_in_update: Arc<Mutex<HashMap<String,String>>>,
-    file_handler: FileHandler,
+    config: Arc<Mutex<NodeConfig>>,
+    config_path: String,
+    folders: Folders,
+    watch_config: WatchConfig,
+    broker_sender: Sender<InternalMessage>,
+    /// Dropping the trigger of a folder stops its watcher
+    watchers: Arc<Mutex<HashMap<String, ShutdownTrigger>>>,
+    /// SHA every file being written by the sync will have, the watcher events of those writes
+    /// are not announced. Removed once the transfer completes or fails.
+    files_in_update: Arc<Mutex<HashMap<FileKey, String>>>,
+    /// Permissions and modification time announced with files waiting to be downloaded, applied
+    /// once the download completes
+    pending_metadata: Arc<Mutex<HashMap<FileKey, FileMetadata>>>,
+    paused: Arc<Mutex<bool>>,
+    transfers: Arc<Mutex<HashMap<FileKey, Transfer>>>,
+    /// Chunk transfers spread over every peer that has the file
+    downloads: Arc<Mutex<HashMap<FileKey, Download>>>,
+    rates: Arc<Mutex<PeerRates>>,
+    /// Announced files waiting until fewer than `max_concurrent` files are downloaded
+    queue: Arc<Mutex<TransferQueue>>,
+    conflicts: Arc<Mutex<Vec<Conflict>>>,
+    drift: Arc<Mutex<HashMap<FileKey, Drift>>>,
+    /// Peer every received file came from, asked first when it has to be restored
+    sources: Arc<Mutex<HashMap<FileKey, String>>>,
+    /// Peers that answered they only have a placeholder of a file, not asked for it until they
+    /// announce it again
+    placeholder_peers: Arc<Mutex<HashMap<FileKey, HashSet<String>>>>,
+    errors: Arc<Mutex<VecDeque<SyncError>>>,
+    /// Hash algorithm of every folder of the connected peers, by peer id
+    peer_hashes: Arc<Mutex<HashMap<String, HashMap<String, HashAlgorithm>>>>,
 }
 
 
 impl Broker {
+    ///
+    /// Creates the broker of a node
+    /// # Arguments
+    /// * `config` - Node id and shared folders, saved to `config_path` when folders are added or removed
+    /// * `folders` - The folders of the config, already opened and shared with the peer message handler
+    /// * `watch_config` - Used for every folder watcher the broker starts
+    /// * `broker_sender` - Sender of the broker's own channel, handed to the folder watchers
+    ///
     pub fn new(
-        my_peer_id: String, file_handler: FileHandler) -> Self {
-            Broker {  my_peer_id, files_in_update: Arc::new(Mutex::new(HashMap::new())),file_handler  }
+        config: NodeConfig,
+        config_path: String,
+        folders: Folders,
+        watch_config: WatchConfig,
+        broker_sender: Sender<InternalMessage>,
+    ) -> Self {
+            Broker {
+                my_peer_id: config.node_id.clone(),
+                config: Arc::new(Mutex::new(config)),
+                config_path,
+                folders,
+                watch_config,
+                broker_sender,
+                watchers: Arc::new(Mutex::new(HashMap::new())),
+                files_in_update: Arc::new(Mutex::new(HashMap::new())),
+                pending_metadata: Arc::new(Mutex::new(HashMap::new())),
+                paused: Arc::new(Mutex::new(false)),
+                transfers: Arc::new(Mutex::new(HashMap::new())),
+                downloads: Arc::new(Mutex::new(HashMap::new())),
+                rates: Arc::new(Mutex::new(PeerRates::default())),
+                queue: Arc::new(Mutex::new(TransferQueue::default())),
+                conflicts: Arc::new(Mutex::new(vec![])),
+                drift: Arc::new(Mutex::new(HashMap::new())),
+                sources: Arc::new(Mutex::new(HashMap::new())),
+                placeholder_peers: Arc::new(Mutex::new(HashMap::new())),
+                errors: Arc::new(Mutex::new(VecDeque::new())),
+                peer_hashes: Arc::new(Mutex::new(HashMap::new())),
+            }
     }
 }
 
@@ -105,6 +421,29 @@ impl Broker {
     pub async fn broker_loop(&self, events: Receiver<InternalMessage>) -> Result<()> {
         // let (disconnect_sender, mut disconnect_receiver) = mpsc::unbounded::<(String, Receiver<PeerEvent>)>();
         let mut peers: HashMap<String, Peer> = HashMap::new();
+        // Sync messages received while paused, replayed in order on resume
+        let mut deferred: Vec<InternalMessage> = vec![];
+        let folders: Vec<Arc<SharedFolder>> = self.folders.read().await.values().cloned().collect();
+        for folder in folders {
+            self.start_watching(folder).await;
+        }
+        let ticker = self.broker_sender.clone();
+        spawn_and_log_error(async move {
+            // Stops once the broker loop is gone
+            while ticker.send(InternalMessage::CheckDownloads).await.is_ok() {
+                task::sleep(DOWNLOAD_CHECK_INTERVAL).await;
+            }
+            Ok(())
+        });
+        let ticker = self.broker_sender.clone();
+        spawn_and_log_error(async move {
+            while ticker.send(InternalMessage::PruneVersions).await.is_ok() {
+                task::sleep(VERSIONS_PRUNE_INTERVAL).await;
+            }
+            Ok(())
+        });
+        // Never receives, only tells how many messages wait
+        let backlog = events.clone();
         let mut events = events.fuse();
         loop {
             let event = select! {
@@ -113,181 +452,1563 @@ impl Broker {
                     Some(event) => event,
                 },
             };
+            // Follows the backlog as it is worked off, a scrape reads it too in case the loop is stuck
+            metrics().set_broker_queue_depth(backlog.len());
+            let is_sync_message = matches!(
+                event,
+                InternalMessage::InternalToExternal { .. }
+                    | InternalMessage::ExternalToInternal { .. }
+                    | InternalMessage::Rescan { .. }
+                    | InternalMessage::Rescanned { .. }
+            );
+            if is_sync_message && *self.paused.lock().await {
+                deferred.push(event);
+                continue;
+            }
             match event {
                 InternalMessage::LeavePeer {
                     id,
                     peer_id: client_id,
-                } => handle_peer_leave(&mut peers, client_id, &id),
+                } => {
+                    handle_peer_leave(&mut peers, client_id.clone(), &id);
+                    self.peer_hashes.lock().await.remove(&client_id);
+                    self.source_left(&client_id, &peers).await;
+                },
                 InternalMessage::NewPeer {
                     id: _,
                     peer_id: client_id,
                     address,
                     port,
                     stream,
+                    compression,
                 } => match peers.entry(client_id.clone()) {
                     Entry::Occupied(..) => (),
                     Entry::Vacant(entry) => {
-                        entry.insert(Peer {
+                        let (control, control_receiver) = async_std::channel::unbounded();
+                        let (data, data_receiver) = async_std::channel::bounded(DATA_QUEUE_SIZE);
+                        task::spawn(write_messages(client_id.clone(), stream.clone(), control_receiver, data_receiver));
+                        let peer = entry.insert(Peer {
                             peer_id: client_id.clone(),
                             address,
                             port,
                             stream: stream.clone(),
+                            compression,
+                            control,
+                            data,
                         });
+                        // Before anything else, the peer checks every later message against it. Transfers
+                        // resume once the peer sent its own.
+                        self.send_hashes(peer).await;
+                    }
+                },
+                InternalMessage::PeerCompression { peer_id, compression } => {
+                    if let Some(peer) = peers.get_mut(&peer_id) {
+                        debug!("Sending to {} compressed with {:?}", peer_id, compression);
+                        peer.compression = compression;
                     }
                 },
-                InternalMessage::ExternalToInternal { message } => {
-                    self.handle_external_to_internal(message, &mut peers).await.unwrap();
+                InternalMessage::PeerHashes { peer_id, hashes } => {
+                    self.peer_hashes_received(peer_id.clone(), hashes).await;
+                    if !*self.paused.lock().await {
+                        self.resume_transfers(&peer_id).await;
+                    }
+                },
+                InternalMessage::ExternalToInternal { folder_id, message } => {
+                    if let Err(err) = self.handle_external_to_internal(&folder_id, message, &mut peers).await {
+                        metrics().transfer_failed();
+                        self.record_error(err.to_string()).await;
+                    }
                 },
-                InternalMessage::InternalToExternal { message } => {
-                    self.handle_internal_to_external(message, &mut peers).await.unwrap();
+                InternalMessage::InternalToExternal { folder_id, message } => {
+                    if let Err(err) = self.handle_internal_to_external(&folder_id, message, &mut peers).await {
+                        self.record_error(err.to_string()).await;
+                    }
+                },
+                InternalMessage::Control { command, reply } => {
+                    self.handle_control(command, reply, &mut peers).await;
+                    if !deferred.is_empty() && !*self.paused.lock().await {
+                        self.replay_deferred(deferred.drain(..), &mut peers).await;
+                    }
+                },
+                InternalMessage::Rescan { id, folder_id } => self.rescan(id, &folder_id).await,
+                InternalMessage::Rescanned { id, folder_id, result } => {
+                    if let Err(err) = self.rescanned(&id, &folder_id, result, &mut peers).await {
+                        self.record_error(err.to_string()).await;
+                    }
+                },
+                InternalMessage::CheckDownloads => {
+                    if !*self.paused.lock().await {
+                        self.check_downloads(&peers).await;
+                    }
+                },
+                InternalMessage::PruneVersions => {
+                    for folder in self.folders.read().await.values() {
+                        if let Err(err) = folder.file_handler.versions().prune().await {
+                            warn!("Cannot remove old versions in {} {}", folder.root(), err);
+                        }
+                    }
+                },
+                InternalMessage::Shutdown { id } => {
+                    // Everything queued before the signal is already handled, file data still queued for peers is dropped
+                    self.handle_shutdown(&id, &mut peers).await;
+                    break;
                 }
             }    
+            self.start_queued(&peers).await;
+            metrics().set_active_connections(peers.len());
         }
+        metrics().set_active_connections(0);
         drop(peers);
         // drop(disconnect_sender);
         // while let Some((_name, _pending_messages)) = disconnect_receiver.next().await {}
         Ok(())
     }
     
-    pub async fn handle_internal_to_external(&self, message: InternalToExternal,  peers: &mut HashMap<String, Peer>) -> Result<()>{
+    pub async fn handle_internal_to_external(&self, folder_id: &str, message: InternalToExternal,  peers: &mut HashMap<String, Peer>) -> Result<()>{
+        let folder = match self.folder(folder_id).await {
+            Some(folder) => folder,
+            None => {
+                debug!("Dropping change to {} of removed folder {}", message.path(), folder_id);
+                return Ok(());
+            }
+        };
+        if folder.is_ignored(message.path()).await {
+            debug!("Not announcing ignored {}", message.path());
+            return Ok(());
+        }
+        if !folder.is_selected(message.path()).await {
+            // Also the removal of a file that was deselected, it stays on the peers
+            debug!("Not announcing unselected {}", message.path());
+            return Ok(());
+        }
+        if !folder.mode().sends() && !matches!(message, InternalToExternal::RequestData { .. }) {
+            self.record_local_drift(&folder, message.path()).await;
+            return Ok(());
+        }
+        // Only the peers the folder is shared with hear about its changes, and only those that
+        // hash it the same way could use them
+        let mut members: Vec<&Peer> = vec![];
+        for peer in peers.values().filter(|peer| folder.config.is_shared_with(&peer.peer_id)) {
+            if self.peer_hash(&peer.peer_id, folder_id).await == Some(folder.config.hash) {
+                members.push(peer);
+            }
+        }
         match message {
-            InternalToExternal::FileCreated { id, file, sha } => {
+            InternalToExternal::FileCreated { id, file, sha, size } => {
                 debug!(
-                    "Recevied FileCreated {:?}  event id {:?}, sha {:?}",
-                    file, id, sha
+                    "Recevied FileCreated {:?} in {} event id {:?}, sha {:?}",
+                    file, folder_id, id, sha
                 );
-                let files_in_update = self.files_in_update.clone();
-                let files_in_update = files_in_update.lock().await;
-                let is_updating = files_in_update.contains_key(&file);
-                
-                if is_updating {
-                    return Ok(()) ;
+                if self.is_echo(&folder, &file, &sha).await {
+                    debug!("Not announcing {} in {}, written by the sync", file, folder_id);
+                    return Ok(());
                 }
-    
-                peers.values().for_each(|peer| {
+                folder.index_file(&file, &sha).await;
+                let metadata = match self.announced_metadata(&folder, &file).await {
+                    Some(metadata) => metadata,
+                    None => return Ok(()),
+                };
+                for peer in members.iter() {
                     let command = PeerMessage::PeerCommand {
                         command: Command::CreateNewFile {
                             id,
-                            file_path: file.clone(),
                             peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
+                            file_path: file.clone(),
                             sha: sha.clone(),
+                            size,
+                            metadata: metadata.clone(),
                         },
                     };
                     let command_json = serde_json::to_string(&command).unwrap();
-                    task::block_on(send_message(peer.stream.clone(), command_json));
-                });
-    
-                let files_in_update = self.files_in_update.clone();
-    
-                task::block_on(async {
-                    let mut f = files_in_update.lock().await;
-                    f.insert(file.clone(), sha.clone());
-                });
+                    send_message(peer, command_json).await;
+                }
             }
-            InternalToExternal::FolderCreated { id, folder, sha } => {
+            InternalToExternal::FolderCreated { id, folder: folder_path, sha } => {
                 debug!(
-                    "Recevied FolderCreated {:?}  event id {:?}, sha {:?} ",
-                    folder, id, sha
+                    "Recevied FolderCreated {:?} in {} event id {:?}, sha {:?} ",
+                    folder_path, folder_id, id, sha
                 );
-                peers.values().for_each(|peer| {
+                for peer in members.iter() {
                     let command = PeerMessage::PeerCommand {
                         command: Command::CreateFolder {
                             id,
-                            folder_path: folder.clone(),
                             peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
+                            folder_path: folder_path.clone(),
                         },
                     };
                     let command_json = serde_json::to_string(&command).unwrap();
-                    task::block_on(send_message(peer.stream.clone(), command_json));
-                });
+                    send_message(peer, command_json).await;
+                }
             }
-            InternalToExternal::FileModified { id, file, sha } => {
+            InternalToExternal::FileModified { id, file, sha, size } => {
                 debug!(
-                    "Recevied FileModified {:?}  event id {:?}, sha {:?}",
-                    file, id, sha
+                    "Recevied FileModified {:?} in {} event id {:?}, sha {:?}",
+                    file, folder_id, id, sha
                 );
-                peers.values().for_each(|peer| {
+                if self.is_echo(&folder, &file, &sha).await {
+                    debug!("Not announcing {} in {}, written by the sync", file, folder_id);
+                    return Ok(());
+                }
+                folder.index_file(&file, &sha).await;
+                let metadata = match self.announced_metadata(&folder, &file).await {
+                    Some(metadata) => metadata,
+                    None => return Ok(()),
+                };
+                for peer in members.iter() {
                     let command = PeerMessage::PeerCommand {
                         command: Command::ModifyFile  {
                             id,
+                            peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
                             file_path: file.clone(),
+                            sha: sha.clone(),
+                            size,
+                            metadata: metadata.clone(),
+                        },
+                    };
+                    let command_json = serde_json::to_string(&command).unwrap();
+                    send_message(peer, command_json).await;
+                }
+            }
+            InternalToExternal::MetadataModified { id, file } => {
+                let key = (String::from(folder_id), file.clone());
+                if self.transfers.lock().await.contains_key(&key) {
+                    return Ok(());
+                }
+                let (known, current) = match (folder.index.lock().await.get(&file).cloned(), folder.file_handler.metadata(&file).await) {
+                    (Some(known), Some(current)) => (known, current),
+                    // Not synced yet, announced with its content once created
+                    _ => return Ok(()),
+                };
+                let modified = current.modified().map(modified_nanos).unwrap_or_default();
+                if known.modified == modified && known.mode == mode(&current) {
+                    debug!("Metadata of {} in {} is already indexed", file, folder_id);
+                    return Ok(());
+                }
+                let sha = folder.file_sha(&file).await.ok_or(format!("Cannot hash {} in {}", file, folder_id))?;
+                if sha != known.sha {
+                    // The write that changed it is announced on its own
+                    return Ok(());
+                }
+                folder.index_file(&file, &sha).await;
+                let metadata = match self.announced_metadata(&folder, &file).await {
+                    Some(metadata) => metadata,
+                    None => return Ok(()),
+                };
+                debug!("Announcing metadata {:?} of {} in {} event id {:?}", metadata, file, folder_id, id);
+                for peer in members.iter() {
+                    let command = PeerMessage::PeerCommand {
+                        command: Command::ModifyMetadata {
+                            id,
                             peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
+                            file_path: file.clone(),
+                            sha: sha.clone(),
+                            metadata: metadata.clone(),
                         },
                     };
                     let command_json = serde_json::to_string(&command).unwrap();
-                    task::block_on(send_message(peer.stream.clone(), command_json));
-                });
+                    send_message(peer, command_json).await;
+                }
             }
-            InternalToExternal::FolderModified { id, folder, sha: _ } => {
-                debug!("Recevied FolderModified {:?}  event id {:?} ", folder, id);
+            InternalToExternal::FolderModified { id, folder: folder_path, sha: _ } => {
+                debug!("Recevied FolderModified {:?} in {} event id {:?} ", folder_path, folder_id, id);
             }
-            InternalToExternal::FileDeleted { id, folder, sha: _ } => {
-                debug!("Recevied RemoveFolder {:?}  event id {:?} ", folder, id);
+            InternalToExternal::FileDeleted { id, folder: file, sha: _ } => {
+                debug!("Recevied RemoveFile {:?} in {} event id {:?} ", file, folder_id, id);
+                // A watcher that cannot tell files from folders reports both as files
+                self.announce_deleted(&folder, id, &file, &members).await?;
             }
-            InternalToExternal::FolderDeleted { id, file, sha: _ } => {
-                debug!("Recevied RemoveFile {:?}  event id {:?} ", file, id);
+            InternalToExternal::FolderDeleted { id, file: folder_path, sha: _ } => {
+                debug!("Recevied RemoveFolder {:?} in {} event id {:?} ", folder_path, folder_id, id);
+                self.announce_deleted(&folder, id, &folder_path, &members).await?;
             },
             InternalToExternal::RequestData { id, file, peer_id, sha: _ } => {
-                peers.values().filter(|peer| peer.peer_id.eq(&peer_id)).for_each(|peer| {
+                for peer in members.iter().filter(|peer| peer.peer_id.eq(&peer_id)) {
                     let command = PeerMessage::PeerCommand {
                         command: Command::DataRequestCommand {
                             id,
                             peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
                             file_path: file.clone(),
                         },
                     };
                     let command_json = serde_json::to_string(&command).unwrap();
-                    task::block_on(send_message(peer.stream.clone(), command_json));
-                });
+                    send_message(peer, command_json).await;
+                }
             }
         }
         Ok(())
     }
 
-    pub async fn handle_external_to_internal(&self, message: ExternalToInternal,  peers: &mut HashMap<String, Peer>) -> Result<()>{
+    pub async fn handle_external_to_internal(&self, folder_id: &str, message: ExternalToInternal,  peers: &mut HashMap<String, Peer>) -> Result<()>{
+        let folder = self
+            .folder(folder_id)
+            .await
+            .ok_or(format!("{} sent {} for unknown folder {}", message.peer_id(), message.file_path(), folder_id))?;
+        // The id of the connection the message came in on, messages claiming another are dropped
+        if !folder.config.is_shared_with(message.peer_id()) {
+            Err(format!("Folder {} is not shared with {}, refusing {}", folder_id, message.peer_id(), message.file_path()))?;
+        }
+        match self.peer_hash(message.peer_id(), folder_id).await {
+            Some(hash) if hash == folder.config.hash => {}
+            Some(hash) => Err(format!(
+                "Refusing {} in {} from {}, it hashes the folder with {} and this node with {}",
+                message.file_path(),
+                folder_id,
+                message.peer_id(),
+                hash,
+                folder.config.hash
+            ))?,
+            None => Err(format!(
+                "Refusing {} in {} from {}, it did not say how it hashes the folder",
+                message.file_path(),
+                folder_id,
+                message.peer_id()
+            ))?,
+        }
+        check_wire_path(message.file_path()).map_err(|err| format!("Refusing {} from {} {}", folder_id, message.peer_id(), err))?;
+        if folder.is_ignored(message.file_path()).await {
+            warn!("Refusing remote change to ignored {}", message.file_path());
+            return Ok(());
+        }
+        let reads_content = matches!(
+            message,
+            ExternalToInternal::DataRequest { .. }
+                | ExternalToInternal::ChunkRequest { .. }
+                | ExternalToInternal::ManifestRequest { .. }
+                | ExternalToInternal::Signatures { .. }
+        );
+        if reads_content && folder.is_placeholder(message.file_path()).await {
+            debug!("Not sending placeholder {} in {} to {}", message.file_path(), folder_id, message.peer_id());
+            if let Some(peer) = peers.get(message.peer_id()) {
+                let command = PeerMessage::PeerCommand {
+                    command: Command::ContentUnavailable {
+                        id: Uuid::new_v4(),
+                        peer_id: self.my_peer_id.clone(),
+                        folder_id: String::from(folder_id),
+                        file_path: String::from(message.file_path()),
+                    },
+                };
+                send_message(peer, serde_json::to_string(&command)?).await;
+            }
+            return Ok(());
+        }
+        if matches!(message, ExternalToInternal::NewFileCreate { .. } | ExternalToInternal::FileModify { .. }) {
+            // Announced with its content
+            let key = (String::from(folder_id), String::from(message.file_path()));
+            if let Some(holders) = self.placeholder_peers.lock().await.get_mut(&key) {
+                holders.remove(message.peer_id());
+            }
+        }
+        if let ExternalToInternal::FileModify { sha, .. } = &message {
+            if sha.is_empty() {
+                // From a peer older than the hashes in announcements, nothing it sends could be checked
+                Err(format!("{} modified {} in {} without saying its hash, ignoring it", message.peer_id(), message.file_path(), folder_id))?;
+            }
+        }
         match message {
             ExternalToInternal::DataRequest { id, peer_id, file_path } => {
-                let mut buf  = vec![0; 255];
-                let mut offset = 0;
-                while !self.file_handler.read_random(&file_path, offset, &mut buf).await.unwrap() {
-                    
-                    peers.values().filter(|peer| peer.peer_id.eq(&peer_id)).for_each(|peer| {
-                        let command = PeerMessage::PeerCommand {
-                            command: Command::WriteDataCommand { id, peer_id: self.my_peer_id.clone(), file_path: file_path.clone(), offset, data: buf.clone() },
-                        };
-                        let command_json = serde_json::to_string(&command).unwrap();
-                        task::block_on(send_message(peer.stream.clone(), command_json));
-                    });
-                    offset = offset + 256;
-                    buf.clear();
+                self.send_data(&folder, id, &peer_id, &file_path, 0, None, peers).await?;
+            },
+            ExternalToInternal::DataWrite { id, peer_id, file_path, offset, data } => {
+                if !folder.mode().receives() {
+                    Err(format!("Refusing data for {} in send only folder {} from {}", file_path, folder_id, peer_id))?;
+                }
+                let key = (String::from(folder_id), file_path.clone());
+                let transfer = self.transfers.lock().await.get(&key).map(|transfer| (transfer.kind, transfer.peer_id == peer_id));
+                match transfer {
+                    // The download only counts data of the peers it asked for a chunk
+                    Some((TransferKind::Chunks, _)) => return self.write_chunk_data(&folder, id, &peer_id, &file_path, offset, data, peers).await,
+                    Some((TransferKind::Whole, true)) => folder.file_handler.write_partial(&file_path, offset, &data).await?,
+                    Some((TransferKind::Whole, false)) => {
+                        warn!("Dropping data of {} in {} from {}, it was asked from another peer", file_path, folder_id, peer_id);
+                        return Ok(());
+                    }
+                    // Not asked for, or cancelled
+                    Some((TransferKind::Delta, _)) | None => return Ok(()),
+                }
+                let mut transfers = self.transfers.lock().await;
+                let transfer = match transfers.get_mut(&key) {
+                    Some(transfer) => transfer,
+                    None => return Ok(()),
+                };
+                transfer.received += data.len() as u64;
+                transfer.updated_at = now();
+                if transfer.received < transfer.size {
+                    return Ok(());
+                }
+                info!("Received {} bytes of {} in {} from {}", transfer.received, file_path, folder_id, peer_id);
+                if let Some(transfer) = transfers.remove(&key) {
+                    drop(transfers);
+                    if !folder.file_handler.finish_partial(&file_path, &transfer.sha).await? {
+                        self.files_in_update.lock().await.remove(&key);
+                        Err(format!("Received {} in {} from {} does not match {}", file_path, folder_id, peer_id, transfer.sha))?;
+                    }
+                    self.file_synced(&folder, &file_path, &transfer.sha, transfer.peer_id).await;
+                }
+            },
+            ExternalToInternal::NewFileCreate { id: _, peer_id, file_path, sha, size, metadata } => {
+                if !folder.mode().receives() {
+                    self.record_remote_drift(&folder, &peer_id, &file_path, &sha).await;
+                    return Ok(());
+                }
+                check_name(&folder, &file_path)?;
+                if !folder.is_selected(&file_path).await {
+                    return self.record_unselected(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                if let Some(target) = &metadata.symlink {
+                    return self.create_symlink(&folder, &peer_id, &file_path, &sha, target).await;
+                }
+                if self.apply_metadata(&folder, &file_path, &sha, &metadata).await? {
+                    return Ok(());
+                }
+                if self.keeps_placeholder(&folder, &file_path, size).await {
+                    return self.record_placeholder(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                self.pending_metadata.lock().await.insert((String::from(folder_id), file_path.clone()), metadata);
+                self.queue.lock().await.push(QueuedFile::new(folder_id, &file_path, &peer_id, sha, size, false, now()));
+            },
+            ExternalToInternal::FileModify { id: _, peer_id, file_path, sha, size, metadata } => {
+                if !folder.mode().receives() {
+                    self.record_remote_drift(&folder, &peer_id, &file_path, &sha).await;
+                    return Ok(());
+                }
+                check_name(&folder, &file_path)?;
+                if !folder.is_selected(&file_path).await {
+                    return self.record_unselected(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                if let Some(target) = &metadata.symlink {
+                    return self.create_symlink(&folder, &peer_id, &file_path, &sha, target).await;
+                }
+                if self.apply_metadata(&folder, &file_path, &sha, &metadata).await? {
+                    return Ok(());
+                }
+                if self.keeps_placeholder(&folder, &file_path, size).await {
+                    return self.record_placeholder(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                self.pending_metadata.lock().await.insert((String::from(folder_id), file_path.clone()), metadata);
+                self.queue.lock().await.push(QueuedFile::new(folder_id, &file_path, &peer_id, sha, size, true, now()));
+            },
+            ExternalToInternal::FileDelete { id: _, peer_id, file_path, sha } => self.delete_file(&folder, &peer_id, &file_path, &sha).await?,
+            ExternalToInternal::MetadataModify { id: _, peer_id, file_path, sha, metadata } => {
+                if !folder.mode().receives() {
+                    return Ok(());
+                }
+                if !folder.is_selected(&file_path).await {
+                    let size = match folder.index.lock().await.get(&file_path) {
+                        Some(known) if known.sha == sha => known.size,
+                        _ => return Ok(()),
+                    };
+                    return self.record_unselected(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                if folder.is_placeholder(&file_path).await {
+                    let size = match folder.index.lock().await.get(&file_path) {
+                        Some(known) if known.sha == sha => known.size,
+                        _ => return Ok(()),
+                    };
+                    return self.record_placeholder(&folder, &peer_id, &file_path, sha, size, metadata).await;
+                }
+                if self.apply_metadata(&folder, &file_path, &sha, &metadata).await? {
+                    return Ok(());
+                }
+                let key = (String::from(folder_id), file_path.clone());
+                if self.files_in_update.lock().await.get(&key) == Some(&sha) {
+                    // Applied once the download of that version completes
+                    self.pending_metadata.lock().await.insert(key, metadata);
+                } else {
+                    debug!("Not applying metadata of another version of {} in {} from {}", file_path, folder_id, peer_id);
+                }
+            },
+            ExternalToInternal::Signatures { id, peer_id, file_path, block_size, signatures } => {
+                if !fits_block_count(block_size, signatures.len()) {
+                    Err(format!(
+                        "Refusing signatures of {} in {} from {}, {} blocks of {} bytes",
+                        file_path, folder_id, peer_id, signatures.len(), block_size
+                    ))?;
+                }
+                let sha = folder.file_sha(&file_path).await.ok_or(format!("{} in {} no longer exists", file_path, folder_id))?;
+                let instructions = folder.file_handler.delta(&file_path, block_size, signatures).await?;
+                info!(
+                    "Delta of {} in {} for {} sends {} bytes as literal data",
+                    file_path, folder_id, peer_id, literal_size(&instructions)
+                );
+                let peer = match peers.get(&peer_id) {
+                    Some(peer) => peer,
+                    None => return Ok(()),
+                };
+                let mut batches = vec![];
+                let mut batch = vec![];
+                let mut batch_size = 0;
+                for instruction in instructions {
+                    if let DeltaInstruction::Literal { data } = &instruction {
+                        batch_size += data.len();
+                    }
+                    batch.push(instruction);
+                    if batch_size >= MAX_LITERAL_SIZE {
+                        batches.push(std::mem::take(&mut batch));
+                        batch_size = 0;
+                    }
+                }
+                // The last batch is sent even when empty, it tells the receiver the file is complete
+                batches.push(batch);
+                let count = batches.len();
+                let mut lines = Vec::with_capacity(count);
+                for (position, instructions) in batches.into_iter().enumerate() {
+                    let command = PeerMessage::PeerCommand {
+                        command: Command::DeltaCommand {
+                            id,
+                            peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
+                            file_path: file_path.clone(),
+                            sha: sha.clone(),
+                            block_size,
+                            instructions,
+                            last: position + 1 == count,
+                        },
+                    };
+                    lines.push(serde_json::to_string(&command)?);
                 }
+                queue_data(peer, lines);
             },
-            ExternalToInternal::DataWrite { id: _, peer_id: _, file_path, offset, data } => {
-                self.file_handler.write_random(file_path, offset, &data).await.unwrap();
+            ExternalToInternal::Delta { id, peer_id, file_path, sha, block_size, instructions, last } => {
+                if !folder.mode().receives() {
+                    Err(format!("Refusing delta for {} in send only folder {} from {}", file_path, folder_id, peer_id))?;
+                }
+                let key = (String::from(folder_id), file_path.clone());
+                let (first, expected_sha) = match self.transfers.lock().await.get(&key) {
+                    Some(transfer) if transfer.kind == TransferKind::Delta && transfer.peer_id == peer_id => (transfer.received == 0, transfer.sha.clone()),
+                    // Not asked for, asked from another peer, or already downloading it whole after a failed delta
+                    _ => return Ok(()),
+                };
+                if sha != expected_sha {
+                    warn!("Delta of {} in {} from {} is of another version, downloading it whole", file_path, folder_id, peer_id);
+                    return self.request_file_again(&folder, id, &peer_id, &file_path, peers).await;
+                }
+                // The block size the signatures were sent with, unless the local copy changed since
+                let local_size = folder.file_handler.metadata(&file_path).await.map(|metadata| metadata.len()).unwrap_or_default();
+                if block_size != block_size_for(local_size) {
+                    warn!("Delta of {} in {} from {} uses blocks of {} bytes, downloading it whole", file_path, folder_id, peer_id, block_size);
+                    return self.request_file_again(&folder, id, &peer_id, &file_path, peers).await;
+                }
+                match folder.file_handler.apply_delta(&file_path, block_size, instructions, first).await {
+                    Ok(written) => {
+                        if let Some(transfer) = self.transfers.lock().await.get_mut(&key) {
+                            transfer.received += written;
+                            transfer.updated_at = now();
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Cannot rebuild {} in {} from the delta, downloading it whole {}", file_path, folder_id, err);
+                        return self.request_file_again(&folder, id, &peer_id, &file_path, peers).await;
+                    }
+                }
+                if !last {
+                    return Ok(());
+                }
+                if !folder.file_handler.finish_partial(&file_path, &expected_sha).await? {
+                    warn!("Rebuilt {} in {} does not match {}, downloading it whole", file_path, folder_id, expected_sha);
+                    return self.request_file_again(&folder, id, &peer_id, &file_path, peers).await;
+                }
+                if let Some(transfer) = self.transfers.lock().await.remove(&key) {
+                    info!("Rebuilt {} bytes of {} in {} with a delta from {}", transfer.received, file_path, folder_id, peer_id);
+                }
+                self.file_synced(&folder, &file_path, &expected_sha, peer_id).await;
             },
-            ExternalToInternal::NewFileCreate { id, peer_id, file_path, sha } => {
-                if self.file_handler.create_file(&file_path,&sha).await.unwrap() {
-                    let files_in_update = self.files_in_update.clone();
-                    let mut files_in_update = files_in_update.lock().await;
-                    files_in_update.insert(file_path.clone(), sha);
-                    peers.values().filter(|peer| peer.peer_id.eq(&peer_id)).for_each(|peer| {
-                        let command = PeerMessage::PeerCommand {
-                            command: Command::DataRequestCommand {
-                                id,
-                                peer_id: self.my_peer_id.clone(),
-                                file_path: file_path.clone(),
-                            },
-                        };
-                        let command_json = serde_json::to_string(&command).unwrap();
-                        task::block_on(send_message(peer.stream.clone(), command_json));
-                    });
+            ExternalToInternal::ManifestRequest { id, peer_id, file_path } => {
+                // Asked from every peer sharing the folder, only the ones that have the file answer
+                let (sha, size, chunks) = match folder.manifest(&file_path).await {
+                    Some(manifest) => manifest,
+                    None => {
+                        debug!("No {} in {} to send the chunks of to {}", file_path, folder_id, peer_id);
+                        return Ok(());
+                    }
+                };
+                if let Some(peer) = peers.get(&peer_id) {
+                    let command = PeerMessage::PeerCommand {
+                        command: Command::ManifestCommand {
+                            id,
+                            peer_id: self.my_peer_id.clone(),
+                            folder_id: String::from(folder_id),
+                            file_path,
+                            sha,
+                            size,
+                            chunks,
+                        },
+                    };
+                    queue_data(peer, vec![serde_json::to_string(&command)?]);
+                }
+            },
+            ExternalToInternal::Manifest { id, peer_id, file_path, sha, size, chunks } => {
+                if !folder.mode().receives() {
+                    Err(format!("Refusing manifest of {} in send only folder {} from {}", file_path, folder_id, peer_id))?;
+                }
+                check_manifest(size, &chunks).map_err(|err| format!("Refusing manifest of {} in {} from {}, {}", file_path, folder_id, peer_id, err))?;
+                let key = (String::from(folder_id), file_path.clone());
+                if let Some(download) = self.downloads.lock().await.get_mut(&key) {
+                    let same_version = self.transfers.lock().await.get(&key).is_some_and(|transfer| transfer.sha == sha);
+                    if same_version {
+                        debug!("Also downloading {} in {} from {}", file_path, folder_id, peer_id);
+                        download.add_source(&peer_id);
+                        let requests = download.schedule(&*self.rates.lock().await);
+                        self.request_chunks_from(&folder, id, &file_path, requests, peers).await?;
+                    }
+                    return Ok(());
+                }
+                match self.transfers.lock().await.get_mut(&key) {
+                    // The peer that announced the file may have changed it again since
+                    Some(transfer) if transfer.kind == TransferKind::Chunks && (transfer.sha == sha || transfer.peer_id == peer_id) => {
+                        transfer.sha = sha.clone();
+                        transfer.size = size;
+                    }
+                    _ => return Ok(()),
+                }
+                self.files_in_update.lock().await.insert(key.clone(), sha.clone());
+                let mut verified = self.resumable(&folder, &file_path, &sha, size).await;
+                let resumed: u64 = chunks.iter().filter(|chunk| verified.contains(&chunk.offset)).map(|chunk| chunk.size).sum();
+                if resumed > 0 {
+                    info!("Resuming {} in {} with {} of {} bytes already verified", file_path, folder_id, resumed, size);
+                } else if let Err(err) = folder.file_handler.start_partial(&file_path, size).await {
+                    warn!("Cannot build {} in {} from chunks, downloading it whole {}", file_path, folder_id, err);
+                    return self.request_file_again(&folder, id, &peer_id, &file_path, peers).await;
+                }
+                let local_chunks = folder.local_chunks(&chunks).await;
+                let mut reused = 0;
+                let mut missing = vec![];
+                for chunk in &chunks {
+                    if verified.contains(&chunk.offset) {
+                        continue;
+                    }
+                    let copied = match local_chunks.get(&chunk.hash) {
+                        Some((source, source_offset)) => matches!(folder.file_handler.copy_chunk(source, *source_offset, chunk, &file_path).await, Ok(true)),
+                        None => false,
+                    };
+                    if copied {
+                        reused += chunk.size;
+                        verified.insert(chunk.offset);
+                    } else {
+                        missing.push(chunk.clone());
+                    }
+                }
+                info!(
+                    "Reusing {} of {} bytes of {} in {} from local chunks, downloading {} chunks",
+                    reused, size, file_path, folder_id, missing.len()
+                );
+                let mut transfer_states = folder.transfer_states.lock().await;
+                transfer_states.insert(file_path.clone(), TransferState {
+                    sha: sha.clone(),
+                    size,
+                    peer_id: peer_id.clone(),
+                    chunks,
+                    verified,
+                });
+                if let Err(err) = transfer_states.save(folder.root(), &folder.file_handler).await {
+                    warn!("Cannot save the transfers of {} {}", folder.root(), err);
+                }
+                drop(transfer_states);
+                if let Some(transfer) = self.transfers.lock().await.get_mut(&key) {
+                    transfer.received = resumed + reused;
+                    transfer.updated_at = now();
+                }
+                if missing.is_empty() {
+                    return self.finish_chunks(&folder, id, &peer_id, &file_path, peers).await;
+                }
+                let mut download = Download::new(missing);
+                download.add_source(&peer_id);
+                let requests = download.schedule(&*self.rates.lock().await);
+                self.downloads.lock().await.insert(key, download);
+                self.request_chunks_from(&folder, id, &file_path, requests, peers).await?;
+            },
+            ExternalToInternal::ChunkRequest { id, peer_id, file_path, chunks } => {
+                for chunk in chunks {
+                    self.send_data(&folder, id, &peer_id, &file_path, chunk.offset, Some(chunk.size), peers).await?;
+                }
+            },
+            ExternalToInternal::ContentUnavailable { id: _, peer_id, file_path } => {
+                let key = (String::from(folder_id), file_path);
+                self.placeholder_peers.lock().await.entry(key.clone()).or_default().insert(peer_id.clone());
+                let removed = self.downloads.lock().await.get_mut(&key).map(|download| download.remove_source(&peer_id));
+                if removed == Some(true) {
+                    self.reschedule(&key, peers).await;
+                } else if removed.is_none() && self.transfers.lock().await.get(&key).is_some_and(|transfer| transfer.peer_id == peer_id) {
+                    info!("{} only has a placeholder of {} in {}, asking another peer", peer_id, key.1, key.0);
+                    self.give_up_transfer(&key, peers).await;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Starts queued downloads until `max_concurrent` files are being downloaded
+    async fn start_queued(&self, peers: &HashMap<String, Peer>) {
+        if self.queue.lock().await.is_empty() || *self.paused.lock().await {
+            return;
+        }
+        let queue_config = self.config.lock().await.queue;
+        while self.transfers.lock().await.len() < queue_config.max_concurrent {
+            let file = match self.queue.lock().await.pop(queue_config.order) {
+                Some(file) => file,
+                None => break,
+            };
+            let folder = match self.folder(&file.folder_id).await {
+                Some(folder) => folder,
+                None => continue,
+            };
+            debug!("Starting queued download of {} in {} from {}", file.file_path, file.folder_id, file.peer_id);
+            let result = if file.modified && peers.contains_key(&file.peer_id) {
+                self.start_modified(&folder, Uuid::new_v4(), file, peers).await
+            } else {
+                // Any peer with the version can send the chunks, not only the one that announced it
+                self.start_new_file(&folder, Uuid::new_v4(), file, peers).await
+            };
+            if let Err(err) = result {
+                metrics().transfer_failed();
+                self.record_error(err.to_string()).await;
+            }
+        }
+    }
+
+    /// Downloads a file a peer created, from the chunks of local files where possible
+    async fn start_new_file(&self, folder: &SharedFolder, id: Uuid, file: QueuedFile, peers: &HashMap<String, Peer>) -> Result<()> {
+        let QueuedFile { folder_id, file_path, peer_id, sha, size, .. } = file;
+        let placeholder = folder.is_placeholder(&file_path).await;
+        if let Some(local_sha) = folder.file_sha(&file_path).await.filter(|_| !placeholder) {
+            if !local_sha.eq(&sha) {
+                warn!("Local {} in {} differs from the version sent by {}", file_path, folder_id, peer_id);
+                self.record_conflict(folder, &file_path, &peer_id, local_sha, &sha).await;
+            }
+        }
+        self.request_chunks(folder, id, &peer_id, &file_path, sha, size, peers).await
+    }
 
+    ///
+    /// Forgets a file or every file below a folder that was deleted, and tells the peers to delete
+    /// the ones that were synced
+    /// # Arguments
+    /// * `path` - The deleted path, replaced rather than deleted when it exists again
+    /// * `members` - The peers of the folder
+    ///
+    async fn announce_deleted(&self, folder: &SharedFolder, id: Uuid, path: &str, members: &[&Peer]) -> Result<()> {
+        if folder.file_handler.metadata(path).await.is_some() {
+            debug!("{} in {} exists again, not announcing its deletion", path, folder.id());
+            return Ok(());
+        }
+        // Files the sync deleted were forgotten before, they are not announced back
+        for (file, entry) in folder.forget(path).await.into_iter().filter(|(_, entry)| entry.presence.is_local()) {
+            debug!("Announcing the deletion of {} in {}", file, folder.id());
+            let command = PeerMessage::PeerCommand {
+                command: Command::DeleteFile {
+                    id,
+                    peer_id: self.my_peer_id.clone(),
+                    folder_id: String::from(folder.id()),
+                    file_path: file,
+                    sha: entry.sha,
+                },
+            };
+            let command_json = serde_json::to_string(&command)?;
+            for peer in members {
+                send_message(peer, command_json.clone()).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a file a peer deleted, a local copy that changed since the version the peer had is kept
+    async fn delete_file(&self, folder: &SharedFolder, peer_id: &str, file_path: &str, sha: &str) -> Result<()> {
+        let key = (String::from(folder.id()), String::from(file_path));
+        self.queue.lock().await.remove(&key);
+        if self.transfers.lock().await.contains_key(&key) {
+            self.cancel_download(folder, &key).await;
+        }
+        self.sources.lock().await.remove(&key);
+        self.placeholder_peers.lock().await.remove(&key);
+        let entry = match folder.index.lock().await.get(file_path).cloned() {
+            Some(entry) => entry,
+            // Never synced, or deleted already
+            None => return Ok(()),
+        };
+        let exists = folder.file_handler.metadata(file_path).await.is_some_and(|metadata| !metadata.is_dir());
+        if exists && entry.presence.is_local() && folder.file_sha(file_path).await.as_deref() != Some(sha) {
+            Err(format!("{} deleted {} in {} but it changed here since, keeping it", peer_id, file_path, folder.id()))?;
+        }
+        folder.forget(file_path).await;
+        if exists {
+            match entry.presence {
+                Presence::Local => {
+                    folder.file_handler.delete_file(String::from(file_path)).await?;
+                    folder.file_handler.remove_empty_parents(file_path).await;
                 }
+                // Holds no content of its own
+                Presence::Placeholder | Presence::Unselected => folder.file_handler.remove_local_copy(file_path).await?,
+            }
+        }
+        info!("Deleted {} in {} as {} did", file_path, folder.id(), peer_id);
+        Ok(())
+    }
+
+    /// Keeps a copy of a local file the version of a peer is about to replace, and lists the conflict
+    async fn record_conflict(&self, folder: &SharedFolder, file_path: &str, peer_id: &str, local_sha: String, remote_sha: &str) {
+        let copy = match folder.file_handler.keep_conflict_copy(file_path, peer_id).await {
+            Ok(copy) => {
+                info!("Kept the local version of {} in {} as {}", file_path, folder.id(), copy);
+                Some(copy)
+            }
+            Err(err) => {
+                self.record_error(format!("Cannot keep the local version of {} in {} {}", file_path, folder.id(), err)).await;
+                None
+            }
+        };
+        self.conflicts.lock().await.push(Conflict {
+            folder_id: String::from(folder.id()),
+            file_path: String::from(file_path),
+            peer_id: String::from(peer_id),
+            local_sha,
+            remote_sha: String::from(remote_sha),
+            copy,
+            detected_at: now(),
+        });
+    }
+
+    /// Rebuilds a file a peer modified from the local copy and a delta
+    async fn start_modified(&self, folder: &SharedFolder, id: Uuid, file: QueuedFile, peers: &HashMap<String, Peer>) -> Result<()> {
+        let QueuedFile { folder_id, file_path, peer_id, sha, size, .. } = file;
+        let local_size = match folder.file_handler.metadata(&file_path).await {
+            Some(metadata) if metadata.is_file() => metadata.len(),
+            _ => 0,
+        };
+        if local_size == 0 {
+            // Nothing to reuse, downloaded in chunks so that it resumes after a disconnect
+            return self.request_chunks(folder, id, &peer_id, &file_path, sha, size, peers).await;
+        }
+        let local_sha = folder.file_sha(&file_path).await;
+        if local_sha.as_deref() == Some(sha.as_str()) {
+            return Ok(());
+        }
+        let indexed_sha = folder.index.lock().await.get(&file_path).map(|entry| entry.sha.clone());
+        if let (Some(local_sha), Some(indexed_sha)) = (local_sha, indexed_sha) {
+            if local_sha != indexed_sha {
+                warn!("Local {} in {} changed since the last sync and was modified by {}", file_path, folder_id, peer_id);
+                self.record_conflict(folder, &file_path, &peer_id, local_sha, &sha).await;
+            }
+        }
+        let block_size = block_size_for(local_size);
+        let signatures = folder.file_handler.block_signatures(&file_path, block_size).await?;
+        let key = (folder_id.clone(), file_path.clone());
+        self.transfers.lock().await.insert(key.clone(), Transfer {
+            folder_id: folder_id.clone(),
+            file_path: file_path.clone(),
+            peer_id: peer_id.clone(),
+            sha: sha.clone(),
+            size,
+            received: 0,
+            kind: TransferKind::Delta,
+            started_at: now(),
+            updated_at: now(),
+        });
+        self.files_in_update.lock().await.insert(key, sha);
+        if let Some(peer) = peers.get(&peer_id) {
+            let command = PeerMessage::PeerCommand {
+                command: Command::SignatureCommand {
+                    id,
+                    peer_id: self.my_peer_id.clone(),
+                    folder_id,
+                    file_path,
+                    block_size,
+                    signatures,
+                },
+            };
+            queue_data(peer, vec![serde_json::to_string(&command)?]);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Downloads a file whole from a peer into its partial file, unless it already has the given SHA
+    /// # Arguments
+    /// * `peer_id` - The peer that announced the file
+    /// * `size` - Size of the announced version
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn request_file(
+        &self,
+        folder: &SharedFolder,
+        id: Uuid,
+        peer_id: &str,
+        file_path: &str,
+        sha: String,
+        size: u64,
+        peers: &HashMap<String, Peer>,
+    ) -> Result<()> {
+        if folder.file_sha(file_path).await.as_deref() == Some(sha.as_str()) {
+            return Ok(());
+        }
+        let key = (String::from(folder.id()), String::from(file_path));
+        self.files_in_update.lock().await.insert(key.clone(), sha.clone());
+        if size == 0 {
+            folder.file_handler.create_file(file_path).await?;
+            self.file_synced(folder, file_path, &sha, String::from(peer_id)).await;
+            return Ok(());
+        }
+        folder.file_handler.start_partial(file_path, size).await?;
+        self.transfers.lock().await.insert(key, Transfer {
+            folder_id: String::from(folder.id()),
+            file_path: String::from(file_path),
+            peer_id: String::from(peer_id),
+            sha,
+            size,
+            received: 0,
+            kind: TransferKind::Whole,
+            started_at: now(),
+            updated_at: now(),
+        });
+        if let Some(peer) = peers.get(peer_id) {
+            let command = PeerMessage::PeerCommand {
+                command: Command::DataRequestCommand {
+                    id,
+                    peer_id: self.my_peer_id.clone(),
+                    folder_id: String::from(folder.id()),
+                    file_path: String::from(file_path),
+                },
+            };
+            let command_json = serde_json::to_string(&command)?;
+            send_message(peer, command_json).await;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Builds a file from the chunks of local files and downloads only the chunks missing
+    /// locally, unless it already has the given SHA
+    /// # Arguments
+    /// * `peer_id` - The peer that announced the file
+    /// * `size` - Size of the announced version
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn request_chunks(
+        &self,
+        folder: &SharedFolder,
+        id: Uuid,
+        peer_id: &str,
+        file_path: &str,
+        sha: String,
+        size: u64,
+        peers: &HashMap<String, Peer>,
+    ) -> Result<()> {
+        if size == 0 {
+            return self.request_file(folder, id, peer_id, file_path, sha, size, peers).await;
+        }
+        if folder.file_sha(file_path).await.as_deref() == Some(sha.as_str()) {
+            return Ok(());
+        }
+        let key = (String::from(folder.id()), String::from(file_path));
+        let mut transfers = self.transfers.lock().await;
+        if transfers.get(&key).is_some_and(|transfer| transfer.kind == TransferKind::Chunks && transfer.sha == sha) {
+            // Announced by another peer too, it was already asked for the chunks
+            return Ok(());
+        }
+        transfers.insert(key.clone(), Transfer {
+            folder_id: String::from(folder.id()),
+            file_path: String::from(file_path),
+            peer_id: String::from(peer_id),
+            sha: sha.clone(),
+            size,
+            received: 0,
+            kind: TransferKind::Chunks,
+            started_at: now(),
+            updated_at: now(),
+        });
+        drop(transfers);
+        self.files_in_update.lock().await.insert(key.clone(), sha);
+        // Every peer with the same version becomes a source, not only the one that announced it
+        let command = PeerMessage::PeerCommand {
+            command: Command::ManifestRequestCommand {
+                id,
+                peer_id: self.my_peer_id.clone(),
+                folder_id: String::from(folder.id()),
+                file_path: String::from(file_path),
             },
+        };
+        let command_json = serde_json::to_string(&command)?;
+        let placeholder_peers = self.placeholder_peers.lock().await.get(&key).cloned().unwrap_or_default();
+        for peer in peers.values().filter(|peer| folder.config.is_shared_with(&peer.peer_id) && !placeholder_peers.contains(&peer.peer_id)) {
+            send_message(peer, command_json.clone()).await;
+        }
+        Ok(())
+    }
+
+    /// Sends the chunk requests the scheduler of a download handed out
+    async fn request_chunks_from(
+        &self,
+        folder: &SharedFolder,
+        id: Uuid,
+        file_path: &str,
+        requests: Vec<(String, Vec<Chunk>)>,
+        peers: &HashMap<String, Peer>,
+    ) -> Result<()> {
+        for (peer_id, chunks) in requests {
+            let peer = match peers.get(&peer_id) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            debug!("Requesting {} chunks of {} in {} from {}", chunks.len(), file_path, folder.id(), peer_id);
+            let command = PeerMessage::PeerCommand {
+                command: Command::ChunkRequestCommand {
+                    id,
+                    peer_id: self.my_peer_id.clone(),
+                    folder_id: String::from(folder.id()),
+                    file_path: String::from(file_path),
+                    chunks,
+                },
+            };
+            let command_json = serde_json::to_string(&command)?;
+            send_message(peer, command_json).await;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Writes data of a chunk transfer, hands out more chunks to the peer whenever it completes one
+    /// and moves the file into place once every chunk arrived
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn write_chunk_data(
+        &self,
+        folder: &SharedFolder,
+        id: Uuid,
+        peer_id: &str,
+        file_path: &str,
+        offset: u64,
+        data: Vec<u8>,
+        peers: &HashMap<String, Peer>,
+    ) -> Result<()> {
+        let key = (String::from(folder.id()), String::from(file_path));
+        let progress = match self.downloads.lock().await.get_mut(&key) {
+            Some(download) => download.received(peer_id, offset, data.len() as u64, &mut *self.rates.lock().await),
+            None => Progress::Ignored,
+        };
+        let chunk = match progress {
+            Progress::Ignored => return Ok(()),
+            Progress::Partial => return folder.file_handler.write_partial(file_path, offset, &data).await,
+            Progress::ChunkDone(chunk) => chunk,
+        };
+        folder.file_handler.write_partial(file_path, offset, &data).await?;
+        let valid = folder.file_handler.verify_partial(file_path, &chunk).await?;
+        if valid {
+            let mut transfer_states = folder.transfer_states.lock().await;
+            transfer_states.verified(file_path, chunk.offset);
+            if let Err(err) = transfer_states.save_every(folder.root(), &folder.file_handler, STATE_SAVE_INTERVAL).await {
+                warn!("Cannot save the transfers of {} {}", folder.root(), err);
+            }
+            drop(transfer_states);
+            if let Some(transfer) = self.transfers.lock().await.get_mut(&key) {
+                transfer.received += chunk.size;
+                transfer.updated_at = now();
+            }
+        }
+        let mut downloads = self.downloads.lock().await;
+        let download = match downloads.get_mut(&key) {
+            Some(download) => download,
+            None => return Ok(()),
+        };
+        if !valid {
+            warn!("Chunk at {} of {} in {} from {} does not match its hash, asking again", chunk.offset, file_path, folder.id(), peer_id);
+            download.retry(chunk);
+        }
+        if download.is_complete() {
+            downloads.remove(&key);
+            drop(downloads);
+            return self.finish_chunks(folder, id, peer_id, file_path, peers).await;
+        }
+        let requests = download.schedule(&*self.rates.lock().await);
+        drop(downloads);
+        self.request_chunks_from(folder, id, file_path, requests, peers).await
+    }
+
+    /// A peer left, the chunks it was sending are asked from the other sources
+    async fn source_left(&self, peer_id: &str, peers: &HashMap<String, Peer>) {
+        let keys: Vec<FileKey> = self
+            .downloads
+            .lock()
+            .await
+            .iter_mut()
+            .filter_map(|(key, download)| download.remove_source(peer_id).then(|| key.clone()))
+            .collect();
+        for key in keys {
+            self.reschedule(&key, peers).await;
+        }
+        let left = self.unscheduled_transfers(|transfer| transfer.peer_id == peer_id).await;
+        for key in left {
+            info!("{} left while sending {} in {}", peer_id, key.1, key.0);
+            self.give_up_transfer(&key, peers).await;
+        }
+    }
+
+    /// Downloads `matches` is true for that are not handed out by chunks to several peers
+    async fn unscheduled_transfers(&self, matches: impl Fn(&Transfer) -> bool) -> Vec<FileKey> {
+        let downloads = self.downloads.lock().await;
+        self.transfers
+            .lock()
+            .await
+            .iter()
+            .filter(|(key, transfer)| !downloads.contains_key(*key) && matches(transfer))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Cancels a download whose peer left or stopped sending, a connected peer of the folder is
+    /// asked for the file from the start
+    async fn give_up_transfer(&self, key: &FileKey, peers: &HashMap<String, Peer>) {
+        let (sha, size, kind) = match self.transfers.lock().await.get(key) {
+            Some(transfer) => (transfer.sha.clone(), transfer.size, transfer.kind),
+            None => return,
+        };
+        let folder = match self.folder(&key.0).await {
+            Some(folder) => folder,
+            None => {
+                self.transfers.lock().await.remove(key);
+                return;
+            }
+        };
+        let metadata = self.pending_metadata.lock().await.get(key).cloned();
+        self.cancel_download(&folder, key).await;
+        let source = self.source(&folder, &key.1, peers).await.map(|peer| peer.peer_id.clone());
+        match source {
+            Some(source) => {
+                if let Some(metadata) = metadata {
+                    self.pending_metadata.lock().await.insert(key.clone(), metadata);
+                }
+                let modified = kind == TransferKind::Delta;
+                self.queue.lock().await.push(QueuedFile::new(&key.0, &key.1, &source, sha, size, modified, now()));
+            }
+            None => self.record_error(format!("No peer left to download {} in {} from", key.1, key.0)).await,
+        }
+    }
+
+    /// Takes their chunks away from peers that stopped sending
+    async fn check_downloads(&self, peers: &HashMap<String, Peer>) {
+        let now = Instant::now();
+        let mut stalled = vec![];
+        for (key, download) in self.downloads.lock().await.iter_mut() {
+            let timed_out = download.expire(now);
+            if !timed_out.is_empty() {
+                warn!("No data of {} in {} from {:?} for a while, asking other peers", key.1, key.0, timed_out);
+                stalled.push(key.clone());
+            }
+        }
+        for key in stalled {
+            self.reschedule(&key, peers).await;
         }
+        let timeout = self::now().saturating_sub(TRANSFER_TIMEOUT);
+        for key in self.unscheduled_transfers(|transfer| transfer.updated_at < timeout).await {
+            warn!("No data of {} in {} for {} seconds, downloading it again", key.1, key.0, TRANSFER_TIMEOUT);
+            self.give_up_transfer(&key, peers).await;
+        }
+        for folder in self.folders.read().await.values() {
+            if let Err(err) = folder.transfer_states.lock().await.save(folder.root(), &folder.file_handler).await {
+                warn!("Cannot save the transfers of {} {}", folder.root(), err);
+            }
+        }
+    }
+
+    ///
+    /// The chunks of a file already verified in its partial file by an earlier attempt
+    /// # Returns
+    /// * `BTreeSet<u64>` - Offsets of the verified chunks, empty when the earlier attempt was for
+    ///   another version or its partial file is gone
+    ///
+    async fn resumable(&self, folder: &SharedFolder, file_path: &str, sha: &str, size: u64) -> BTreeSet<u64> {
+        let state = folder.transfer_states.lock().await.get(file_path).cloned();
+        match state {
+            Some(state) if state.sha == sha && folder.file_handler.partial_size(file_path).await == Some(size) => state.verified,
+            _ => BTreeSet::new(),
+        }
+    }
+
+    /// Queues the files whose download stopped before it finished, a peer that just said how it
+    /// hashes its folders may have them
+    async fn resume_transfers(&self, peer_id: &str) {
+        let folders: Vec<Arc<SharedFolder>> = self.folders.read().await.values().cloned().collect();
+        for folder in folders.iter().filter(|folder| folder.config.is_shared_with(peer_id) && folder.mode().receives()) {
+            if self.peer_hash(peer_id, folder.id()).await != Some(folder.config.hash) {
+                continue;
+            }
+            let states: Vec<(String, TransferState)> =
+                folder.transfer_states.lock().await.files().map(|(file, state)| (file.clone(), state.clone())).collect();
+            for (file, state) in states {
+                let key = (String::from(folder.id()), file.clone());
+                if self.transfers.lock().await.contains_key(&key) {
+                    continue;
+                }
+                if folder.file_sha(&file).await.as_deref() == Some(state.sha.as_str()) {
+                    folder.transfer_states.lock().await.remove(&file);
+                    let _ = async_std::fs::remove_file(folder.file_handler.partial_path(&file)).await;
+                    continue;
+                }
+                info!("Resuming the download of {} in {}, {} of {} bytes verified", file, folder.id(), state.verified_size(), state.size);
+                self.queue.lock().await.push(QueuedFile::new(folder.id(), &file, peer_id, state.sha, state.size, false, now()));
+            }
+        }
+    }
+
+    /// Hands out the queued chunks of a download again, gives up when no peer is left to ask
+    async fn reschedule(&self, key: &FileKey, peers: &HashMap<String, Peer>) {
+        let folder = match self.folder(&key.0).await {
+            Some(folder) => folder,
+            None => return,
+        };
+        let mut downloads = self.downloads.lock().await;
+        let download = match downloads.get_mut(key) {
+            Some(download) => download,
+            None => return,
+        };
+        if !download.has_sources() {
+            // The partial file and its verified chunks are kept until a peer with the file connects
+            downloads.remove(key);
+            drop(downloads);
+            self.transfers.lock().await.remove(key);
+            self.files_in_update.lock().await.remove(key);
+            self.record_error(format!("No peer left to download {} in {} from, resuming once one connects", key.1, key.0)).await;
+            return;
+        }
+        let requests = download.schedule(&*self.rates.lock().await);
+        drop(downloads);
+        if let Err(err) = self.request_chunks_from(&folder, Uuid::new_v4(), &key.1, requests, peers).await {
+            self.record_error(err.to_string()).await;
+        }
+    }
+
+    /// Moves a file built from chunks into place once every chunk was written
+    async fn finish_chunks(&self, folder: &SharedFolder, id: Uuid, peer_id: &str, file_path: &str, peers: &HashMap<String, Peer>) -> Result<()> {
+        let key = (String::from(folder.id()), String::from(file_path));
+        let sha = match self.transfers.lock().await.get(&key) {
+            Some(transfer) => transfer.sha.clone(),
+            None => return Ok(()),
+        };
+        folder.transfer_states.lock().await.remove(file_path);
+        if !folder.file_handler.finish_partial(file_path, &sha).await? {
+            warn!("Built {} in {} does not match {}, downloading it whole", file_path, folder.id(), sha);
+            return self.request_file_again(folder, id, peer_id, file_path, peers).await;
+        }
+        if let Some(transfer) = self.transfers.lock().await.remove(&key) {
+            info!("Built {} bytes of {} in {} from chunks of {}", transfer.received, file_path, folder.id(), peer_id);
+        }
+        self.file_synced(folder, file_path, &sha, String::from(peer_id)).await;
+        Ok(())
+    }
+
+    ///
+    /// Whether a local change only reports a write of the sync itself, either one still in
+    /// progress or one that completed and left the file as indexed. Any other content is a user
+    /// edit and announced, also while the file is being downloaded.
+    ///
+    async fn is_echo(&self, folder: &SharedFolder, file_path: &str, sha: &str) -> bool {
+        let key = (String::from(folder.id()), String::from(file_path));
+        if self.files_in_update.lock().await.get(&key).is_some_and(|expected| expected == sha) {
+            return true;
+        }
+        match folder.index.lock().await.get(file_path) {
+            // Written by the sync as long as it stays empty, the hash is of the content it stands for
+            Some(entry) if entry.presence == Presence::Placeholder => sha == folder.config.hash.digest(&[]),
+            Some(entry) => entry.sha == sha,
+            None => false,
+        }
+    }
+
+    /// Indexes a file fully received from a peer, the watcher events of its last write are
+    /// recognized by the index from now on
+    async fn file_synced(&self, folder: &SharedFolder, file_path: &str, sha: &str, peer_id: String) {
+        let key = (String::from(folder.id()), String::from(file_path));
+        if let Some(metadata) = self.pending_metadata.lock().await.remove(&key) {
+            if let Err(err) = folder.file_handler.set_metadata(file_path, &metadata).await {
+                warn!("Cannot set the permissions and modification time of {} in {} {}", file_path, folder.id(), err);
+            }
+        }
+        folder.index_file(file_path, sha).await;
+        self.files_in_update.lock().await.remove(&key);
+        self.sources.lock().await.insert(key.clone(), peer_id);
+        self.drift.lock().await.remove(&key);
+        metrics().file_synced();
+    }
+
+    ///
+    /// Gives the local copy of a file the permissions and modification time a peer announced,
+    /// when its content is the announced version
+    /// # Returns
+    /// * `bool` - False when the local copy is another version or does not exist
+    ///
+    async fn apply_metadata(&self, folder: &SharedFolder, file_path: &str, sha: &str, metadata: &FileMetadata) -> Result<bool> {
+        if folder.file_sha(file_path).await.as_deref() != Some(sha) {
+            return Ok(false);
+        }
+        if folder.file_handler.file_metadata(file_path).await.as_ref() != Some(metadata) {
+            folder.file_handler.set_metadata(file_path, metadata).await?;
+            folder.index_file(file_path, sha).await;
+        }
+        Ok(true)
+    }
+
+    /// Creates a link a peer announced, unless it points outside the folder
+    async fn create_symlink(&self, folder: &SharedFolder, peer_id: &str, file_path: &str, sha: &str, target: &str) -> Result<()> {
+        if !is_link_inside(Path::new(folder.root()), file_path, target) {
+            Err(format!("Refusing link {} in {} from {} to {} outside the folder", file_path, folder.id(), peer_id, target))?;
+        }
+        if folder.file_sha(file_path).await.as_deref() == Some(sha) {
+            return Ok(());
+        }
+        let key = (String::from(folder.id()), String::from(file_path));
+        self.queue.lock().await.remove(&key);
+        self.files_in_update.lock().await.insert(key.clone(), String::from(sha));
+        if let Err(err) = folder.file_handler.create_symlink(file_path, target).await {
+            self.files_in_update.lock().await.remove(&key);
+            return Err(err);
+        }
+        info!("Linked {} in {} to {} as {} did", file_path, folder.id(), target, peer_id);
+        self.file_synced(folder, file_path, sha, String::from(peer_id)).await;
         Ok(())
     }
 
+    /// The metadata announced with a local file, None for links pointing outside the folder,
+    /// those are never synced
+    async fn announced_metadata(&self, folder: &SharedFolder, file_path: &str) -> Option<FileMetadata> {
+        let metadata = folder.file_handler.file_metadata(file_path).await.unwrap_or_default();
+        if let Some(target) = metadata.symlink.as_deref().filter(|target| !is_link_inside(Path::new(folder.root()), file_path, target)) {
+            warn!("Not syncing link {} in {} to {} outside the folder", file_path, folder.id(), target);
+            return None;
+        }
+        Some(metadata)
+    }
+
+    ///
+    /// Sends part of a file to a peer as `WriteDataCommand`s. The file is read by a task of its own
+    /// that waits for room in the data queue of the peer.
+    /// # Arguments
+    /// * `offset` - Where to start reading
+    /// * `length` - Bytes to send, up to the end of the file when None
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn send_data(
+        &self,
+        folder: &SharedFolder,
+        id: Uuid,
+        peer_id: &str,
+        file_path: &str,
+        offset: u64,
+        length: Option<u64>,
+        peers: &HashMap<String, Peer>,
+    ) -> Result<()> {
+        let peer = match peers.get(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let mut file = async_std::fs::File::open(folder.file_handler.path(file_path)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let end = length.map(|length| offset + length);
+        let my_peer_id = self.my_peer_id.clone();
+        let folder_id = String::from(folder.id());
+        let file_path = String::from(file_path);
+        // Compressed even for formats that are compressed already, the data is sent as JSON numbers
+        let compression = peer.compression;
+        let data = peer.data.clone();
+        spawn_and_log_error(async move {
+            let mut buf = vec![0; DATA_CHUNK_SIZE];
+            let mut offset = offset;
+            loop {
+                let wanted = match end {
+                    Some(end) if end <= offset => break,
+                    Some(end) => DATA_CHUNK_SIZE.min((end - offset) as usize),
+                    None => DATA_CHUNK_SIZE,
+                };
+                let read = file.read(&mut buf[..wanted]).await?;
+                if read == 0 {
+                    break;
+                }
+                let command = PeerMessage::PeerCommand {
+                    command: Command::WriteDataCommand {
+                        id,
+                        peer_id: my_peer_id.clone(),
+                        folder_id: folder_id.clone(),
+                        file_path: file_path.clone(),
+                        offset,
+                        data: buf[..read].to_vec(),
+                    },
+                };
+                let command_json = compressed(compression, serde_json::to_string(&command)?).await;
+                if data.send(command_json).await.is_err() {
+                    debug!("Stopped sending {} in {}, the peer left", file_path, folder_id);
+                    break;
+                }
+                offset += read as u64;
+            }
+            Ok(())
+        });
+        Ok(())
+    }
+
+    /// Falls back to a whole download when a delta or chunks could not be applied
+    async fn request_file_again(&self, folder: &SharedFolder, id: Uuid, peer_id: &str, file_path: &str, peers: &HashMap<String, Peer>) -> Result<()> {
+        let key = (String::from(folder.id()), String::from(file_path));
+        let transfer = match self.transfers.lock().await.remove(&key) {
+            Some(transfer) => transfer,
+            None => return Ok(()),
+        };
+        self.downloads.lock().await.remove(&key);
+        folder.transfer_states.lock().await.remove(file_path);
+        let _ = async_std::fs::remove_file(folder.file_handler.partial_path(file_path)).await;
+        self.request_file(folder, id, peer_id, file_path, transfer.sha, transfer.size, peers).await
+    }
+
+    ///
+    /// Tells a peer how this node hashes every folder shared with it
+    /// # Arguments
+    /// * `peer` - The peer, just connected or sharing a folder that was just added
+    ///
+    async fn send_hashes(&self, peer: &Peer) {
+        let hashes: HashMap<String, HashAlgorithm> = self
+            .folders
+            .read()
+            .await
+            .values()
+            .filter(|folder| folder.config.is_shared_with(&peer.peer_id))
+            .map(|folder| (String::from(folder.id()), folder.config.hash))
+            .collect();
+        let command = PeerMessage::PeerCommand {
+            command: Command::HashAlgorithms { id: Uuid::new_v4(), peer_id: self.my_peer_id.clone(), hashes },
+        };
+        match serde_json::to_string(&command) {
+            Ok(command_json) => send_message(peer, command_json).await,
+            Err(err) => warn!("Cannot send hash algorithms to {} {}", peer.peer_id, err),
+        }
+    }
+
+    /// Remembers how a peer hashes its folders, a folder hashed differently cannot be synced with it
+    async fn peer_hashes_received(&self, peer_id: String, hashes: HashMap<String, HashAlgorithm>) {
+        let folders: Vec<Arc<SharedFolder>> = self.folders.read().await.values().cloned().collect();
+        for folder in folders.iter().filter(|folder| folder.config.is_shared_with(&peer_id)) {
+            match hashes.get(folder.id()) {
+                Some(hash) if *hash != folder.config.hash => {
+                    let error = format!(
+                        "{} hashes {} with {} and this node with {}, the folder is not synced with it until both use the same",
+                        peer_id,
+                        folder.id(),
+                        hash,
+                        folder.config.hash
+                    );
+                    self.record_error(error).await;
+                }
+                _ => {}
+            }
+        }
+        self.peer_hashes.lock().await.insert(peer_id, hashes);
+    }
+
+    /// How a peer hashes a folder, None until it said so, nothing of the folder is exchanged with it before
+    async fn peer_hash(&self, peer_id: &str, folder_id: &str) -> Option<HashAlgorithm> {
+        self.peer_hashes.lock().await.get(peer_id).and_then(|hashes| hashes.get(folder_id)).copied()
+    }
+
+    async fn folder(&self, folder_id: &str) -> Option<Arc<SharedFolder>> {
+        self.folders.read().await.get(folder_id).cloned()
+    }
+
+    /// Ids of every shared folder, sorted
+    async fn folder_ids(&self) -> Vec<String> {
+        let mut folder_ids: Vec<String> = self.folders.read().await.keys().cloned().collect();
+        folder_ids.sort();
+        folder_ids
+    }
+
+    ///
+    /// Resolves the folder a control request is about
+    /// # Arguments
+    /// * `folder` - Id or path of the folder, every folder when None
+    ///
+    async fn resolve_folders(&self, folder: Option<String>) -> std::result::Result<Vec<String>, String> {
+        match folder {
+            Some(folder) => {
+                let folder_config = self.config.lock().await.folder(&folder).cloned();
+                Ok(vec![folder_config.ok_or(format!("Unknown folder {}", folder))?.id])
+            }
+            None => Ok(self.folder_ids().await),
+        }
+    }
+
+    /// The peer a file came from when it is connected, any other connected peer of the folder if not.
+    /// Never a peer that only has a placeholder of the file.
+    async fn source<'a>(&self, folder: &SharedFolder, file: &str, peers: &'a HashMap<String, Peer>) -> Option<&'a Peer> {
+        let key = (String::from(folder.id()), String::from(file));
+        let source = self.sources.lock().await.get(&key).cloned();
+        let placeholder_peers = self.placeholder_peers.lock().await;
+        let has_content = |peer: &&Peer| !placeholder_peers.get(&key).is_some_and(|holders| holders.contains(&peer.peer_id));
+        source
+            .and_then(|source| peers.get(&source))
+            .filter(has_content)
+            .or_else(|| peers.values().filter(has_content).find(|peer| folder.config.is_shared_with(&peer.peer_id)))
+    }
+
+    async fn start_watching(&self, folder: Arc<SharedFolder>) {
+        let (trigger, listener) = shutdown_channel();
+        self.watchers.lock().await.insert(String::from(folder.id()), trigger);
+        spawn_and_log_error(async_watch(folder, self.watch_config.clone(), self.broker_sender.clone(), listener));
+    }
+
+    async fn replay_deferred(&self, deferred: impl Iterator<Item = InternalMessage>, peers: &mut HashMap<String, Peer>) {
+        for event in deferred {
+            let result = match event {
+                InternalMessage::InternalToExternal { folder_id, message } => self.handle_internal_to_external(&folder_id, message, peers).await,
+                InternalMessage::ExternalToInternal { folder_id, message } => self
+                    .handle_external_to_internal(&folder_id, message, peers)
+                    .await
+                    .inspect_err(|_| metrics().transfer_failed()),
+                InternalMessage::Rescan { id, folder_id } => {
+                    self.rescan(id, &folder_id).await;
+                    Ok(())
+                }
+                InternalMessage::Rescanned { id, folder_id, result } => self.rescanned(&id, &folder_id, result, peers).await,
+                _ => Ok(()),
+            };
+            if let Err(err) = result {
+                self.record_error(err.to_string()).await;
+            }
+        }
+    }
+
+    async fn record_error(&self, message: String) {
+        warn!("Sync error {}", message);
+        let mut errors = self.errors.lock().await;
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(SyncError { message, occurred_at: now() });
+    }
+
+    async fn handle_shutdown(&self, id: &Uuid, peers: &mut HashMap<String, Peer>) {
+        info!("Shutting down id::{}, notifying {} peers", id, peers.len());
+        for peer in peers.values() {
+            self.send_leave(id, peer).await;
+        }
+        peers.clear();
+        self.watchers.lock().await.clear();
+        for folder in self.folders.read().await.values() {
+            if let Err(err) = folder.save_state().await {
+                warn!("Cannot save the state of {} {}", folder.root(), err);
+            }
+        }
+    }
+
+    async fn send_leave(&self, id: &Uuid, peer: &Peer) {
+        let command = PeerMessage::PeerCommand {
+            command: Command::Leave {
+                id: *id,
+                client_id: self.my_peer_id.clone(),
+            },
+        };
+        let command_json = compressed(peer.compression, serde_json::to_string(&command).unwrap()).await;
+        // Goes out before any queued file data, the writer task closes the connection after it
+        let _ = peer.control.send(Outgoing::Leave(command_json)).await;
+    }
+
 }
 
 
@@ -305,7 +2026,316 @@ fn handle_peer_leave(peers: &mut HashMap<String, Peer>, client_id: String, id: &
     }
 }
 
-pub async fn send_message(stream: Arc<TcpStream>, peers_json: String) {
-    let _rs = (&*stream).write_all(peers_json.as_bytes()).await;
-    let _rs = (&*stream).write_all(b"\n").await;
-}
\ No newline at end of file
+/// A path relative to the root of a folder in wire format, paths inside the folder may also be
+/// given absolute
+fn relative_to(folder: &SharedFolder, path: &str) -> String {
+    let relative = Path::new(path).strip_prefix(folder.root()).unwrap_or(Path::new(path));
+    to_wire_path(relative).unwrap_or_else(|_| String::from(path))
+}
+
+/// Refuses a file a peer announced when the folder cannot store it under its own name
+fn check_name(folder: &SharedFolder, file_path: &str) -> Result<()> {
+    folder.config.names.check(file_path)?;
+    if let Some(existing) = folder.config.names.find_collision(Path::new(folder.root()), file_path) {
+        Err(format!("Not syncing {} in {}, its name collides with {}", file_path, folder.id(), existing))?;
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Queues a message for a peer, it goes out before any file data and without waiting for the
+/// upload limit
+pub async fn send_message(peer: &Peer, peers_json: String) {
+    let peers_json = compressed(peer.compression, peers_json).await;
+    if peer.control.send(Outgoing::Message(peers_json)).await.is_err() {
+        debug!("Not sending to {}, the connection is closed", peer.peer_id);
+    }
+}
+
+///
+/// Queues file data for a peer in the given order. A task of its own waits for room in the data
+/// queue of the peer, the broker never waits for the upload limit.
+///
+fn queue_data(peer: &Peer, lines: Vec<String>) {
+    let compression = peer.compression;
+    let data = peer.data.clone();
+    task::spawn(async move {
+        for line in lines {
+            if data.send(compressed(compression, line).await).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+async fn compressed(compression: Option<Compression>, line: String) -> String {
+    match compression {
+        Some(compression) => task::spawn_blocking(move || compress_message(compression, line)).await,
+        None => line,
+    }
+}
+
+///
+/// Writes the messages queued for a peer until it leaves. Messages go out before file data, only
+/// file data waits for the upload limit.
+/// # Arguments
+/// * `peer_id` - The peer, limits and metrics are kept by peer
+/// * `stream` - The connection to the peer
+/// * `control` - Messages of the broker
+/// * `data` - File data in the order it was queued
+///
+async fn write_messages(peer_id: String, stream: Arc<TcpStream>, control: Receiver<Outgoing>, data: Receiver<String>) {
+    let address = stream.peer_addr().ok().map(|address| address.ip());
+    loop {
+        let (line, limited) = select_biased! {
+            outgoing = control.recv().fuse() => match outgoing {
+                Ok(Outgoing::Message(line)) => (line, false),
+                Ok(Outgoing::Leave(line)) => {
+                    let _ = write_line(&peer_id, &stream, &line).await;
+                    // Closing the stream ends the read loop that belongs to this peer
+                    if let Err(err) = stream.shutdown(std::net::Shutdown::Both) {
+                        warn!("Error {:?} closing connection to {}", err, peer_id);
+                    }
+                    break;
+                }
+                // The broker dropped the peer, it left
+                Err(_) => break,
+            },
+            line = data.recv().fuse() => match line {
+                Ok(line) => (line, true),
+                Err(_) => break,
+            },
+        };
+        if limited {
+            limiter().acquire(Direction::Upload, &peer_id, address, line.len() as u64 + 1).await;
+        }
+        if let Err(err) = write_line(&peer_id, &stream, &line).await {
+            debug!("Stopped writing to {} {}", peer_id, err);
+            break;
+        }
+    }
+}
+
+async fn write_line(peer_id: &str, stream: &TcpStream, line: &str) -> std::io::Result<()> {
+    let mut stream = stream;
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    metrics().add_bytes_sent(peer_id, line.len() as u64 + 1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::sync::RwLock;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{folder::FolderConfig, io::index::IndexEntry};
+
+    const FOLDER: &str = "docs";
+
+    /// A broker sharing one empty folder, which is removed with it
+    struct TestBroker {
+        broker: Broker,
+        root: std::path::PathBuf,
+    }
+
+    impl std::ops::Deref for TestBroker {
+        type Target = Broker;
+
+        fn deref(&self) -> &Broker {
+            &self.broker
+        }
+    }
+
+    impl Drop for TestBroker {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    async fn test_broker(max_concurrent: usize) -> TestBroker {
+        let root = std::env::temp_dir().join(format!("decen-broker-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let folder: FolderConfig = serde_json::from_value(json!({ "id": FOLDER, "path": root.to_str().unwrap(), "peers": ["*"] })).unwrap();
+        let mut config: NodeConfig = serde_json::from_value(json!({ "node_id": "me" })).unwrap();
+        config.queue.max_concurrent = max_concurrent;
+        config.folders.push(folder.clone());
+        let folders = HashMap::from([(String::from(FOLDER), Arc::new(SharedFolder::open(folder).await))]);
+        let watch_config = WatchConfig { quiet_period: Duration::ZERO, poll_interval: None, rescan_interval: None };
+        let (sender, _) = async_std::channel::unbounded();
+        let broker = Broker::new(config, root.join("config.json").display().to_string(), Arc::new(RwLock::new(folders)), watch_config, sender);
+        TestBroker { broker, root }
+    }
+
+    async fn queue(broker: &Broker, path: &str, peer_id: &str) {
+        broker.queue.lock().await.push(QueuedFile::new(FOLDER, path, peer_id, String::from("sha"), 100, false, now()));
+    }
+
+    fn key(path: &str) -> FileKey {
+        (String::from(FOLDER), String::from(path))
+    }
+
+    #[test]
+    fn downloads_wait_for_a_free_slot() {
+        task::block_on(async {
+            let broker = test_broker(2).await;
+            for path in ["a", "b", "c"] {
+                queue(&broker, path, "peer").await;
+            }
+            broker.start_queued(&HashMap::new()).await;
+            let mut started: Vec<FileKey> = broker.transfers.lock().await.keys().cloned().collect();
+            started.sort();
+            assert_eq!(started, [key("a"), key("b")]);
+            assert!(broker.queue.lock().await.contains(&key("c")));
+            broker.start_queued(&HashMap::new()).await;
+            assert_eq!(broker.queue.lock().await.len(), 1);
+        });
+    }
+
+    #[test]
+    fn downloads_of_a_peer_that_left_free_their_slot() {
+        task::block_on(async {
+            let broker = test_broker(1).await;
+            queue(&broker, "a", "gone").await;
+            queue(&broker, "b", "other").await;
+            broker.start_queued(&HashMap::new()).await;
+            assert!(broker.transfers.lock().await.contains_key(&key("a")));
+            // Asked for a manifest that never came
+            broker.source_left("gone", &HashMap::new()).await;
+            assert!(broker.transfers.lock().await.is_empty());
+            assert!(broker.files_in_update.lock().await.is_empty());
+            broker.start_queued(&HashMap::new()).await;
+            assert!(broker.transfers.lock().await.contains_key(&key("b")));
+        });
+    }
+
+    #[test]
+    fn stalled_downloads_are_given_up() {
+        task::block_on(async {
+            let broker = test_broker(4).await;
+            for path in ["a", "b"] {
+                queue(&broker, path, "peer").await;
+            }
+            broker.start_queued(&HashMap::new()).await;
+            for kind in [TransferKind::Whole, TransferKind::Delta] {
+                let mut transfers = broker.transfers.lock().await;
+                let transfer = transfers.get_mut(&key("a")).unwrap();
+                transfer.kind = kind;
+                transfer.updated_at = now() - TRANSFER_TIMEOUT - 1;
+                drop(transfers);
+                broker.check_downloads(&HashMap::new()).await;
+                let transfers = broker.transfers.lock().await;
+                assert!(!transfers.contains_key(&key("a")));
+                assert!(transfers.contains_key(&key("b")));
+                drop(transfers);
+                queue(&broker, "a", "peer").await;
+                broker.start_queued(&HashMap::new()).await;
+            }
+        });
+    }
+
+    fn indexed(sha: &str) -> IndexEntry {
+        IndexEntry { sha: String::from(sha), size: 5, modified: 0, mode: None, chunks: vec![], presence: Presence::Local, symlink: None }
+    }
+
+    #[test]
+    fn deselected_files_changed_locally_are_kept() {
+        task::block_on(async {
+            let broker = test_broker(4).await;
+            let folder = broker.folder(FOLDER).await.unwrap();
+            for file in ["same.txt", "changed.txt"] {
+                std::fs::write(Path::new(folder.root()).join(file), "hello").unwrap();
+            }
+            let sha = folder.file_sha("same.txt").await.unwrap();
+            broker.remove_unselected(&folder, "same.txt", indexed(&sha)).await.unwrap();
+            assert!(!Path::new(folder.root()).join("same.txt").exists());
+            assert_eq!(folder.index.lock().await.get("same.txt").unwrap().presence, Presence::Unselected);
+            assert!(broker.remove_unselected(&folder, "changed.txt", indexed("older")).await.is_err());
+            assert!(Path::new(folder.root()).join("changed.txt").exists());
+        });
+    }
+
+    #[test]
+    fn deletions_of_peers_keep_files_changed_here() {
+        task::block_on(async {
+            let broker = test_broker(4).await;
+            let folder = broker.folder(FOLDER).await.unwrap();
+            std::fs::create_dir_all(Path::new(folder.root()).join("dir")).unwrap();
+            for file in ["dir/same.txt", "changed.txt"] {
+                std::fs::write(Path::new(folder.root()).join(file), "hello").unwrap();
+            }
+            let sha = folder.file_sha("changed.txt").await.unwrap();
+            for file in ["dir/same.txt", "changed.txt"] {
+                folder.index.lock().await.insert(String::from(file), indexed(&sha));
+            }
+            broker.delete_file(&folder, "peer", "dir/same.txt", &sha).await.unwrap();
+            assert!(!Path::new(folder.root()).join("dir").exists());
+            assert!(folder.index.lock().await.get("dir/same.txt").is_none());
+            assert!(broker.delete_file(&folder, "peer", "changed.txt", "older").await.is_err());
+            assert!(Path::new(folder.root()).join("changed.txt").exists());
+            assert!(folder.index.lock().await.get("changed.txt").is_some());
+        });
+    }
+
+    #[test]
+    fn peers_with_only_a_placeholder_are_not_asked_again() {
+        task::block_on(async {
+            let broker = test_broker(4).await;
+            let hash = broker.folder(FOLDER).await.unwrap().config.hash;
+            broker.peer_hashes.lock().await.insert(String::from("holder"), HashMap::from([(String::from(FOLDER), hash)]));
+            queue(&broker, "a", "holder").await;
+            broker.start_queued(&HashMap::new()).await;
+            let message = ExternalToInternal::ContentUnavailable { id: Uuid::new_v4(), peer_id: String::from("holder"), file_path: String::from("a") };
+            broker.handle_external_to_internal(FOLDER, message, &mut HashMap::new()).await.unwrap();
+            assert!(broker.transfers.lock().await.is_empty());
+            assert!(broker.placeholder_peers.lock().await[&key("a")].contains("holder"));
+        });
+    }
+
+    async fn test_peer(peer_id: &str) -> Peer {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (control, _) = async_std::channel::unbounded();
+        let (data, _) = async_std::channel::bounded(DATA_QUEUE_SIZE);
+        Peer { peer_id: String::from(peer_id), address: String::from("127.0.0.1"), port: 0, stream: Arc::new(stream), compression: None, control, data }
+    }
+
+    fn fetched_paths(result: &serde_json::Value, key: &str) -> Vec<String> {
+        let mut paths: Vec<String> = result[key].as_array().unwrap().iter().map(|file| String::from(file["file_path"].as_str().unwrap())).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn fetch_reports_the_files_it_cannot_fetch() {
+        task::block_on(async {
+            let broker = test_broker(4).await;
+            let folder = broker.folder(FOLDER).await.unwrap();
+            for (file, sha) in [("a/one", "sha"), ("a/two", ""), ("b/three", "sha")] {
+                let entry = IndexEntry { presence: Presence::Placeholder, ..indexed(sha) };
+                folder.index.lock().await.insert(String::from(file), entry);
+            }
+            let result = broker.fetch(None, String::from("a"), &HashMap::new()).await.unwrap();
+            assert!(fetched_paths(&result, "fetched").is_empty());
+            assert_eq!(fetched_paths(&result, "failed"), ["a/one", "a/two"]);
+            assert!(broker.queue.lock().await.is_empty());
+
+            let peers = HashMap::from([(String::from("peer"), test_peer("peer").await)]);
+            let result = broker.fetch(Some(String::from(FOLDER)), String::from("a"), &peers).await.unwrap();
+            assert_eq!(fetched_paths(&result, "fetched"), ["a/one"]);
+            assert_eq!(fetched_paths(&result, "failed"), ["a/two"]);
+            let queue = broker.queue.lock().await;
+            assert!(queue.contains(&key("a/one")) && queue.len() == 1);
+            drop(queue);
+            assert!(broker.fetch(None, String::from("c"), &peers).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::FileKey;
+
+const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+/// Which queued file is downloaded next, bumped files always go first
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOrder {
+    SmallestFirst,
+    #[default]
+    OldestFirst,
+    Random,
+}
+
+/// How many files are downloaded at once and in which order, stored in the node config
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct QueueConfig {
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    #[serde(default)]
+    pub order: QueueOrder,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { max_concurrent: DEFAULT_MAX_CONCURRENT, order: QueueOrder::default() }
+    }
+}
+
+fn default_max_concurrent() -> usize {
+    DEFAULT_MAX_CONCURRENT
+}
+
+/// A file a peer announced that waits for a free download slot
+#[derive(Serialize, Debug, Clone)]
+pub struct QueuedFile {
+    pub folder_id: String,
+    pub file_path: String,
+    pub peer_id: String,
+    pub sha: String,
+    pub size: u64,
+    /// Announced as modified, rebuilt with a delta of the local copy once it starts
+    pub modified: bool,
+    pub queued_at: u64,
+    /// Set when the file was bumped, the most recently bumped file goes first
+    pub bumped: Option<u64>,
+    #[serde(skip)]
+    sequence: u64,
+}
+
+impl QueuedFile {
+    pub fn new(folder_id: &str, file_path: &str, peer_id: &str, sha: String, size: u64, modified: bool, queued_at: u64) -> Self {
+        QueuedFile {
+            folder_id: String::from(folder_id),
+            file_path: String::from(file_path),
+            peer_id: String::from(peer_id),
+            sha,
+            size,
+            modified,
+            queued_at,
+            bumped: None,
+            sequence: 0,
+        }
+    }
+}
+
+/// Announced files waiting to be downloaded
+#[derive(Debug, Default)]
+pub struct TransferQueue {
+    files: HashMap<FileKey, QueuedFile>,
+    next_sequence: u64,
+}
+
+impl TransferQueue {
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Queues a file, a file announced again keeps its place but downloads the newer version
+    pub fn push(&mut self, mut file: QueuedFile) {
+        let key = (file.folder_id.clone(), file.file_path.clone());
+        if let Some(queued) = self.files.get(&key) {
+            file.sequence = queued.sequence;
+            file.queued_at = queued.queued_at;
+            file.bumped = queued.bumped;
+            // Modified stays set, the local copy it refers to is still there
+            file.modified |= queued.modified;
+        } else {
+            file.sequence = self.next_sequence();
+        }
+        self.files.insert(key, file);
+    }
+
+    pub fn remove(&mut self, key: &FileKey) -> Option<QueuedFile> {
+        self.files.remove(key)
+    }
+
+    pub fn contains(&self, key: &FileKey) -> bool {
+        self.files.contains_key(key)
+    }
+
+    /// Drops every file of a folder
+    pub fn remove_folder(&mut self, folder_id: &str) {
+        self.files.retain(|(file_folder, _), _| file_folder != folder_id);
+    }
+
+    /// Drops the files `keep` is false for
+    pub fn retain(&mut self, keep: impl Fn(&QueuedFile) -> bool) {
+        self.files.retain(|_, file| keep(file));
+    }
+
+    /// Moves a file to the front of the queue, false when it is not queued
+    pub fn bump(&mut self, key: &FileKey) -> bool {
+        let sequence = self.next_sequence();
+        match self.files.get_mut(key) {
+            Some(file) => {
+                file.bumped = Some(sequence);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The file to download next
+    pub fn pop(&mut self, order: QueueOrder) -> Option<QueuedFile> {
+        let key = match self.files.values().filter_map(|file| file.bumped.map(|bumped| (bumped, file))).max_by_key(|(bumped, _)| *bumped) {
+            Some((_, file)) => (file.folder_id.clone(), file.file_path.clone()),
+            None => {
+                let file = match order {
+                    QueueOrder::SmallestFirst => self.files.values().min_by_key(|file| (file.size, file.sequence)),
+                    QueueOrder::OldestFirst => self.files.values().min_by_key(|file| file.sequence),
+                    // A random v4 uuid saves a dependency on rand for picking a file
+                    QueueOrder::Random if !self.files.is_empty() => {
+                        self.files.values().nth((Uuid::new_v4().as_u128() % self.files.len() as u128) as usize)
+                    }
+                    QueueOrder::Random => None,
+                }?;
+                (file.folder_id.clone(), file.file_path.clone())
+            }
+        };
+        self.files.remove(&key)
+    }
+
+    /// Queued files in the order they are downloaded, random order lists them oldest first
+    pub fn files(&self, order: QueueOrder) -> Vec<&QueuedFile> {
+        let mut files: Vec<&QueuedFile> = self.files.values().collect();
+        files.sort_by_key(|file| {
+            let bumped = file.bumped.map(|bumped| u64::MAX - bumped).unwrap_or(u64::MAX);
+            match order {
+                QueueOrder::SmallestFirst => (bumped, file.size, file.sequence),
+                QueueOrder::OldestFirst | QueueOrder::Random => (bumped, 0, file.sequence),
+            }
+        });
+        files
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        self.next_sequence += 1;
+        self.next_sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, size: u64) -> QueuedFile {
+        QueuedFile::new("docs", path, "peer", String::from("sha"), size, false, 0)
+    }
+
+    fn key(path: &str) -> FileKey {
+        (String::from("docs"), String::from(path))
+    }
+
+    fn popped(queue: &mut TransferQueue, order: QueueOrder) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop(order)).map(|file| file.file_path).collect()
+    }
+
+    fn queue(files: &[(&str, u64)]) -> TransferQueue {
+        let mut queue = TransferQueue::default();
+        for (path, size) in files {
+            queue.push(file(path, *size));
+        }
+        queue
+    }
+
+    #[test]
+    fn oldest_and_smallest_first() {
+        let files = [("a", 30), ("b", 10), ("c", 20), ("d", 10)];
+        assert_eq!(popped(&mut queue(&files), QueueOrder::OldestFirst), ["a", "b", "c", "d"]);
+        // Files of the same size keep the order they were queued in
+        assert_eq!(popped(&mut queue(&files), QueueOrder::SmallestFirst), ["b", "d", "c", "a"]);
+        let mut random = popped(&mut queue(&files), QueueOrder::Random);
+        random.sort();
+        assert_eq!(random, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn listed_in_the_order_they_start() {
+        let files = [("a", 30), ("b", 10), ("c", 20)];
+        for order in [QueueOrder::OldestFirst, QueueOrder::SmallestFirst] {
+            let mut queue = queue(&files);
+            queue.bump(&key("c"));
+            let listed: Vec<String> = queue.files(order).iter().map(|file| file.file_path.clone()).collect();
+            assert_eq!(listed, popped(&mut queue, order));
+        }
+    }
+
+    #[test]
+    fn announced_again_keeps_its_place() {
+        let mut queue = queue(&[("a", 10), ("b", 10)]);
+        queue.push(QueuedFile::new("docs", "b", "peer", String::from("sha"), 10, true, 0));
+        queue.push(QueuedFile::new("docs", "a", "other", String::from("newer"), 5, false, 100));
+        assert_eq!(queue.len(), 2);
+        let first = queue.pop(QueueOrder::OldestFirst).unwrap();
+        assert_eq!((first.file_path.as_str(), first.sha.as_str(), first.queued_at), ("a", "newer", 0));
+        // The local copy a delta needs is still there
+        queue.push(file("b", 10));
+        assert!(queue.pop(QueueOrder::OldestFirst).unwrap().modified);
+    }
+
+    #[test]
+    fn bumped_files_go_first() {
+        let mut queue = queue(&[("a", 10), ("b", 20), ("c", 30)]);
+        assert!(queue.bump(&key("b")));
+        assert!(queue.bump(&key("c")));
+        assert!(!queue.bump(&key("missing")));
+        // The last bumped first, then the others in queue order
+        assert_eq!(popped(&mut queue, QueueOrder::SmallestFirst), ["c", "b", "a"]);
+    }
+}
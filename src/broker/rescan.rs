@@ -0,0 +1,183 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use futures::channel::oneshot;
+use log::{debug, info};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{Broker, InternalToExternal, Rescanned};
+use crate::{control::ControlResult, folder::SharedFolder, io::{index::{IndexEntry, Presence}, scan::scan_folder}, peer::Peer, spawn_and_log_error, InternalMessage, Result};
+
+impl Broker {
+    /// A folder and the files being downloaded in it, which a rescan leaves alone
+    pub(super) async fn rescan_target(&self, folder_id: &str) -> Option<(Arc<SharedFolder>, Vec<String>)> {
+        let folder = self.folder(folder_id).await?;
+        // Files being downloaded are announced once complete, not half written
+        let downloading = self
+            .transfers
+            .lock()
+            .await
+            .keys()
+            .filter(|(transfer_folder, _)| transfer_folder == folder_id)
+            .map(|(_, file)| file.clone())
+            .collect();
+        Some((folder, downloading))
+    }
+
+    /// Compares a folder with its index in a task, what changed comes back as `Rescanned`
+    pub(super) async fn rescan(&self, id: Uuid, folder_id: &str) {
+        let (folder, downloading) = match self.rescan_target(folder_id).await {
+            Some(target) => target,
+            None => {
+                debug!("Not rescanning removed folder {}", folder_id);
+                return;
+            }
+        };
+        let sender = self.broker_sender.clone();
+        spawn_and_log_error(async move {
+            let result = find_changes(id, &folder, &downloading).await.map_err(|err| format!("Cannot rescan {} {}", folder.id(), err));
+            sender.send(InternalMessage::Rescanned { id, folder_id: String::from(folder.id()), result }).await?;
+            Ok(())
+        });
+    }
+
+    ///
+    /// Rescans folders for the control API, answered once every folder is hashed
+    /// # Arguments
+    /// * `folder` - Id or path of the folder, every folder when None
+    /// * `reply` - Gets the number of files scanned and of changes found
+    ///
+    pub(super) async fn rescan_folders(&self, folder: Option<String>, reply: oneshot::Sender<ControlResult>) {
+        if *self.paused.lock().await {
+            let _ = reply.send(Err(String::from("Sync is paused")));
+            return;
+        }
+        let folder_ids = match self.resolve_folders(folder).await {
+            Ok(folder_ids) => folder_ids,
+            Err(err) => {
+                let _ = reply.send(Err(err));
+                return;
+            }
+        };
+        let mut targets = vec![];
+        for folder_id in folder_ids {
+            targets.extend(self.rescan_target(&folder_id).await);
+        }
+        let sender = self.broker_sender.clone();
+        spawn_and_log_error(async move {
+            let id = Uuid::new_v4();
+            let (mut scanned, mut changes) = (0, 0);
+            for (folder, downloading) in targets {
+                let found = match find_changes(id, &folder, &downloading).await {
+                    Ok(found) => found,
+                    Err(err) => {
+                        let _ = reply.send(Err(err.to_string()));
+                        return Ok(());
+                    }
+                };
+                scanned += found.scanned;
+                changes += found.changes.len();
+                sender.send(InternalMessage::Rescanned { id, folder_id: String::from(folder.id()), result: Ok(found) }).await?;
+            }
+            let _ = reply.send(Ok(json!({ "files": scanned, "changes": changes })));
+            Ok(())
+        });
+    }
+
+    /// Announces the changes a rescan found, except in files a download started on meanwhile
+    pub(super) async fn rescanned(
+        &self,
+        id: &Uuid,
+        folder_id: &str,
+        result: std::result::Result<Rescanned, String>,
+        peers: &mut HashMap<String, Peer>,
+    ) -> Result<()> {
+        let Rescanned { scanned, changes, problems } = result?;
+        let folder = match self.folder(folder_id).await {
+            Some(folder) => folder,
+            None => return Ok(()),
+        };
+        for problem in problems {
+            self.record_error(format!("{} in {}", problem, folder_id)).await;
+        }
+        info!("Rescan id::{} of {} found {} changes in {} files", id, folder.root(), changes.len(), scanned);
+        for message in changes {
+            let key = (String::from(folder_id), String::from(message.path()));
+            if self.transfers.lock().await.contains_key(&key) {
+                continue;
+            }
+            self.handle_internal_to_external(folder_id, message, peers).await?;
+        }
+        folder.index.lock().await.save(folder.root()).await?;
+        Ok(())
+    }
+}
+
+///
+/// Compares a folder with its index and hashes every file that was created or modified without
+/// the watcher noticing, outside of the broker loop
+/// # Arguments
+/// * `downloading` - Files being downloaded, announced once complete instead
+///
+/// # Returns
+/// * `Rescanned` - What the broker announces
+///
+async fn find_changes(id: Uuid, folder: &SharedFolder, downloading: &[String]) -> Result<Rescanned> {
+    let scan = scan_folder(folder.root(), &*folder.ignore_rules.read().await, &folder.config.names).await?;
+    let files = scan.files;
+    let scanned = files.len();
+    // Hashes of deleted files would otherwise stay in the index forever
+    let inodes = files.iter().map(|file| file.inode).collect();
+    folder.index.lock().await.retain_hashes(&inodes);
+
+    let mut changes = vec![];
+    let mut seen = HashSet::new();
+    for file in files {
+        seen.insert(file.relative_path.clone());
+        if downloading.contains(&file.relative_path) || !folder.is_selected(&file.relative_path).await {
+            continue;
+        }
+        let known = folder.index.lock().await.get(&file.relative_path).cloned();
+        if let Some(known) = &known {
+            if known.presence == Presence::Placeholder && file.size == 0 {
+                continue;
+            }
+            if known.size == file.size && known.modified == file.modified && known.mode == file.mode && !known.sha.is_empty() {
+                continue;
+            }
+        }
+        let sha = match folder.file_sha(&file.relative_path).await {
+            Some(sha) => sha,
+            None => continue,
+        };
+        let message = match known {
+            // Unchanged since the folder switched its hash algorithm, only the hash is new
+            Some(known) if known.sha.is_empty() && known.size == file.size && known.modified == file.modified => {
+                folder.index_file(&file.relative_path, &sha).await;
+                continue;
+            }
+            None => InternalToExternal::FileCreated { id, file: file.relative_path, sha, size: file.size },
+            Some(known) if known.sha != sha => InternalToExternal::FileModified { id, file: file.relative_path, sha, size: file.size },
+            // Touched or its permissions changed, the peers apply the same
+            Some(_) => InternalToExternal::MetadataModified { id, file: file.relative_path },
+        };
+        changes.push(message);
+    }
+    let deleted: Vec<(String, IndexEntry)> = folder
+        .index
+        .lock()
+        .await
+        .files()
+        .filter(|(file, entry)| entry.presence.is_local() && !seen.contains(*file) && !downloading.contains(*file))
+        .map(|(file, entry)| (file.clone(), entry.clone()))
+        .collect();
+    for (file, entry) in deleted {
+        if folder.is_ignored(&file).await {
+            // Ignored since it was indexed, forget it without deleting it on the peers
+            folder.index.lock().await.remove(&file);
+            continue;
+        }
+        changes.push(InternalToExternal::FileDeleted { id, folder: file, sha: entry.sha });
+    }
+    Ok(Rescanned { scanned, changes, problems: scan.problems })
+}
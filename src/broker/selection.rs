@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use log::{debug, info, warn};
+use serde_json::json;
+
+use super::{now, queue::QueuedFile, relative_to, Broker, FileKey};
+use crate::{control::ControlResult, folder::SharedFolder, io::{index::{IndexEntry, Presence}, metadata::FileMetadata, selection::{is_below, Selection}}, peer::Peer, Result};
+
+impl Broker {
+    ///
+    /// Indexes a file a peer announced below a path that is not selected, without downloading it
+    /// # Arguments
+    /// * `peer_id` - The peer that announced it, asked for it first once it is selected
+    /// * `size` - Size of the announced version
+    /// * `metadata` - Announced with the file, applied once it is downloaded
+    ///
+    pub(super) async fn record_unselected(
+        &self,
+        folder: &SharedFolder,
+        peer_id: &str,
+        file_path: &str,
+        sha: String,
+        size: u64,
+        metadata: FileMetadata,
+    ) -> Result<()> {
+        let mut index = folder.index.lock().await;
+        if index.get(file_path).is_some_and(|known| known.presence.is_local()) {
+            // Deselected while the node was not running, its removal is up to the user
+            debug!("Not replacing local {} in {}, it is not selected", file_path, folder.id());
+            return Ok(());
+        }
+        debug!("Indexing unselected {} in {} from {}", file_path, folder.id(), peer_id);
+        let entry = IndexEntry {
+            sha,
+            size,
+            modified: metadata.modified.unwrap_or_default(),
+            mode: metadata.mode,
+            chunks: vec![],
+            presence: Presence::Unselected,
+            symlink: metadata.symlink,
+        };
+        index.insert(String::from(file_path), entry);
+        drop(index);
+        self.sources.lock().await.insert((String::from(folder.id()), String::from(file_path)), String::from(peer_id));
+        Ok(())
+    }
+
+    ///
+    /// Changes the paths of a folder this node downloads, newly selected files are queued for
+    /// download and deselected ones removed locally, the peers keep them
+    /// # Arguments
+    /// * `folder` - Id or path of the folder
+    /// * `include` - Only these paths are downloaded, every path when empty
+    /// * `exclude` - Paths never downloaded
+    ///
+    pub(super) async fn select(&self, folder: String, include: Vec<String>, exclude: Vec<String>, peers: &HashMap<String, Peer>) -> ControlResult {
+        let selection = Selection::new(include, exclude)?;
+        let mut config = self.config.lock().await;
+        let folder_config = config
+            .folders
+            .iter_mut()
+            .find(|folder_config| folder_config.id == folder || folder_config.path == folder)
+            .ok_or(format!("Unknown folder {}", folder))?;
+        let folder_id = folder_config.id.clone();
+        let previous = std::mem::replace(&mut folder_config.selection, selection.clone());
+        if let Err(err) = config.save(&self.config_path).await {
+            if let Some(folder_config) = config.folders.iter_mut().find(|folder_config| folder_config.id == folder_id) {
+                folder_config.selection = previous;
+            }
+            return Err(format!("Cannot save {} {}", self.config_path, err));
+        }
+        drop(config);
+        let folder = self.folder(&folder_id).await.ok_or(format!("Unknown folder {}", folder_id))?;
+        *folder.selection.write().await = selection.clone();
+        info!("Selection of {} changed to {:?}", folder_id, selection);
+
+        let entries: Vec<(String, IndexEntry)> =
+            folder.index.lock().await.files().map(|(file, entry)| (file.clone(), entry.clone())).collect();
+        let (mut fetched, mut removed) = (0, 0);
+        for (file, entry) in entries {
+            let selected = selection.is_selected(&file);
+            if selected && entry.presence == Presence::Unselected {
+                if let Err(err) = self.fetch_unselected(&folder, &file, entry, peers).await {
+                    self.record_error(err.to_string()).await;
+                    continue;
+                }
+                fetched += 1;
+            } else if !selected && entry.presence != Presence::Unselected {
+                if let Err(err) = self.remove_unselected(&folder, &file, entry).await {
+                    self.record_error(format!("Cannot remove unselected {} in {} {}", file, folder_id, err)).await;
+                    continue;
+                }
+                removed += 1;
+            }
+        }
+        self.queue.lock().await.retain(|queued| queued.folder_id != folder_id || selection.is_selected(&queued.file_path));
+        let cancelled: Vec<FileKey> = self
+            .transfers
+            .lock()
+            .await
+            .keys()
+            .filter(|(transfer_folder, file)| *transfer_folder == folder_id && !selection.is_selected(file))
+            .cloned()
+            .collect();
+        for key in cancelled {
+            self.cancel_download(&folder, &key).await;
+        }
+        info!("Fetching {} newly selected files of {}, removed {} deselected ones", fetched, folder_id, removed);
+        Ok(json!({ "id": folder_id, "selection": selection, "fetched": fetched, "removed": removed }))
+    }
+
+    /// Queues the download of a file that was just selected, from the peer that announced it, or
+    /// creates its placeholder
+    pub(super) async fn fetch_unselected(&self, folder: &SharedFolder, file: &str, entry: IndexEntry, peers: &HashMap<String, Peer>) -> Result<()> {
+        let peer = self.source(folder, file, peers).await.ok_or(format!("No peer of {} is connected to fetch {}", folder.id(), file))?;
+        if let Some(target) = &entry.symlink {
+            return self.create_symlink(folder, &peer.peer_id, file, &entry.sha, target).await;
+        }
+        let metadata = FileMetadata { mode: entry.mode, modified: Some(entry.modified), symlink: None };
+        if folder.config.placeholders && entry.size > 0 {
+            return self.record_placeholder(folder, &peer.peer_id, file, entry.sha, entry.size, metadata).await;
+        }
+        let key = (String::from(folder.id()), String::from(file));
+        self.pending_metadata.lock().await.insert(key, metadata);
+        self.queue.lock().await.push(QueuedFile::new(folder.id(), file, &peer.peer_id, entry.sha, entry.size, false, now()));
+        Ok(())
+    }
+
+    /// Stops downloading a file, the data still arriving for it is dropped
+    pub(super) async fn cancel_download(&self, folder: &SharedFolder, key: &FileKey) {
+        debug!("Cancelling the download of {} in {}", key.1, key.0);
+        self.transfers.lock().await.remove(key);
+        self.downloads.lock().await.remove(key);
+        self.files_in_update.lock().await.remove(key);
+        self.pending_metadata.lock().await.remove(key);
+        folder.transfer_states.lock().await.remove(&key.1);
+        if let Err(err) = async_std::fs::remove_file(folder.file_handler.partial_path(&key.1)).await {
+            debug!("No partial file of {} in {} {}", key.1, key.0, err);
+        }
+    }
+
+    /// Removes the local copy of a file that was deselected, it stays indexed as unselected. A file
+    /// changed since it was indexed is kept, its content is on no peer.
+    pub(super) async fn remove_unselected(&self, folder: &SharedFolder, file: &str, mut entry: IndexEntry) -> Result<()> {
+        if let Some(metadata) = folder.file_handler.metadata(file).await {
+            // Placeholders and links hold no content of their own
+            if entry.presence.is_local() && metadata.is_file() && folder.file_sha(file).await.as_deref() != Some(entry.sha.as_str()) {
+                Err(format!("{} changed since it was indexed, keeping it", file))?;
+            }
+            folder.file_handler.remove_local_copy(file).await?;
+        }
+        entry.presence = Presence::Unselected;
+        entry.chunks.clear();
+        folder.index.lock().await.insert(String::from(file), entry);
+        Ok(())
+    }
+
+    /// Whether a file a peer announced only gets a placeholder, unless its content is already local
+    /// or being downloaded. An empty file needs none.
+    pub(super) async fn keeps_placeholder(&self, folder: &SharedFolder, file_path: &str, size: u64) -> bool {
+        if !folder.config.placeholders || size == 0 {
+            return false;
+        }
+        let key = (String::from(folder.id()), String::from(file_path));
+        if self.transfers.lock().await.contains_key(&key) || self.queue.lock().await.contains(&key) {
+            return false;
+        }
+        folder.is_placeholder(file_path).await || folder.file_handler.metadata(file_path).await.is_none()
+    }
+
+    ///
+    /// Creates an empty file in place of a file a peer announced, its hash, size and metadata are
+    /// kept in the index until it is fetched. Not a sparse file of the announced size, programs
+    /// reading it would take its zeros for the content.
+    /// # Arguments
+    /// * `peer_id` - The peer that announced it, asked for it once it is fetched
+    /// * `size` - Size of the announced version
+    /// * `metadata` - Announced with the file, given to the placeholder
+    ///
+    pub(super) async fn record_placeholder(
+        &self,
+        folder: &SharedFolder,
+        peer_id: &str,
+        file_path: &str,
+        sha: String,
+        size: u64,
+        metadata: FileMetadata,
+    ) -> Result<()> {
+        debug!("Creating placeholder of {} in {} from {}", file_path, folder.id(), peer_id);
+        let entry = IndexEntry {
+            sha,
+            size,
+            modified: metadata.modified.unwrap_or_default(),
+            mode: metadata.mode,
+            chunks: vec![],
+            presence: Presence::Placeholder,
+            symlink: None,
+        };
+        // Indexed first, the watcher takes the empty file for what it is
+        folder.index.lock().await.insert(String::from(file_path), entry);
+        if folder.file_handler.metadata(file_path).await.is_none() {
+            folder.file_handler.create_file(file_path).await?;
+        }
+        folder.file_handler.set_metadata(file_path, &metadata).await?;
+        self.sources.lock().await.insert((String::from(folder.id()), String::from(file_path)), String::from(peer_id));
+        Ok(())
+    }
+
+    ///
+    /// Downloads placeholders ahead of every other queued file. Their empty local copy makes them
+    /// download in chunks, the manifest is asked from every peer of the folder that does not
+    /// only have a placeholder too, so the chunks come from whoever has them
+    /// # Arguments
+    /// * `folder` - Id or path of the folder, every folder when None
+    /// * `path` - A placeholder or a directory with placeholders, relative to the folder or absolute
+    ///
+    pub(super) async fn fetch(&self, folder: Option<String>, path: String, peers: &HashMap<String, Peer>) -> ControlResult {
+        let (mut fetched, mut failed) = (vec![], vec![]);
+        for folder_id in self.resolve_folders(folder).await? {
+            let folder = match self.folder(&folder_id).await {
+                Some(folder) => folder,
+                None => continue,
+            };
+            let below = relative_to(&folder, &path);
+            let placeholders: Vec<(String, IndexEntry)> = folder
+                .index
+                .lock()
+                .await
+                .files()
+                .filter(|(file, entry)| entry.presence == Presence::Placeholder && (below.is_empty() || is_below(file, &below)))
+                .map(|(file, entry)| (file.clone(), entry.clone()))
+                .collect();
+            for (file, entry) in placeholders {
+                let key = (folder_id.clone(), file.clone());
+                if self.transfers.lock().await.contains_key(&key) {
+                    continue;
+                }
+                let peer = if entry.sha.is_empty() {
+                    // Hashed with the algorithm the folder used before, the peers announce it again
+                    Err(format!("not known with the {} hash of the folder yet", folder.config.hash))
+                } else {
+                    self.source(&folder, &file, peers).await.ok_or(format!("no peer of {} is connected", folder_id))
+                };
+                let peer = match peer {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        warn!("Cannot fetch {} in {}, {}", file, folder_id, err);
+                        failed.push(json!({ "folder_id": folder_id, "file_path": file, "error": err }));
+                        continue;
+                    }
+                };
+                let metadata = FileMetadata { mode: entry.mode, modified: Some(entry.modified), symlink: None };
+                self.pending_metadata.lock().await.insert(key.clone(), metadata);
+                let mut queue = self.queue.lock().await;
+                // Modified, the empty local copy is built from chunks, the peer is only the fallback
+                queue.push(QueuedFile::new(&folder_id, &file, &peer.peer_id, entry.sha, entry.size, true, now()));
+                queue.bump(&key);
+                info!("Fetching {} in {} from {}", file, folder_id, peer.peer_id);
+                fetched.push(json!({ "folder_id": folder_id, "file_path": file, "size": entry.size }));
+            }
+        }
+        if fetched.is_empty() && failed.is_empty() {
+            return Err(format!("No placeholder to fetch at {}", path));
+        }
+        Ok(json!({ "fetched": fetched, "failed": failed }))
+    }
+}
@@ -1,13 +1,589 @@
-use clap::Parser;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// CLI application that search duplicate files in a folder
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+
+use crate::{broker::queue::QueueOrder, control::{default_socket_path, ControlCommand}, folder::FolderMode, io::hash::HashAlgorithm};
+
+/// Peer to peer folder synchronisation node and the commands to manage a running one
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CmdArgs {
-    /// Root folder to search duplicate
-    #[arg(short, long, default_value_t = String::from("/Users/kasunranasinghe/Development/RUST/test"))]
-    pub folder: String,
+    /// Unix socket the node listens on for local control requests, only its user may connect
+    #[arg(short, long, global = true, default_value_t = default_socket_path())]
+    pub control_socket: String,
+    /// Print the answer of the node as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: CmdCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CmdCommand {
+    /// Run the node in the foreground
+    Run {
+        /// JSON file with the node id and the shared folders, created on first run
+        #[arg(long, default_value_t = String::from("config/node.json"))]
+        config: String,
+        /// Share this folder with every peer, under the name of the directory, unless the config already shares it
+        #[arg(short, long)]
+        folder: Vec<String>,
+        /// Serve Prometheus metrics on this address, for example 127.0.0.1:9100
+        #[arg(short, long)]
+        metrics_addr: Option<String>,
+        /// Milliseconds a changed file must stay untouched before it is synced
+        #[arg(short, long, default_value_t = 1000)]
+        quiet_period_ms: u64,
+        /// Poll the folder every N seconds instead of relying on OS notifications
+        #[arg(short, long)]
+        poll_interval_secs: Option<u64>,
+        /// Compare the whole folder with the index every N seconds, 0 disables it
+        #[arg(short, long, default_value_t = 3600)]
+        rescan_interval_secs: u64,
+    },
+    /// Show a summary of the running node
+    Status,
+    /// List connected peers
+    Peers,
+    /// Manage shared folders
+    Folders {
+        #[command(subcommand)]
+        command: FoldersCommand,
+    },
+    /// List local files that differed from the version a peer sent
+    Conflicts,
+    /// List files that differ from the cluster because of the mode of their folder
+    Drift,
+    /// Undo local changes to receive only folders
+    Revert {
+        /// Id or path of the folder, every receive only folder by default
+        folder: Option<String>,
+    },
+    /// Compare shared folders with their index and announce what changed
+    Rescan {
+        /// Id or path of the folder, every folder by default
+        folder: Option<String>,
+    },
+    /// Show or change bandwidth limits
+    Limits {
+        #[command(subcommand)]
+        command: LimitsCommand,
+    },
+    /// Show or reorder the files waiting to be downloaded
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommand,
+    },
+    /// List or restore versions of files the sync replaced or deleted
+    Versions {
+        #[command(subcommand)]
+        command: VersionsCommand,
+    },
+    /// Download placeholders, the file at the path or every one below it
+    Fetch {
+        /// Path of a placeholder or a directory, relative to its folder or absolute
+        path: String,
+        /// Id or path of the folder, every folder by default
+        #[arg(long)]
+        folder: Option<String>,
+    },
+    /// Print the id of the running node
+    Id,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VersionsCommand {
+    /// List kept versions, newest first
+    List {
+        /// Only the versions of this file, relative to its folder or absolute
+        path: Option<String>,
+        /// Id or path of the folder, every folder by default
+        #[arg(long)]
+        folder: Option<String>,
+    },
+    /// Put a version back in place of its file
+    Restore {
+        /// Id or path of the folder
+        folder: String,
+        /// The version as listed
+        version: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum QueueCommand {
+    /// List queued files in the order they are downloaded
+    List,
+    /// Download a queued file next
+    Bump {
+        /// Path of the file, relative to its folder or absolute
+        path: String,
+        /// Id or path of the folder, every folder by default
+        #[arg(long)]
+        folder: Option<String>,
+    },
+    /// Change how many files are downloaded at once and which one starts next
+    Set {
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+        #[arg(long, value_enum)]
+        order: Option<QueueOrder>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LimitsCommand {
+    /// Show the current limits
+    Show,
+    /// Set the limits of every peer together, or of one peer with --peer
+    Set {
+        /// Upload limit in KiB per second, 0 for unlimited, unchanged when not given
+        #[arg(long)]
+        upload: Option<u64>,
+        /// Download limit in KiB per second, 0 for unlimited, unchanged when not given
+        #[arg(long)]
+        download: Option<u64>,
+        /// Peer to limit, `*` for every peer without its own limits
+        #[arg(long)]
+        peer: Option<String>,
+        /// Lift every limit for peers on a private network
+        #[arg(long)]
+        lan_unlimited: Option<bool>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FoldersCommand {
+    /// Start sharing a folder
+    Add {
+        path: String,
+        /// Id the peers know the folder by, the name of the directory by default
+        #[arg(long)]
+        id: Option<String>,
+        /// Peer to share the folder with, repeat for several, every peer by default. Peer ids are
+        /// not authenticated, this is no access control
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+        /// Which way changes flow
+        #[arg(long, value_enum, default_value_t = FolderMode::SendReceive)]
+        mode: FolderMode,
+        /// How files are hashed, every peer of the folder has to use the same
+        #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+        hash: HashAlgorithm,
+        /// Create the files of the peers empty, their content is downloaded with `fetch`
+        #[arg(long)]
+        placeholders: bool,
+    },
+    /// Stop sharing a folder, its files are kept
+    Remove {
+        /// Id or path of the folder
+        folder: String,
+    },
+    /// List shared folders
+    List,
+    /// Choose the paths of a folder this node downloads, replacing the previous choice
+    Select {
+        /// Id or path of the folder
+        folder: String,
+        /// Path relative to the folder to download, repeat for several, every path when none
+        #[arg(long)]
+        include: Vec<String>,
+        /// Path relative to the folder never to download, repeat for several
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+}
+
+impl CmdCommand {
+    /// The control request that answers this command, None for `run`
+    pub fn control_command(&self) -> Option<ControlCommand> {
+        let command = match self {
+            CmdCommand::Run { .. } => return None,
+            CmdCommand::Status => ControlCommand::Status,
+            CmdCommand::Peers => ControlCommand::Peers,
+            CmdCommand::Folders { command } => match command {
+                FoldersCommand::Add { path, id, peers, mode, hash, placeholders } => ControlCommand::AddFolder {
+                    // The node may run in another directory
+                    path: absolute(path),
+                    id: id.clone(),
+                    peers: peers.clone(),
+                    mode: *mode,
+                    hash: *hash,
+                    placeholders: *placeholders,
+                },
+                FoldersCommand::Remove { folder } => ControlCommand::RemoveFolder { folder: absolute_if_exists(folder) },
+                FoldersCommand::List => ControlCommand::Folders,
+                FoldersCommand::Select { folder, include, exclude } => ControlCommand::SelectPaths {
+                    folder: absolute_if_exists(folder),
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                },
+            },
+            CmdCommand::Conflicts => ControlCommand::Conflicts,
+            CmdCommand::Drift => ControlCommand::Drift,
+            CmdCommand::Revert { folder } => ControlCommand::Revert { folder: folder.as_deref().map(absolute_if_exists) },
+            CmdCommand::Rescan { folder } => ControlCommand::Rescan { folder: folder.as_deref().map(absolute_if_exists) },
+            CmdCommand::Limits { command } => match command {
+                LimitsCommand::Show => ControlCommand::Limits,
+                LimitsCommand::Set { upload, download, peer, lan_unlimited } => ControlCommand::SetLimits {
+                    peer: peer.clone(),
+                    upload: upload.map(|kib| kib * 1024),
+                    download: download.map(|kib| kib * 1024),
+                    lan_unlimited: *lan_unlimited,
+                },
+            },
+            CmdCommand::Queue { command } => match command {
+                QueueCommand::List => ControlCommand::Queue,
+                QueueCommand::Bump { path, folder } => ControlCommand::BumpTransfer {
+                    folder: folder.as_deref().map(absolute_if_exists),
+                    path: path.clone(),
+                },
+                QueueCommand::Set { max_concurrent, order } => ControlCommand::SetQueue { max_concurrent: *max_concurrent, order: *order },
+            },
+            CmdCommand::Versions { command } => match command {
+                VersionsCommand::List { path, folder } => ControlCommand::Versions {
+                    folder: folder.as_deref().map(absolute_if_exists),
+                    path: path.as_deref().map(absolute_if_exists),
+                },
+                VersionsCommand::Restore { folder, version } => ControlCommand::RestoreVersion {
+                    folder: absolute_if_exists(folder),
+                    version: version.clone(),
+                },
+            },
+            CmdCommand::Fetch { path, folder } => ControlCommand::Fetch {
+                folder: folder.as_deref().map(absolute_if_exists),
+                path: absolute_if_exists(path),
+            },
+            CmdCommand::Id => ControlCommand::Id,
+        };
+        Some(command)
+    }
+
+    ///
+    /// Formats the answer of the node for a terminal
+    /// # Arguments
+    /// * `result` - The `result` member of the control response
+    ///
+    pub fn render(&self, result: &Value) -> String {
+        match self {
+            CmdCommand::Run { .. } => String::new(),
+            CmdCommand::Status => {
+                let state = if result["paused"].as_bool().unwrap_or_default() { "paused" } else { "running" };
+                let compression = &result["compression"];
+                format!(
+                    "Node       {}\nState      {}\nFolders    {}\nPeers      {}\nTransfers  {}\nQueued     {}\nConflicts  {}\nDrift      {}\nErrors     {}\nCompressed {} KiB to {} KiB ({:.0}%) in {:.2}s",
+                    text(&result["id"]),
+                    state,
+                    result["folders"],
+                    result["peers"],
+                    result["transfers"],
+                    result["queued"],
+                    result["conflicts"],
+                    result["drift"],
+                    result["errors"],
+                    compression["input_bytes"].as_u64().unwrap_or_default() / 1024,
+                    compression["output_bytes"].as_u64().unwrap_or_default() / 1024,
+                    compression["ratio"].as_f64().unwrap_or(1.0) * 100.0,
+                    compression["compress_seconds"].as_f64().unwrap_or_default(),
+                )
+            }
+            CmdCommand::Peers => render_list(result, "No connected peers", |peer| {
+                let rate = match peer["download_rate"].as_u64() {
+                    Some(rate) => format!("  {} KiB/s", rate / 1024),
+                    None => String::new(),
+                };
+                format!("{}  {}:{}{}", text(&peer["peer_id"]), text(&peer["address"]), peer["port"], rate)
+            }),
+            CmdCommand::Folders { command } => match command {
+                FoldersCommand::Add { .. } => format!("Sharing {} as {}", text(&result["path"]), text(&result["id"])),
+                FoldersCommand::Remove { .. } => format!("Stopped sharing {} ({})", text(&result["id"]), text(&result["path"])),
+                FoldersCommand::List => render_list(result, "No shared folders", |folder| {
+                    let state = if folder["paused"].as_bool().unwrap_or_default() { "paused" } else { "syncing" };
+                    let peers = folder["peers"].as_array().map(|peers| peers.iter().map(text).collect::<Vec<_>>().join(", ")).unwrap_or_default();
+                    let paths = |list: &Value| list.as_array().map(|paths| paths.iter().map(text).collect::<Vec<_>>().join(", "));
+                    let selection = match (paths(&folder["selection"]["include"]), paths(&folder["selection"]["exclude"])) {
+                        (None, None) => String::new(),
+                        (include, exclude) => format!(
+                            "  downloading {}{}",
+                            include.unwrap_or_else(|| String::from("everything")),
+                            exclude.map(|exclude| format!(" except {}", exclude)).unwrap_or_default()
+                        ),
+                    };
+                    let placeholders = if folder["placeholders"].as_bool().unwrap_or_default() { "  placeholders" } else { "" };
+                    format!(
+                        "{}  {}  {}  {}  {}  {} files  {} transfers  shared with {}{}{}",
+                        text(&folder["id"]),
+                        text(&folder["path"]),
+                        text(&folder["mode"]),
+                        text(&folder["hash"]),
+                        state,
+                        folder["files"],
+                        folder["transfers"],
+                        peers,
+                        selection,
+                        placeholders,
+                    )
+                }),
+                FoldersCommand::Select { .. } => format!(
+                    "Selection of {} changed, fetching {} files, removed {} local copies",
+                    text(&result["id"]),
+                    result["fetched"],
+                    result["removed"]
+                ),
+            },
+            CmdCommand::Conflicts => render_list(result, "No conflicts", |conflict| {
+                let kept = match conflict["copy"].as_str() {
+                    Some(copy) => format!("kept as {}", copy),
+                    None => String::from("not kept"),
+                };
+                format!(
+                    "{}  local {} {}  remote {} from {}",
+                    text(&conflict["file_path"]),
+                    short_sha(&conflict["local_sha"]),
+                    kept,
+                    short_sha(&conflict["remote_sha"]),
+                    text(&conflict["peer_id"]),
+                )
+            }),
+            CmdCommand::Drift => render_list(result, "No drift", |drift| {
+                let source = match drift["peer_id"].as_str() {
+                    Some(peer_id) => format!("sent by {}", peer_id),
+                    None => String::from("changed locally"),
+                };
+                format!(
+                    "{}  {}  local {}  cluster {}  {}",
+                    text(&drift["folder_id"]),
+                    text(&drift["file_path"]),
+                    short_sha_or_missing(&drift["local_sha"]),
+                    short_sha_or_missing(&drift["remote_sha"]),
+                    source,
+                )
+            }),
+            CmdCommand::Revert { .. } => format!("Reverted {} files", result["reverted"]),
+            CmdCommand::Rescan { .. } => format!("Scanned {} files, {} changes", result["files"], result["changes"]),
+            CmdCommand::Limits { .. } => {
+                let mut lines = vec![format!("All peers  {}", render_rates(&result["global"]))];
+                if let Some(peers) = result["peers"].as_object() {
+                    let mut peers: Vec<_> = peers.iter().collect();
+                    peers.sort_by_key(|(peer_id, _)| peer_id.as_str());
+                    lines.extend(peers.into_iter().map(|(peer_id, rates)| format!("{}  {}", peer_id, render_rates(rates))));
+                }
+                let lan = if result["lan_unlimited"].as_bool().unwrap_or_default() { "unlimited" } else { "limited" };
+                lines.push(format!("LAN peers  {}", lan));
+                lines.join("\n")
+            }
+            CmdCommand::Queue { command: QueueCommand::List } => {
+                let files = render_list(&result["files"], "No queued files", |file| {
+                    format!(
+                        "{}  {}  {} bytes  from {}{}",
+                        text(&file["folder_id"]),
+                        text(&file["file_path"]),
+                        file["size"],
+                        text(&file["peer_id"]),
+                        if file["bumped"].is_null() { "" } else { "  bumped" },
+                    )
+                });
+                format!("Up to {} files at once, {}\n{}", result["max_concurrent"], text(&result["order"]).replace('_', " "), files)
+            }
+            CmdCommand::Queue { command: QueueCommand::Bump { .. } } => render_list(result, "Nothing bumped", |file| {
+                format!("Downloading {} in {} next", text(&file["file_path"]), text(&file["folder_id"]))
+            }),
+            CmdCommand::Queue { command: QueueCommand::Set { .. } } => {
+                format!("Up to {} files at once, {}", result["max_concurrent"], text(&result["order"]).replace('_', " "))
+            }
+            CmdCommand::Versions { command: VersionsCommand::List { .. } } => render_list(result, "No versions", |version| {
+                format!(
+                    "{}  {}  {}  {} bytes  {}",
+                    text(&version["folder_id"]),
+                    text(&version["file_path"]),
+                    text(&version["version"]),
+                    version["size"],
+                    age(version["saved_at"].as_u64().unwrap_or_default()),
+                )
+            }),
+            CmdCommand::Versions { command: VersionsCommand::Restore { .. } } => {
+                format!("Restored {} in {} from {}", text(&result["file_path"]), text(&result["folder_id"]), text(&result["version"]))
+            }
+            CmdCommand::Fetch { .. } => {
+                let files = |key: &str| result[key].as_array().cloned().unwrap_or_default();
+                let fetched = files("fetched").into_iter().map(|file| {
+                    format!("Fetching {} in {}, {} bytes", text(&file["file_path"]), text(&file["folder_id"]), file["size"])
+                });
+                let failed = files("failed").into_iter().map(|file| {
+                    format!("Cannot fetch {} in {}, {}", text(&file["file_path"]), text(&file["folder_id"]), text(&file["error"]))
+                });
+                let lines: Vec<String> = fetched.chain(failed).collect();
+                if lines.is_empty() {
+                    String::from("Nothing to fetch")
+                } else {
+                    lines.join("\n")
+                }
+            }
+            CmdCommand::Id => text(&result["id"]),
+        }
+    }
+}
+
+fn render_list(result: &Value, empty: &str, line: impl Fn(&Value) -> String) -> String {
+    match result.as_array() {
+        Some(items) if !items.is_empty() => items.iter().map(line).collect::<Vec<_>>().join("\n"),
+        _ => String::from(empty),
+    }
+}
+
+fn render_rates(rates: &Value) -> String {
+    let rate = |value: &Value| match value.as_u64() {
+        Some(rate) => format!("{} KiB/s", rate / 1024),
+        None => String::from("unlimited"),
+    };
+    format!("upload {}  download {}", rate(&rates["upload"]), rate(&rates["download"]))
+}
+
+/// How long ago a time in milliseconds since the epoch was, roughly
+fn age(millis: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or_default();
+    let seconds = now.saturating_sub(millis) / 1000;
+    match seconds {
+        0..60 => format!("{}s ago", seconds),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+fn text(value: &Value) -> String {
+    value.as_str().map(String::from).unwrap_or_else(|| value.to_string())
+}
+
+fn absolute(path: &str) -> String {
+    std::path::absolute(path).map(|path| path.to_string_lossy().to_string()).unwrap_or_else(|_| String::from(path))
+}
+
+/// Folders are given by id or path, only an existing path is made absolute
+fn absolute_if_exists(folder: &str) -> String {
+    if std::path::Path::new(folder).exists() {
+        absolute(folder)
+    } else {
+        String::from(folder)
+    }
+}
+
+fn short_sha(value: &Value) -> String {
+    text(value).chars().take(12).collect()
+}
+
+fn short_sha_or_missing(value: &Value) -> String {
+    if value.is_null() {
+        String::from("missing")
+    } else {
+        short_sha(value)
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> CmdArgs {
+        CmdArgs::try_parse_from(std::iter::once("decen-peer").chain(args.iter().copied())).unwrap()
+    }
+
+    /// The control request a command line maps to, as it is sent to the node
+    fn request(args: &[&str]) -> Value {
+        serde_json::to_value(parse(args).command.control_command().unwrap()).unwrap()
+    }
+
+    fn now_millis() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+
+    #[test]
+    fn global_options_go_anywhere() {
+        let args = parse(&["status", "--json", "-c", "/tmp/other.sock"]);
+        assert_eq!(args.control_socket, "/tmp/other.sock");
+        assert!(args.json);
+        assert_eq!(request(&["status"]), json!({ "method": "status" }));
+        assert!(parse(&["run"]).command.control_command().is_none());
+    }
+
+    #[test]
+    fn commands_map_to_control_requests() {
+        assert_eq!(
+            request(&["limits", "set", "--upload", "10", "--peer", "node-b"]),
+            json!({ "method": "set_limits", "params": { "peer": "node-b", "upload": 10240, "download": null, "lan_unlimited": null } })
+        );
+        assert_eq!(
+            request(&["queue", "set", "--max-concurrent", "2", "--order", "smallest-first"]),
+            json!({ "method": "set_queue", "params": { "max_concurrent": 2, "order": "smallest_first" } })
+        );
+        assert_eq!(
+            request(&["queue", "bump", "big/one.bin", "--folder", "docs"]),
+            json!({ "method": "bump_transfer", "params": { "folder": "docs", "path": "big/one.bin" } })
+        );
+        assert_eq!(
+            request(&["versions", "list", "notes.txt", "--folder", "docs"]),
+            json!({ "method": "versions", "params": { "folder": "docs", "path": "notes.txt" } })
+        );
+        assert_eq!(
+            request(&["folders", "add", "/data/photos", "--peer", "a", "--peer", "b", "--mode", "receive-only", "--placeholders"]),
+            json!({ "method": "add_folder", "params": {
+                "path": "/data/photos", "id": null, "peers": ["a", "b"], "mode": "receive_only", "hash": "sha256", "placeholders": true,
+            } })
+        );
+        assert_eq!(request(&["rescan"]), json!({ "method": "rescan", "params": { "folder": null } }));
+    }
+
+    #[test]
+    fn invalid_command_lines_are_refused() {
+        let parse = |args: &[&str]| CmdArgs::try_parse_from(std::iter::once("decen-peer").chain(args.iter().copied()));
+        assert!(parse(&["queue", "set", "--order", "fastest"]).is_err());
+        assert!(parse(&["fetch"]).is_err());
+        assert!(parse(&["versions"]).is_err());
+        assert!(parse(&["limits", "set", "--upload", "fast"]).is_err());
+    }
+
+    #[test]
+    fn status_is_rendered() {
+        let result = json!({
+            "id": "node-a", "paused": true, "folders": 2, "peers": 1, "transfers": 3, "queued": 4,
+            "conflicts": 0, "drift": 1, "errors": 5,
+            "compression": { "input_bytes": 4096, "output_bytes": 1024, "ratio": 0.25, "compress_seconds": 0.5 },
+        });
+        assert_eq!(
+            parse(&["status"]).command.render(&result),
+            "Node       node-a\nState      paused\nFolders    2\nPeers      1\nTransfers  3\nQueued     4\nConflicts  0\nDrift      1\nErrors     5\nCompressed 4 KiB to 1 KiB (25%) in 0.50s"
+        );
+    }
+
+    #[test]
+    fn queue_is_rendered() {
+        let command = parse(&["queue", "list"]).command;
+        let result = json!({
+            "max_concurrent": 2,
+            "order": "oldest_first",
+            "files": [
+                { "folder_id": "docs", "file_path": "a.txt", "size": 10, "peer_id": "node-b", "bumped": 1 },
+                { "folder_id": "docs", "file_path": "b.txt", "size": 20, "peer_id": "node-c", "bumped": null },
+            ],
+        });
+        assert_eq!(
+            command.render(&result),
+            "Up to 2 files at once, oldest first\ndocs  a.txt  10 bytes  from node-b  bumped\ndocs  b.txt  20 bytes  from node-c"
+        );
+        let result = json!({ "max_concurrent": 4, "order": "random", "files": [] });
+        assert_eq!(command.render(&result), "Up to 4 files at once, random\nNo queued files");
+    }
+
+    #[test]
+    fn versions_are_rendered() {
+        let command = parse(&["versions", "list"]).command;
+        let saved_at = now_millis() - 2 * 3600 * 1000;
+        let version = format!("a~{}.txt", saved_at);
+        let result = json!([{ "folder_id": "docs", "file_path": "a.txt", "version": version, "size": 7, "saved_at": saved_at }]);
+        assert_eq!(command.render(&result), format!("docs  a.txt  {}  7 bytes  2h ago", version));
+        assert_eq!(command.render(&json!([])), "No versions");
+    }
+}
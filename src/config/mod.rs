@@ -0,0 +1,133 @@
+use async_std::{fs, path::Path};
+use log::info;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{broker::queue::QueueConfig, folder::{FolderConfig, FolderMode, ANY_PEER}, io::{hash::HashAlgorithm, names::NameRules, selection::Selection, versions::VersioningConfig}, limit::BandwidthLimits, Result};
+
+/// Id of the node and the folders it shares, kept in a JSON file so both survive restarts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeConfig {
+    pub node_id: String,
+    #[serde(default)]
+    pub folders: Vec<FolderConfig>,
+    #[serde(default)]
+    pub limits: BandwidthLimits,
+    #[serde(default)]
+    pub queue: QueueConfig,
+}
+
+impl NodeConfig {
+    ///
+    /// Reads the node config, a new one with a fresh node id when the file does not exist yet
+    /// # Arguments
+    /// * `path` - The JSON file the config is stored in
+    ///
+    pub async fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists().await {
+            let config = NodeConfig { node_id: Uuid::new_v4().to_string(), folders: vec![], limits: BandwidthLimits::default(), queue: QueueConfig::default() };
+            info!("Created node {} with config {}", config.node_id, path);
+            config.save(path).await?;
+            return Ok(config);
+        }
+        let json = fs::read_to_string(path).await?;
+        let config = serde_json::from_str(&json).map_err(|err| format!("Invalid config {} {}", path, err))?;
+        Ok(config)
+    }
+
+    pub async fn save(&self, path: &str) -> Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        // Written next to the config and renamed, a crash never leaves half a config behind
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, json).await?;
+        fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+
+    pub fn folder(&self, id_or_path: &str) -> Option<&FolderConfig> {
+        self.folders.iter().find(|folder| folder.id == id_or_path || folder.path == id_or_path)
+    }
+
+    ///
+    /// Adds a folder to the config, the caller saves it
+    /// # Arguments
+    /// * `path` - Folder to share, made absolute
+    /// * `id` - Id the peers know the folder by, the name of the directory when None
+    /// * `peers` - Peers to share the folder with, every peer when empty
+    /// * `mode` - Which way changes flow
+    /// * `hash` - How files are hashed, every peer of the folder has to use the same
+    /// * `placeholders` - Files of the peers are created empty and downloaded once fetched
+    ///
+    pub async fn add_folder(
+        &mut self,
+        path: &str,
+        id: Option<String>,
+        peers: Vec<String>,
+        mode: FolderMode,
+        hash: HashAlgorithm,
+        placeholders: bool,
+    ) -> std::result::Result<FolderConfig, String> {
+        let path = fs::canonicalize(path).await.map_err(|err| format!("Cannot share {} {}", path, err))?;
+        if !path.is_dir().await {
+            return Err(format!("{} is not a folder", path.display()));
+        }
+        let path = path.to_string_lossy().to_string();
+        let folder = FolderConfig {
+            id: id.unwrap_or_else(|| self.default_folder_id(&path)),
+            path,
+            peers: if peers.is_empty() { vec![String::from(ANY_PEER)] } else { peers },
+            mode,
+            versioning: VersioningConfig::default(),
+            names: NameRules::default(),
+            hash,
+            selection: Selection::default(),
+            placeholders,
+        };
+        self.validate_new_folder(&folder)?;
+        self.folders.push(folder.clone());
+        Ok(folder)
+    }
+
+    ///
+    /// Checks that a folder can be added next to the ones already shared
+    /// # Returns
+    /// * `Err` - The id is taken, or the path is, contains or sits inside a shared folder
+    ///
+    fn validate_new_folder(&self, folder: &FolderConfig) -> std::result::Result<(), String> {
+        if folder.id.is_empty() {
+            return Err(String::from("Folder id cannot be empty"));
+        }
+        for existing in &self.folders {
+            if existing.id == folder.id {
+                return Err(format!("Folder id {} is already used by {}", folder.id, existing.path));
+            }
+            let existing_path = Path::new(&existing.path);
+            let path = Path::new(&folder.path);
+            if path.starts_with(existing_path) || existing_path.starts_with(path) {
+                return Err(format!("{} overlaps the shared folder {} ({})", folder.path, existing.id, existing.path));
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Picks an id for a folder added without one, the name of the directory so that peers
+    /// adding a folder with the same name sync it without further setup
+    ///
+    fn default_folder_id(&self, path: &str) -> String {
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("folder"));
+        let mut id = name.clone();
+        let mut suffix = 2;
+        while self.folders.iter().any(|folder| folder.id == id) {
+            id = format!("{}-{}", name, suffix);
+            suffix += 1;
+        }
+        id
+    }
+}
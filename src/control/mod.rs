@@ -0,0 +1,444 @@
+use std::{os::unix::fs::PermissionsExt, sync::Arc};
+
+use async_std::{
+    fs::DirBuilder,
+    io::BufReader,
+    os::unix::{fs::DirBuilderExt, net::{UnixListener, UnixStream}},
+    path::Path,
+    prelude::*,
+};
+use futures::{channel::oneshot, select, FutureExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{broker::queue::QueueOrder, folder::FolderMode, io::hash::HashAlgorithm, shutdown::ShutdownListener, spawn_and_log_error, InternalMessage, Result, Sender};
+
+pub type ControlResult = std::result::Result<Value, String>;
+
+const JSONRPC_VERSION: &str = "2.0";
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Actions a local client can ask the running node to perform, one per JSON-RPC method
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Broadcasts a `Command::Test` with the given message to every connected peer
+    Test { message: String },
+    /// Summary of the node: id, pause state and the size of every list below
+    Status,
+    /// Id this node registered with the rendezvous server
+    Id,
+    /// Connected peers
+    Peers,
+    /// Shared folders, the peers they are shared with and their sync state
+    Folders,
+    /// Files currently being downloaded
+    Transfers,
+    /// Local files that differed from the version a peer sent, with the copy their local version
+    /// was kept in
+    Conflicts,
+    /// Files that differ from the cluster because of the mode of their folder
+    Drift,
+    /// Deletes new files and downloads changed ones again in a receive only folder, every receive
+    /// only folder when None
+    Revert {
+        #[serde(default)]
+        folder: Option<String>,
+    },
+    /// Most recent sync errors, oldest first
+    Errors,
+    /// Compares a folder, every folder when None, with its index and announces every difference
+    /// to the peers
+    Rescan {
+        #[serde(default)]
+        folder: Option<String>,
+    },
+    /// Stops announcing local changes and applying remote ones until resumed
+    Pause,
+    Resume,
+    /// Bandwidth limits of the node
+    Limits,
+    /// Sets the upload and download limits in bytes per second of a peer, `*` for every peer
+    /// without its own limits, or the global ones when None. A missing limit stays as it is, 0
+    /// lifts it.
+    SetLimits {
+        #[serde(default)]
+        peer: Option<String>,
+        #[serde(default)]
+        upload: Option<u64>,
+        #[serde(default)]
+        download: Option<u64>,
+        /// Lifts every limit for peers on a private network, unchanged when None
+        #[serde(default)]
+        lan_unlimited: Option<bool>,
+    },
+    /// Files waiting for a download slot in the order they start, and the queue settings
+    Queue,
+    /// Downloads a queued file next, `path` is relative to the folder or absolute. Looked up in
+    /// every folder when `folder` is None.
+    BumpTransfer {
+        #[serde(default)]
+        folder: Option<String>,
+        path: String,
+    },
+    /// Changes how many files are downloaded at once and which queued file starts next, a
+    /// setting is unchanged when None
+    SetQueue {
+        #[serde(default)]
+        max_concurrent: Option<usize>,
+        #[serde(default)]
+        order: Option<QueueOrder>,
+    },
+    /// Versions the sync kept of replaced and deleted files, newest first. Only those of `path`,
+    /// relative to the folder or absolute, when given, and of every folder when `folder` is None.
+    Versions {
+        #[serde(default)]
+        folder: Option<String>,
+        #[serde(default)]
+        path: Option<String>,
+    },
+    /// Puts a kept version back in place of its file, the current content is kept as a version
+    RestoreVersion { folder: String, version: String },
+    /// Shares a folder under `id`, the directory name by default, with `peers`, every peer by default
+    AddFolder {
+        path: String,
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        peers: Vec<String>,
+        #[serde(default)]
+        mode: FolderMode,
+        #[serde(default)]
+        hash: HashAlgorithm,
+        #[serde(default)]
+        placeholders: bool,
+    },
+    /// Stops sharing a folder given by id or path, its files are kept
+    RemoveFolder { folder: String },
+    /// Downloads only the `include` paths of a folder, every path when empty, except `exclude`.
+    /// Replaces the previous selection.
+    SelectPaths {
+        folder: String,
+        #[serde(default)]
+        include: Vec<String>,
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+    /// Downloads the placeholders at `path`, or below it for a directory. `path` is relative to
+    /// the folder or absolute, looked up in every folder when `folder` is None. Placeholders that
+    /// cannot be fetched are returned with the reason next to the fetched ones.
+    Fetch {
+        #[serde(default)]
+        folder: Option<String>,
+        path: String,
+    },
+    /// Sends `Command::Leave` to the peer and closes its connection
+    DisconnectPeer { peer_id: String },
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ControlRequest {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ControlError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ControlResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+}
+
+impl ControlResponse {
+    fn result(id: Value, result: Value) -> Self {
+        ControlResponse { jsonrpc: String::from(JSONRPC_VERSION), id, result: Some(result), error: None }
+    }
+
+    fn error(id: Value, code: i64, message: String) -> Self {
+        ControlResponse {
+            jsonrpc: String::from(JSONRPC_VERSION),
+            id,
+            result: None,
+            error: Some(ControlError { code, message }),
+        }
+    }
+}
+
+/// Serves JSON-RPC 2.0 requests, one JSON document per line, on a Unix domain socket
+pub struct ControlServer {
+    socket_path: String,
+}
+
+/// The socket in the runtime directory of the user when there is one, in `/tmp` otherwise
+pub fn default_socket_path() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_dir) if !runtime_dir.is_empty() => format!("{}/decen-peer.sock", runtime_dir),
+        _ => String::from("/tmp/decen-peer.sock"),
+    }
+}
+
+impl ControlServer {
+    pub fn new(socket_path: String) -> Self {
+        ControlServer { socket_path }
+    }
+}
+
+impl ControlServer {
+    pub async fn accept_loop(self, broker_sender: Sender<InternalMessage>, shutdown: ShutdownListener) -> Result<()> {
+        let socket_path = Path::new(&self.socket_path);
+        if socket_path.exists().await {
+            // Left over by a previous run that did not exit cleanly
+            async_std::fs::remove_file(socket_path).await?;
+        }
+        let listener = bind_private(socket_path).await?;
+        info!("Control socket listening on {}", self.socket_path);
+
+        let mut incoming = listener.incoming().fuse();
+        let mut shutdown = shutdown.fuse();
+        loop {
+            let stream = select! {
+                stream = incoming.next().fuse() => match stream {
+                    Some(stream) => stream?,
+                    None => break,
+                },
+                _ = shutdown => break,
+            };
+            spawn_and_log_error(control_connection_loop(stream, broker_sender.clone()));
+        }
+        async_std::fs::remove_file(socket_path).await?;
+        Ok(())
+    }
+}
+
+///
+/// Binds the control socket so that only the user running the node can connect to it. Anyone who
+/// can connect controls the node, so the socket is bound in a directory only that user can enter,
+/// restricted and then moved to its place
+/// # Arguments
+/// * `socket_path` - Where the socket is served
+///
+async fn bind_private(socket_path: &Path) -> Result<UnixListener> {
+    let file_name = socket_path.file_name().ok_or("The control socket path has no file name")?;
+    let parent = socket_path.parent().unwrap_or(Path::new(""));
+    // Short names, socket paths are limited to about a hundred bytes
+    let private_dir = parent.join(format!(".{}.{}", file_name.to_string_lossy(), &Uuid::new_v4().simple().to_string()[..8]));
+    DirBuilder::new().mode(0o700).create(&private_dir).await?;
+    let private_path = private_dir.join("sock");
+    let bound: Result<UnixListener> = async {
+        let listener = UnixListener::bind(&private_path).await?;
+        async_std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600)).await?;
+        async_std::fs::rename(&private_path, socket_path).await?;
+        Ok(listener)
+    }
+    .await;
+    if bound.is_err() {
+        let _ = async_std::fs::remove_file(&private_path).await;
+    }
+    async_std::fs::remove_dir(&private_dir).await?;
+    bound
+}
+
+async fn control_connection_loop(stream: UnixStream, mut broker_sender: Sender<InternalMessage>) -> Result<()> {
+    let stream = Arc::new(stream);
+    let mut lines = BufReader::new(&*stream).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        debug!("Control request {}", line);
+        let response = handle_request(&line, &mut broker_sender).await;
+        let response_json = serde_json::to_string(&response)?;
+        (&*stream).write_all(response_json.as_bytes()).await?;
+        (&*stream).write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(line: &str, broker_sender: &mut Sender<InternalMessage>) -> ControlResponse {
+    let request: ControlRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return ControlResponse::error(Value::Null, PARSE_ERROR, err.to_string()),
+    };
+    let command = serde_json::json!({ "method": request.method, "params": request.params });
+    let command: ControlCommand = match serde_json::from_value(command) {
+        Ok(command) => command,
+        // Methods whose params are all optional may be called without params
+        Err(err) => match serde_json::from_value(serde_json::json!({ "method": request.method, "params": {} })) {
+            Ok(command) if request.params.is_null() => command,
+            _ => return ControlResponse::error(request.id, INVALID_REQUEST, err.to_string()),
+        },
+    };
+
+    let (reply, response) = oneshot::channel();
+    if let Err(err) = broker_sender.send(InternalMessage::Control { command, reply }).await {
+        warn!("Cannot reach the broker {:?}", err);
+        return ControlResponse::error(request.id, INTERNAL_ERROR, err.to_string());
+    }
+    match response.await {
+        Ok(Ok(result)) => ControlResponse::result(request.id, result),
+        Ok(Err(message)) => ControlResponse::error(request.id, INTERNAL_ERROR, message),
+        Err(err) => ControlResponse::error(request.id, INTERNAL_ERROR, err.to_string()),
+    }
+}
+
+///
+/// Sends one request to a running node and waits for its answer
+/// # Arguments
+/// * `socket_path` - Control socket of the node
+/// * `command` - The request to send
+///
+/// # Returns
+/// * `Value` - The `result` member of the response, a JSON-RPC error becomes `Err`
+///
+pub async fn call(socket_path: &str, command: &ControlCommand) -> Result<Value> {
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .map_err(|err| format!("Cannot connect to {}, is the node running? {}", socket_path, err))?;
+    let mut request = serde_json::to_value(command)?;
+    request["jsonrpc"] = Value::from(JSONRPC_VERSION);
+    request["id"] = Value::from(1);
+    let request_json = serde_json::to_string(&request)?;
+    (&stream).write_all(request_json.as_bytes()).await?;
+    (&stream).write_all(b"\n").await?;
+
+    let mut lines = BufReader::new(&stream).lines();
+    let line = match lines.next().await {
+        Some(line) => line?,
+        None => Err("Node closed the control connection")?,
+    };
+    let response: ControlResponse = serde_json::from_str(&line)?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(error.message)?,
+        (Some(result), None) => Ok(result),
+        (None, None) => Ok(Value::Null),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{channel, task};
+    use serde_json::json;
+
+    /// Sends a request to a broker that answers the first control request with `answer`
+    async fn answered(line: &str, answer: ControlResult) -> (ControlResponse, Option<ControlCommand>) {
+        let (mut sender, receiver) = channel::unbounded();
+        let broker = async {
+            match receiver.recv().await {
+                Ok(InternalMessage::Control { command, reply }) => {
+                    let _ = reply.send(answer);
+                    Some(command)
+                }
+                _ => None,
+            }
+        };
+        futures::join!(handle_request(line, &mut sender), broker)
+    }
+
+    /// Sends a request that must be answered without the broker
+    async fn refused(line: &str) -> ControlResponse {
+        let (mut sender, receiver) = channel::unbounded();
+        let response = handle_request(line, &mut sender).await;
+        assert!(receiver.is_empty());
+        response
+    }
+
+    fn error_code(response: &ControlResponse) -> i64 {
+        assert!(response.result.is_none());
+        response.error.as_ref().unwrap().code
+    }
+
+    #[test]
+    fn requests_are_decoded_into_commands() {
+        task::block_on(async {
+            let line = r#"{"jsonrpc":"2.0","id":7,"method":"fetch","params":{"path":"a.txt"}}"#;
+            let (response, command) = answered(line, Ok(json!({ "fetched": [] }))).await;
+            assert!(matches!(command, Some(ControlCommand::Fetch { folder: None, path }) if path == "a.txt"));
+            assert_eq!(response.id, json!(7));
+            assert_eq!(response.result, Some(json!({ "fetched": [] })));
+            assert!(response.error.is_none());
+        });
+    }
+
+    #[test]
+    fn params_may_be_left_out() {
+        task::block_on(async {
+            let line = r#"{"jsonrpc":"2.0","id":1,"method":"status"}"#;
+            let (_, command) = answered(line, Ok(Value::Null)).await;
+            assert!(matches!(command, Some(ControlCommand::Status)));
+
+            // Every param of a rescan is optional
+            let line = r#"{"jsonrpc":"2.0","id":2,"method":"rescan","params":null}"#;
+            let (_, command) = answered(line, Ok(Value::Null)).await;
+            assert!(matches!(command, Some(ControlCommand::Rescan { folder: None })));
+        });
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        task::block_on(async {
+            let response = refused("{not json").await;
+            assert_eq!(error_code(&response), PARSE_ERROR);
+            assert_eq!(response.id, Value::Null);
+
+            let response = refused(r#"{"jsonrpc":"2.0","id":"a","method":"shutdown_all"}"#).await;
+            assert_eq!(error_code(&response), INVALID_REQUEST);
+            assert_eq!(response.id, json!("a"));
+
+            // A required param is missing
+            let response = refused(r#"{"jsonrpc":"2.0","id":3,"method":"fetch"}"#).await;
+            assert_eq!(error_code(&response), INVALID_REQUEST);
+            let response = refused(r#"{"jsonrpc":"2.0","id":4,"method":"fetch","params":{"path":1}}"#).await;
+            assert_eq!(error_code(&response), INVALID_REQUEST);
+        });
+    }
+
+    #[test]
+    fn failures_of_the_broker_are_internal_errors() {
+        task::block_on(async {
+            let line = r#"{"jsonrpc":"2.0","id":5,"method":"pause"}"#;
+            let (response, _) = answered(line, Err(String::from("Sync is paused"))).await;
+            assert_eq!(error_code(&response), INTERNAL_ERROR);
+            assert_eq!(response.error.unwrap().message, "Sync is paused");
+
+            let (mut sender, receiver) = channel::unbounded();
+            drop(receiver);
+            let response = handle_request(line, &mut sender).await;
+            assert_eq!(error_code(&response), INTERNAL_ERROR);
+            assert_eq!(response.id, json!(5));
+        });
+    }
+
+    #[test]
+    fn the_socket_is_only_reachable_by_its_user() {
+        task::block_on(async {
+            let root = std::env::temp_dir().join(format!("decen-control-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&root).unwrap();
+            let socket_path = root.join("node.sock");
+            let listener = bind_private(Path::new(&socket_path)).await.unwrap();
+
+            let mode = std::fs::metadata(&socket_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            // Only the socket is left, the directory it was bound in is gone
+            assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+            assert!(UnixStream::connect(&socket_path).await.is_ok());
+
+            drop(listener);
+            std::fs::remove_dir_all(&root).unwrap();
+        });
+    }
+}
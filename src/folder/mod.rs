@@ -0,0 +1,276 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_std::sync::{Mutex, RwLock};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    io::{
+    chunk::Chunk,
+    file_handler::FileHandler,
+    hash::HashAlgorithm,
+    hash_cache::HashKey,
+    ignore_rules::IgnoreRules,
+    index::{modified_nanos, Index, IndexEntry, Presence},
+    metadata::mode,
+    names::NameRules,
+    selection::Selection,
+    transfer_state::TransferStates,
+    versions::VersioningConfig,
+    },
+    metrics::metrics,
+    Result,
+};
+
+/// Peer entry that shares a folder with every peer
+pub const ANY_PEER: &str = "*";
+
+/// Shared folders by id, the same map is read by the broker and the peer message handler
+pub type Folders = Arc<RwLock<HashMap<String, Arc<SharedFolder>>>>;
+
+/// Which way changes of a folder flow
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum FolderMode {
+    /// Local changes are announced and remote ones applied
+    #[default]
+    SendReceive,
+    /// Local changes are announced, remote ones are only reported as drift
+    SendOnly,
+    /// Remote changes are applied, local ones are never announced and can be reverted
+    ReceiveOnly,
+}
+
+impl FolderMode {
+    pub fn sends(&self) -> bool {
+        *self != FolderMode::ReceiveOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        *self != FolderMode::SendOnly
+    }
+}
+
+/// A folder as stored in the node config. Peers only sync folders with the same id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FolderConfig {
+    pub id: String,
+    pub path: String,
+    /// Ids of the peers this folder is shared with, `*` for every peer. Peers are not
+    /// authenticated, any host that can reach the node may claim the id of a member, so this
+    /// limits which peers the folder is offered to, it does not keep it from anyone else
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub mode: FolderMode,
+    /// Retention of the versions the sync replaced or deleted
+    #[serde(default)]
+    pub versioning: VersioningConfig,
+    /// Names the file system of the folder cannot store or tell apart, of the platform by default
+    #[serde(default)]
+    pub names: NameRules,
+    /// How files and chunks are hashed, the same on every peer of the folder
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Paths downloaded from the peers, every path by default
+    #[serde(default)]
+    pub selection: Selection,
+    /// Files announced by the peers are created empty and downloaded once fetched
+    #[serde(default)]
+    pub placeholders: bool,
+}
+
+impl FolderConfig {
+    /// Whether the folder is synced with the peer that claims this id, which proves nothing
+    pub fn is_shared_with(&self, peer_id: &str) -> bool {
+        self.peers.iter().any(|peer| peer == ANY_PEER || peer == peer_id)
+    }
+}
+
+/// A folder being synced with everything the node keeps track of for it
+#[derive(Debug)]
+pub struct SharedFolder {
+    pub config: FolderConfig,
+    pub file_handler: FileHandler,
+    pub index: Mutex<Index>,
+    /// Chunk downloads to resume
+    pub transfer_states: Mutex<TransferStates>,
+    pub ignore_rules: Arc<RwLock<IgnoreRules>>,
+    /// The selection of the config, changed at runtime
+    pub selection: RwLock<Selection>,
+}
+
+impl SharedFolder {
+    ///
+    /// Loads the index, the unfinished downloads and the ignore patterns of a folder
+    /// # Arguments
+    /// * `config` - The folder to open
+    ///
+    pub async fn open(config: FolderConfig) -> Self {
+        let mut index = Index::load(&config.path).await;
+        let mut transfer_states = TransferStates::load(&config.path).await;
+        if index.use_hash(config.hash) {
+            warn!("Folder {} now hashes with {}, its files are hashed again", config.id, config.hash);
+            // Their chunks were listed with the other algorithm
+            transfer_states.clear();
+        }
+        let ignore_rules = IgnoreRules::load(&config.path);
+        SharedFolder {
+            file_handler: FileHandler::new(config.path.clone(), config.versioning, config.hash),
+            index: Mutex::new(index),
+            transfer_states: Mutex::new(transfer_states),
+            ignore_rules: Arc::new(RwLock::new(ignore_rules)),
+            selection: RwLock::new(config.selection.clone()),
+            config,
+        }
+    }
+}
+
+impl SharedFolder {
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn root(&self) -> &str {
+        self.file_handler.root()
+    }
+
+    pub fn mode(&self) -> FolderMode {
+        self.config.mode
+    }
+
+    /// Writes the index and the unfinished downloads, both only when they changed
+    pub async fn save_state(&self) -> Result<()> {
+        self.index.lock().await.save(self.root()).await?;
+        self.transfer_states.lock().await.save(self.root(), &self.file_handler).await
+    }
+
+    pub async fn is_ignored(&self, file: &str) -> bool {
+        let is_dir = match self.file_handler.metadata(file).await {
+            Some(metadata) => metadata.is_dir(),
+            None => false,
+        };
+        self.ignore_rules.read().await.is_ignored(file, is_dir)
+    }
+
+    /// False for files this node does not download
+    pub async fn is_selected(&self, file: &str) -> bool {
+        self.selection.read().await.is_selected(file)
+    }
+
+    /// True while an empty file stands in for the content a peer announced
+    pub async fn is_placeholder(&self, file: &str) -> bool {
+        self.index.lock().await.get(file).is_some_and(|entry| entry.presence == Presence::Placeholder)
+    }
+
+    ///
+    /// The SHA of a file, from the hash cache while its inode, size and modification time are
+    /// the ones it was hashed with
+    /// # Returns
+    /// * `Option<String>` - None when the file does not exist or is a folder
+    ///
+    pub async fn file_sha(&self, file: &str) -> Option<String> {
+        let metadata = self.file_handler.metadata(file).await.filter(|metadata| !metadata.is_dir())?;
+        let key = HashKey::of(&metadata);
+        if let Some(sha) = self.index.lock().await.cached_sha(&key, file) {
+            metrics().hash_cache_hit();
+            return Some(sha);
+        }
+        let sha = self.file_handler.file_sha(file).await?;
+        // Written while it was hashed, the hash may be of neither version
+        let unchanged = self.file_handler.metadata(file).await.is_some_and(|metadata| HashKey::of(&metadata) == key);
+        if unchanged && key.is_cacheable() {
+            self.index.lock().await.cache_sha(key, file, sha.clone());
+        }
+        Some(sha)
+    }
+
+    /// Remembers the current size, modification time, permissions and chunks of a file along
+    /// with its hash
+    pub async fn index_file(&self, file: &str, sha: &str) {
+        let metadata = match self.file_handler.metadata(file).await {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        let size = metadata.len();
+        let modified = metadata.modified().map(modified_nanos).unwrap_or_default();
+        let known = self.index.lock().await.get(file).cloned();
+        let chunks = match known {
+            // A link has no content of its own to reuse
+            _ if metadata.is_symlink() => vec![],
+            // Touched but not changed, the chunks are still right
+            Some(known) if known.sha == sha && known.size == size && !known.chunks.is_empty() => known.chunks,
+            _ => match self.file_handler.manifest(file).await {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    warn!("Cannot cut {} in {} into chunks {}", file, self.id(), err);
+                    vec![]
+                }
+            },
+        };
+        let entry = IndexEntry {
+            sha: String::from(sha),
+            size,
+            modified,
+            mode: mode(&metadata),
+            chunks,
+            presence: Presence::Local,
+            symlink: None,
+        };
+        self.index.lock().await.insert(String::from(file), entry);
+    }
+
+    ///
+    /// The chunks of the current version of a file, from the index when the file did not change
+    /// since it was indexed
+    /// # Returns
+    /// * `(String, u64, Vec<Chunk>)` - SHA, size and chunks of the file, None if it does not exist
+    ///
+    pub async fn manifest(&self, file: &str) -> Option<(String, u64, Vec<Chunk>)> {
+        let metadata = self.file_handler.metadata(file).await.filter(|metadata| metadata.is_file())?;
+        let modified = metadata.modified().map(modified_nanos).unwrap_or_default();
+        let known = self.index.lock().await.get(file).cloned();
+        if let Some(known) = known {
+            if known.size == metadata.len() && known.modified == modified && (known.size == 0 || !known.chunks.is_empty()) {
+                return Some((known.sha, known.size, known.chunks));
+            }
+        }
+        let sha = self.file_sha(file).await?;
+        let chunks = self.file_handler.manifest(file).await.ok()?;
+        Some((sha, chunks.iter().map(|chunk| chunk.size).sum(), chunks))
+    }
+
+    ///
+    /// Finds local files holding the given chunks, as they were when indexed
+    /// # Returns
+    /// * `HashMap<String, (String, u64)>` - File and offset of every chunk found, by chunk hash
+    ///
+    pub async fn local_chunks(&self, chunks: &[Chunk]) -> HashMap<String, (String, u64)> {
+        let wanted: HashSet<&str> = chunks.iter().map(|chunk| chunk.hash.as_str()).collect();
+        let mut found = HashMap::new();
+        for (file, entry) in self.index.lock().await.files() {
+            for chunk in entry.chunks.iter().filter(|chunk| wanted.contains(chunk.hash.as_str())) {
+                found.entry(chunk.hash.clone()).or_insert_with(|| (file.clone(), chunk.offset));
+            }
+        }
+        found
+    }
+
+    ///
+    /// Drops a file from the index, or every file below it when it was a folder
+    /// # Returns
+    /// * `Vec<(String, IndexEntry)>` - What the index knew of the files it dropped
+    ///
+    pub async fn forget(&self, path: &str) -> Vec<(String, IndexEntry)> {
+        let mut index = self.index.lock().await;
+        if let Some(entry) = index.remove(path) {
+            return vec![(String::from(path), entry)];
+        }
+        let prefix = format!("{}/", path);
+        let files: Vec<String> = index.files().map(|(file, _)| file.clone()).filter(|file| file.starts_with(&prefix)).collect();
+        files.into_iter().filter_map(|file| index.remove(&file).map(|entry| (file, entry))).collect()
+    }
+}
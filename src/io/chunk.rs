@@ -0,0 +1,211 @@
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::io::hash::HashAlgorithm;
+
+const MIN_CHUNK_SIZE: usize = 4 * 1024;
+const AVG_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// Cut points are harder to hit before the average size and easier after it, FastCDC's
+/// normalized chunking keeps most chunks close to the average
+const MASK_SMALL: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() + 2);
+const MASK_LARGE: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() - 2);
+
+/// Random values for every byte, the same on every peer so both cut a file at the same places
+static GEAR: [u64; 256] = gear_table();
+
+/// A piece of a file cut where its content says, so an insertion only changes the chunks around it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub size: u64,
+    /// Hash of the chunk with the algorithm of the folder
+    pub hash: String,
+}
+
+/// The top `bits` bits of the gear hash, the low bits only depend on the last few bytes
+const fn mask(bits: u32) -> u64 {
+    ((1u64 << bits) - 1) << (64 - bits)
+}
+
+/// Fills the gear table with splitmix64, no need to ship 256 constants
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5eed_c0de_5eed_c0de;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the chunk at the start of `data`, `data` holds at least `MAX_CHUNK_SIZE` bytes
+/// unless it is the end of the file
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+///
+/// Cuts a file into content defined chunks with FastCDC
+/// # Arguments
+/// * `reader` - The file to cut
+/// * `algorithm` - How the chunks are hashed
+///
+/// # Returns
+/// * `Vec<Chunk>` - Every chunk of the file in order, empty for an empty file
+///
+pub fn chunks(mut reader: impl Read, algorithm: HashAlgorithm) -> io::Result<Vec<Chunk>> {
+    let mut chunks = vec![];
+    let mut buf: Vec<u8> = Vec::with_capacity(2 * MAX_CHUNK_SIZE);
+    let mut read_buf = vec![0; MAX_CHUNK_SIZE];
+    let mut offset = 0;
+    let mut eof = false;
+    loop {
+        while !eof && buf.len() < MAX_CHUNK_SIZE {
+            let read = reader.read(&mut read_buf)?;
+            if read == 0 {
+                eof = true;
+            }
+            buf.extend_from_slice(&read_buf[..read]);
+        }
+        if buf.is_empty() {
+            break;
+        }
+        let size = cut_point(&buf);
+        chunks.push(Chunk { offset, size: size as u64, hash: algorithm.digest(&buf[..size]) });
+        buf.drain(..size);
+        offset += size as u64;
+    }
+    Ok(chunks)
+}
+
+///
+/// Checks the chunks a peer listed for a file before a download is built from them
+/// # Arguments
+/// * `size` - Size of the file
+/// * `chunks` - The chunks as received
+///
+/// # Returns
+/// * `Result<(), String>` - Why the chunks cannot be of a file of that size, they have to follow
+///   each other from the start and none may be larger than `chunks` cuts them
+///
+pub fn check_manifest(size: u64, chunks: &[Chunk]) -> Result<(), String> {
+    let mut offset = 0;
+    for chunk in chunks {
+        if chunk.offset != offset {
+            return Err(format!("chunk at {} where {} was expected", chunk.offset, offset));
+        }
+        if chunk.size == 0 || chunk.size > MAX_CHUNK_SIZE as u64 {
+            return Err(format!("chunk at {} of {} bytes", chunk.offset, chunk.size));
+        }
+        offset += chunk.size;
+    }
+    if offset != size {
+        return Err(format!("chunks of {} bytes for a file of {} bytes", offset, size));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn cuts(chunks: &[Chunk]) -> Vec<u64> {
+        chunks.iter().map(|chunk| chunk.offset + chunk.size).collect()
+    }
+
+    #[test]
+    fn cut_points_stay_within_the_bounds() {
+        let data = data(4 * MAX_CHUNK_SIZE, 1);
+        assert_eq!(cut_point(&data[..100]), 100);
+        assert_eq!(cut_point(&data[..MIN_CHUNK_SIZE]), MIN_CHUNK_SIZE);
+        let mut start = 0;
+        while data.len() - start > MAX_CHUNK_SIZE {
+            let size = cut_point(&data[start..]);
+            assert!((MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size), "{} at {}", size, start);
+            start += size;
+        }
+        // Never a cut at all, the largest chunk
+        assert_eq!(cut_point(&vec![0; 2 * MAX_CHUNK_SIZE]), MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        let data = data(1_000_000, 2);
+        let found = chunks(Cursor::new(&data), HashAlgorithm::Sha256).unwrap();
+        assert!(check_manifest(data.len() as u64, &found).is_ok());
+        for chunk in &found {
+            let range = chunk.offset as usize..(chunk.offset + chunk.size) as usize;
+            assert_eq!(chunk.hash, HashAlgorithm::Sha256.digest(&data[range]));
+        }
+        assert!(chunks(Cursor::new(vec![]), HashAlgorithm::Sha256).unwrap().is_empty());
+    }
+
+    #[test]
+    fn chunks_are_deterministic() {
+        let data = data(300_000, 3);
+        let first = chunks(Cursor::new(&data), HashAlgorithm::Blake3).unwrap();
+        // Read in small pieces, the cuts do not depend on how the file is read
+        let second = chunks(io::BufReader::with_capacity(777, Cursor::new(&data)), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn insert_only_moves_nearby_cuts() {
+        let old = data(1_000_000, 4);
+        let mut new = old.clone();
+        new.splice(500_000..500_000, b"a few inserted bytes".iter().copied());
+        let shift = (new.len() - old.len()) as u64;
+        let old_cuts = cuts(&chunks(Cursor::new(&old), HashAlgorithm::Sha256).unwrap());
+        let new_cuts = cuts(&chunks(Cursor::new(&new), HashAlgorithm::Sha256).unwrap());
+        let before: Vec<&u64> = old_cuts.iter().filter(|cut| **cut < 500_000).collect();
+        assert_eq!(before, new_cuts.iter().filter(|cut| **cut < 500_000).collect::<Vec<_>>());
+        // Past the insert the same cuts come back, moved by its length
+        let after: Vec<u64> = old_cuts.iter().filter(|cut| **cut > 500_000 + 2 * MAX_CHUNK_SIZE as u64).map(|cut| cut + shift).collect();
+        assert!(!after.is_empty());
+        assert!(after.iter().all(|cut| new_cuts.contains(cut)));
+    }
+
+    #[test]
+    fn manifests_from_peers_are_checked() {
+        let chunk = |offset, size| Chunk { offset, size, hash: String::new() };
+        assert!(check_manifest(0, &[]).is_ok());
+        assert!(check_manifest(10, &[chunk(0, 4), chunk(4, 6)]).is_ok());
+        assert!(check_manifest(10, &[chunk(0, 4)]).is_err());
+        assert!(check_manifest(10, &[chunk(0, 4), chunk(5, 5)]).is_err());
+        assert!(check_manifest(10, &[chunk(4, 6), chunk(0, 4)]).is_err());
+        assert!(check_manifest(u64::MAX, &[chunk(0, u64::MAX)]).is_err());
+        assert!(check_manifest(0, &[chunk(0, 0)]).is_err());
+    }
+}
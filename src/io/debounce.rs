@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::debug;
+use notify::{
+    event::ModifyKind,
+    Event, EventKind,
+};
+
+/// Size and modification time of a file, used to tell whether it is still being written
+type Snapshot = Option<(u64, SystemTime)>;
+
+#[derive(Debug)]
+struct PendingEvent {
+    kind: EventKind,
+    last_seen: Instant,
+    snapshot: Snapshot,
+}
+
+/// Holds watcher events back per path until the path has been quiet for a while, merging the
+/// events received in the meantime
+#[derive(Debug)]
+pub struct Debouncer {
+    quiet_period: Duration,
+    pending: HashMap<PathBuf, PendingEvent>,
+}
+
+impl Debouncer {
+    pub fn new(quiet_period: Duration) -> Self {
+        Debouncer { quiet_period, pending: HashMap::new() }
+    }
+}
+
+impl Debouncer {
+    ///
+    /// Adds a raw watcher event, merging it with the event already pending for the same path
+    /// # Returns
+    /// * `Option<Event>` - The event itself when it is not debounced (no path, access events)
+    ///
+    pub async fn push(&mut self, event: Event) -> Option<Event> {
+        let path = match event.paths.first() {
+            Some(path) if is_debounced(&event.kind) => path.clone(),
+            _ => return Some(event),
+        };
+        let now = Instant::now();
+        let snapshot = snapshot(&path).await;
+        match self.pending.remove(&path) {
+            None => {
+                self.pending.insert(path, PendingEvent { kind: event.kind, last_seen: now, snapshot });
+            }
+            Some(previous) => match coalesce(previous.kind, event.kind) {
+                Some(kind) => {
+                    self.pending.insert(path, PendingEvent { kind, last_seen: now, snapshot });
+                }
+                None => debug!("{:?} created and removed before it settled", path),
+            },
+        }
+        None
+    }
+
+    ///
+    /// Takes the events whose path has been quiet for the whole quiet period and whose size and
+    /// modification time did not change since the last event
+    ///
+    pub async fn ready(&mut self, now: Instant) -> Vec<Event> {
+        let quiet: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.last_seen) >= self.quiet_period)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut ready = vec![];
+        for path in quiet {
+            let current = snapshot(&path).await;
+            let pending = match self.pending.get_mut(&path) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            if current.is_some() && current != pending.snapshot {
+                // Still being written, wait for another quiet period
+                pending.snapshot = current;
+                pending.last_seen = now;
+                continue;
+            }
+            if let Some(pending) = self.pending.remove(&path) {
+                ready.push(Event::new(pending.kind).add_path(path));
+            }
+        }
+        ready
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        (self.quiet_period / 2).max(Duration::from_millis(50))
+    }
+}
+
+fn is_debounced(kind: &EventKind) -> bool {
+    matches!(kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_))
+}
+
+///
+/// Merges two events for the same path into the one that describes the net change
+/// # Returns
+/// * `Option<EventKind>` - None when the events cancel out, a file created and removed again
+///
+fn coalesce(previous: EventKind, next: EventKind) -> Option<EventKind> {
+    match (previous, next) {
+        (EventKind::Create(_), EventKind::Remove(_)) => None,
+        (EventKind::Create(kind), EventKind::Modify(_)) => Some(EventKind::Create(kind)),
+        // Replaced by a new file with the same name, the peers only need the new content
+        (EventKind::Remove(_), EventKind::Create(kind)) => Some(EventKind::Create(kind)),
+        // A metadata change after a write must not hide the write
+        (EventKind::Modify(ModifyKind::Data(kind)), EventKind::Modify(_))
+        | (EventKind::Modify(_), EventKind::Modify(ModifyKind::Data(kind))) => Some(EventKind::Modify(ModifyKind::Data(kind))),
+        // The polling watcher reports writes as Any
+        (EventKind::Modify(ModifyKind::Any), EventKind::Modify(_)) => Some(EventKind::Modify(ModifyKind::Any)),
+        (_, next) => Some(next),
+    }
+}
+
+async fn snapshot(path: &Path) -> Snapshot {
+    let metadata = async_std::fs::metadata(path).await.ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    const QUIET: Duration = Duration::from_millis(100);
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    #[test]
+    fn writes_are_not_hidden_by_metadata_changes() {
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        let metadata = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions));
+        assert_eq!(coalesce(data, metadata), Some(data));
+        assert_eq!(coalesce(metadata, data), Some(data));
+        let any = EventKind::Modify(ModifyKind::Any);
+        assert_eq!(coalesce(any, metadata), Some(any));
+        assert_eq!(coalesce(metadata, any), Some(any));
+    }
+
+    #[test]
+    fn creation_and_removal_are_merged() {
+        let create = EventKind::Create(CreateKind::File);
+        let remove = EventKind::Remove(RemoveKind::File);
+        assert_eq!(coalesce(create, remove), None);
+        assert_eq!(coalesce(remove, create), Some(create));
+        assert_eq!(coalesce(create, EventKind::Modify(ModifyKind::Any)), Some(create));
+    }
+
+    #[test]
+    fn events_wait_for_the_quiet_period() {
+        task::block_on(async {
+            let mut debouncer = Debouncer::new(QUIET);
+            let access = event(EventKind::Access(notify::event::AccessKind::Any), "/missing/a");
+            assert!(debouncer.push(access).await.is_some());
+            assert!(debouncer.push(event(EventKind::Create(CreateKind::File), "/missing/a")).await.is_none());
+            assert!(debouncer.push(event(EventKind::Modify(ModifyKind::Any), "/missing/a")).await.is_none());
+            assert!(debouncer.ready(Instant::now()).await.is_empty());
+
+            let ready = debouncer.ready(Instant::now() + QUIET).await;
+            assert_eq!(ready.len(), 1);
+            assert_eq!(ready[0].kind, EventKind::Create(CreateKind::File));
+            assert!(debouncer.ready(Instant::now() + QUIET).await.is_empty());
+        });
+    }
+
+    #[test]
+    fn files_created_and_removed_are_dropped() {
+        task::block_on(async {
+            let mut debouncer = Debouncer::new(QUIET);
+            debouncer.push(event(EventKind::Create(CreateKind::File), "/missing/b")).await;
+            debouncer.push(event(EventKind::Remove(RemoveKind::File), "/missing/b")).await;
+            assert!(debouncer.ready(Instant::now() + QUIET).await.is_empty());
+        });
+    }
+}
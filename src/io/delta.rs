@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::io::hash::HashAlgorithm;
+
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+/// Literal data is cut into pieces of at most this size, so one instruction never holds a whole file
+pub const MAX_LITERAL_SIZE: usize = 64 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+
+/// Checksums of one block of the receiver's copy of a file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockSignature {
+    pub index: u64,
+    pub weak: u32,
+    pub strong: String,
+}
+
+/// One step of rebuilding a file from the receiver's old copy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeltaInstruction {
+    /// `count` blocks of the old copy, starting at block `index`
+    Copy { index: u64, count: u64 },
+    /// Data the old copy does not have
+    Literal { data: Vec<u8> },
+}
+
+/// Block size for a file, about the square root of its size like rsync
+pub fn block_size_for(size: u64) -> usize {
+    ((size as f64).sqrt() as usize).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Refuses a block size `block_size_for` never picks, it comes from a peer
+pub fn check_block_size(block_size: usize) -> io::Result<()> {
+    if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Ok(());
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Block size {} is not between {} and {}", block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)))
+}
+
+/// The rsync rolling checksum, two 16 bit sums that slide one byte at a time
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let length = block.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(*byte as u32));
+        }
+        Rolling { a: a & 0xffff, b: b & 0xffff }
+    }
+
+    fn roll(&mut self, out: u8, next: u8, block_size: usize) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32) & 0xffff;
+        self.b = self
+            .b
+            .wrapping_sub((block_size as u32).wrapping_mul(out as u32))
+            .wrapping_add(self.a)
+            & 0xffff;
+    }
+
+    fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+/// The first 16 bytes of the hash of a block, enough once the weak checksum matched
+fn strong_hash(block: &[u8], algorithm: HashAlgorithm) -> String {
+    let mut hash = algorithm.digest(block);
+    hash.truncate(32);
+    hash
+}
+
+/// Whether the receiver of a delta could have picked `block_size` for a copy of `blocks` full blocks
+pub fn fits_block_count(block_size: usize, blocks: usize) -> bool {
+    let smallest = (blocks as u64).saturating_mul(block_size as u64);
+    let largest = smallest.saturating_add(block_size as u64).saturating_sub(1);
+    check_block_size(block_size).is_ok() && block_size_for(smallest) <= block_size && block_size <= block_size_for(largest)
+}
+
+///
+/// Signs every full block of the receiver's copy, a shorter last block is always sent as literal data
+/// # Arguments
+/// * `reader` - The old copy of the file
+/// * `block_size` - Size of every block
+/// * `algorithm` - How the blocks are hashed, the one of the folder
+///
+pub fn signatures(mut reader: impl Read, block_size: usize, algorithm: HashAlgorithm) -> io::Result<Vec<BlockSignature>> {
+    check_block_size(block_size)?;
+    let mut signatures = vec![];
+    let mut block = vec![0; block_size];
+    let mut index = 0;
+    loop {
+        let read = read_full(&mut reader, &mut block)?;
+        if read < block_size {
+            break;
+        }
+        signatures.push(BlockSignature { index, weak: Rolling::new(&block).digest(), strong: strong_hash(&block, algorithm) });
+        index += 1;
+    }
+    Ok(signatures)
+}
+
+///
+/// Compares the sender's file with the signatures of the receiver's copy
+/// # Arguments
+/// * `reader` - The sender's current file
+/// * `block_size` - Block size the signatures were made with
+/// * `signatures` - Signatures of the receiver's copy
+/// * `algorithm` - How the blocks were hashed
+///
+/// # Returns
+/// * `Vec<DeltaInstruction>` - Blocks to copy and data to send, in file order
+///
+pub fn delta(
+    mut reader: impl Read,
+    block_size: usize,
+    signatures: &[BlockSignature],
+    algorithm: HashAlgorithm,
+) -> io::Result<Vec<DeltaInstruction>> {
+    check_block_size(block_size)?;
+    let mut blocks: HashMap<u32, Vec<&BlockSignature>> = HashMap::new();
+    for signature in signatures {
+        blocks.entry(signature.weak).or_default().push(signature);
+    }
+    let find_block = |rolling: &Rolling, window: &[u8]| -> Option<u64> {
+        let candidates = blocks.get(&rolling.digest())?;
+        let strong = strong_hash(window, algorithm);
+        candidates.iter().find(|signature| signature.strong == strong).map(|signature| signature.index)
+    };
+
+    let mut instructions = vec![];
+    // Bytes not sent yet, the window starts at `pos` and everything before it is literal
+    let mut buf: Vec<u8> = vec![];
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut chunk = vec![0; READ_SIZE];
+    loop {
+        while !eof && buf.len() < pos + block_size + 1 {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                eof = true;
+            }
+            buf.extend_from_slice(&chunk[..read]);
+        }
+        if signatures.is_empty() || buf.len() < pos + block_size {
+            break;
+        }
+        let window = &buf[pos..pos + block_size];
+        let current = rolling.unwrap_or_else(|| Rolling::new(window));
+        if let Some(index) = find_block(&current, window) {
+            push_literal(&mut instructions, &buf[..pos]);
+            push_copy(&mut instructions, index);
+            buf.drain(..pos + block_size);
+            pos = 0;
+            rolling = None;
+            continue;
+        }
+        if pos + block_size == buf.len() {
+            // End of the file, the window cannot slide any further
+            break;
+        }
+        let mut next = current;
+        next.roll(buf[pos], buf[pos + block_size], block_size);
+        rolling = Some(next);
+        pos += 1;
+        if pos == MAX_LITERAL_SIZE {
+            push_literal(&mut instructions, &buf[..pos]);
+            buf.drain(..pos);
+            pos = 0;
+        }
+    }
+    // Without signatures the loop stops before the end of the file
+    reader.read_to_end(&mut buf)?;
+    for literal in buf.chunks(MAX_LITERAL_SIZE) {
+        push_literal(&mut instructions, literal);
+    }
+    Ok(instructions)
+}
+
+///
+/// Writes the part of the new file described by the instructions
+/// # Arguments
+/// * `old` - The receiver's copy the signatures were made of
+/// * `block_size` - Block size the signatures were made with
+/// * `instructions` - Next instructions from the sender
+/// * `out` - The new file, written at its current position
+///
+/// # Returns
+/// * `u64` - Bytes written to the new file
+///
+pub fn apply(mut old: impl Read + Seek, block_size: usize, instructions: &[DeltaInstruction], mut out: impl Write) -> io::Result<u64> {
+    check_block_size(block_size)?;
+    let mut written = 0;
+    let mut block = vec![0; block_size];
+    for instruction in instructions {
+        match instruction {
+            DeltaInstruction::Copy { index, count } => {
+                let start = index
+                    .checked_mul(block_size as u64)
+                    .ok_or(io::Error::new(io::ErrorKind::InvalidInput, format!("Block {} is past any file", index)))?;
+                old.seek(SeekFrom::Start(start))?;
+                for _ in 0..*count {
+                    old.read_exact(&mut block)?;
+                    out.write_all(&block)?;
+                    written += block_size as u64;
+                }
+            }
+            DeltaInstruction::Literal { data } => {
+                out.write_all(data)?;
+                written += data.len() as u64;
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Bytes of literal data in the instructions, what the delta saves is the rest of the file
+pub fn literal_size(instructions: &[DeltaInstruction]) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match instruction {
+            DeltaInstruction::Literal { data } => data.len(),
+            DeltaInstruction::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+fn push_literal(instructions: &mut Vec<DeltaInstruction>, data: &[u8]) {
+    if !data.is_empty() {
+        instructions.push(DeltaInstruction::Literal { data: data.to_vec() });
+    }
+}
+
+/// Adds a block, extending the previous copy when the block follows it in the old copy
+fn push_copy(instructions: &mut Vec<DeltaInstruction>, index: u64) {
+    if let Some(DeltaInstruction::Copy { index: first, count }) = instructions.last_mut() {
+        if *first + *count == index {
+            *count += 1;
+            return;
+        }
+    }
+    instructions.push(DeltaInstruction::Copy { index, count: 1 });
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Bytes that do not repeat within a block, every block is found in one place only
+    fn data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn rebuild(old: &[u8], new: &[u8], block_size: usize) -> (Vec<u8>, usize) {
+        let signatures = signatures(Cursor::new(old), block_size, HashAlgorithm::Sha256).unwrap();
+        let instructions = delta(Cursor::new(new), block_size, &signatures, HashAlgorithm::Sha256).unwrap();
+        let mut out = vec![];
+        let written = apply(Cursor::new(old), block_size, &instructions, &mut out).unwrap();
+        assert_eq!(written as usize, out.len());
+        (out, literal_size(&instructions))
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = data(4096, 1);
+        let block_size = 1024;
+        let mut rolling = Rolling::new(&data[..block_size]);
+        for start in 1..data.len() - block_size {
+            rolling.roll(data[start - 1], data[start + block_size - 1], block_size);
+            assert_eq!(rolling.digest(), Rolling::new(&data[start..start + block_size]).digest(), "at {}", start);
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_copied() {
+        let old = data(10 * 1024 + 100, 2);
+        let (out, literal) = rebuild(&old, &old, 1024);
+        assert_eq!(out, old);
+        // Only the short last block
+        assert_eq!(literal, 100);
+    }
+
+    #[test]
+    fn insert_sends_only_the_new_bytes() {
+        let old = data(20 * 1024, 3);
+        let mut new = old.clone();
+        new.splice(5000..5000, b"inserted in the middle".iter().copied());
+        let (out, literal) = rebuild(&old, &new, 1024);
+        assert_eq!(out, new);
+        assert!(literal < 2 * 1024 + 100, "{} literal bytes", literal);
+    }
+
+    #[test]
+    fn unrelated_file_is_all_literal() {
+        let (old, new) = (data(8 * 1024, 4), data(9 * 1024, 5));
+        let (out, literal) = rebuild(&old, &new, 1024);
+        assert_eq!(out, new);
+        assert_eq!(literal, new.len());
+    }
+
+    #[test]
+    fn empty_old_copy() {
+        let new = data(3000, 6);
+        let (out, _) = rebuild(&[], &new, 1024);
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn block_sizes_from_peers_are_checked() {
+        assert!(check_block_size(block_size_for(0)).is_ok());
+        assert!(check_block_size(block_size_for(u64::MAX)).is_ok());
+        assert!(check_block_size(0).is_err());
+        assert!(check_block_size(MAX_BLOCK_SIZE + 1).is_err());
+        assert!(fits_block_count(block_size_for(5_000_000), 5_000_000 / block_size_for(5_000_000)));
+        assert!(fits_block_count(MIN_BLOCK_SIZE, 0));
+        assert!(!fits_block_count(MAX_BLOCK_SIZE, 1));
+        assert!(!fits_block_count(MIN_BLOCK_SIZE, 1_000_000));
+        assert!(signatures(Cursor::new(vec![1; 10]), 0, HashAlgorithm::Sha256).is_err());
+        let copy = [DeltaInstruction::Copy { index: u64::MAX, count: 1 }];
+        assert!(apply(Cursor::new(vec![0; 2048]), 1024, &copy, vec![]).is_err());
+        assert!(apply(Cursor::new(vec![0; 2048]), usize::MAX, &copy, vec![]).is_err());
+    }
+}
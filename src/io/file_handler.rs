@@ -1,46 +1,160 @@
 use std::io::SeekFrom;
 
-use crate::{Result};
+use crate::{
+    io::{
+        chunk::{self, Chunk},
+        delta::{self, BlockSignature, DeltaInstruction},
+        hash::HashAlgorithm,
+        ignore_rules::PARTIAL_SUFFIX,
+        metadata::{linked_parent, FileMetadata},
+        names,
+        versions::{VersioningConfig, Versions},
+    },
+    Result,
+};
 use async_std::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{prelude::SeekExt, ReadExt, WriteExt},
-    path::Path,
+    path::PathBuf,
+    task,
 };
 use log::{debug};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 pub struct FileHandler {
     root: String,
+    /// Keeps what the sync replaces or deletes
+    versions: Versions,
+    /// How files, chunks and delta blocks are hashed
+    hash: HashAlgorithm,
 }
 
 impl FileHandler {
-    pub fn new(root: String) -> Self {
-        FileHandler { root }
+    pub fn new(root: String, versioning: VersioningConfig, hash: HashAlgorithm) -> Self {
+        FileHandler { versions: Versions::new(&root, versioning), root, hash }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// The local file of a path in wire format, see `names::local_path`
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        PathBuf::from(names::local_path(std::path::Path::new(&self.root), file_name))
+    }
+
+    /// The local file of a path the sync writes, refused when a folder on the way is a link
+    fn write_path(&self, file_name: &str) -> Result<PathBuf> {
+        let path = names::local_path(std::path::Path::new(&self.root), file_name);
+        if let Some(link) = linked_parent(std::path::Path::new(&self.root), &path) {
+            Err(format!("Refusing to write {} through the link {}", file_name, link.display()))?;
+        }
+        Ok(PathBuf::from(path))
     }
 }
 
 impl FileHandler {
     ///
-    /// Create a file in the root folder
+    /// Create an empty file in the root folder, an existing file is replaced, the caller checked
+    /// that its content differs
     /// # Arguments
     /// * `root_folder` - The root folder where the file will be created
     /// * `file_name` - The relative path to the root folder and name of the file to be created
     ///
-    pub async fn create_file(&self, file_name: &String, sha: &String) -> Result<bool> {
-        let path = Path::new(&self.root).join(file_name);
+    pub async fn create_file(&self, file_name: &str) -> Result<()> {
+        let path = self.write_path(file_name)?;
         if path.exists().await {
-            let asyn_buf = async_std::path::PathBuf::from(path.clone());
-            let existing_sha = crate::io::sha(&asyn_buf).await;
-            if let Some(existing_sha) =  existing_sha {
-                if existing_sha.eq(sha) {
-                    debug!("File alaready exisits in with the same SHA {:?}",sha);
-                    return Ok(false)
-                }
-            }
+            self.versions.preserve(file_name).await?;
+            // The version may be a hard link to the file, truncating it would empty the version too
+            async_std::fs::remove_file(&path).await?;
+        }
+        if let Some(parent) = path.parent() {
+            async_std::fs::create_dir_all(parent).await?;
         }
         let new_file = File::create(path).await?;
         debug!("New file created {:?}",new_file);
-        Ok(true)
+        Ok(())
+    }
+
+    ///
+    ///    Returns the SHA of a file in the root folder, None if the file does not exist
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn file_sha(&self, file_name: &str) -> Option<String> {
+        let path = self.path(file_name);
+        if !path.is_file().await {
+            return None;
+        }
+        crate::io::sha(&path, self.hash).await
+    }
+
+    ///
+    ///    Returns the metadata of a file in the root folder, of the link itself for links, None if
+    ///    it does not exist
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn metadata(&self, file_name: &str) -> Option<async_std::fs::Metadata> {
+        let path = self.path(file_name);
+        async_std::fs::symlink_metadata(path).await.ok()
+    }
+
+    ///
+    ///    Returns the permissions, modification time and link target peers sync for a file
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn file_metadata(&self, file_name: &str) -> Option<FileMetadata> {
+        FileMetadata::read(&self.path(file_name)).await
+    }
+
+    ///
+    ///    Gives a file the permissions and modification time a peer announced
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `metadata` - The metadata of the version the file has now
+    ///
+    pub async fn set_metadata(&self, file_name: &str, metadata: &FileMetadata) -> Result<()> {
+        let path = self.write_path(file_name)?;
+        let is_link = async_std::fs::symlink_metadata(&path).await.is_ok_and(|local| local.is_symlink());
+        if is_link && metadata.symlink.is_none() {
+            // Applied to what the link points to otherwise
+            Err(format!("Refusing to set the metadata of {} through a link", file_name))?;
+        }
+        metadata.apply(&path).await
+    }
+
+    ///
+    ///    Creates a link in the root folder, replacing the file with the same name
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the link
+    ///    * `target` - What the link points to, already checked to stay inside the root
+    ///
+    pub async fn create_symlink(&self, file_name: &str, target: &str) -> Result<()> {
+        let path = self.write_path(file_name)?;
+        if let Ok(existing) = async_std::fs::symlink_metadata(&path).await {
+            if existing.is_dir() {
+                Err(format!("Cannot replace folder {} with a link", file_name))?;
+            }
+            self.versions.preserve(file_name).await?;
+            async_std::fs::remove_file(&path).await?;
+        }
+        if let Some(parent) = path.parent() {
+            async_std::fs::create_dir_all(parent).await?;
+        }
+        async_std::os::unix::fs::symlink(target, &path).await?;
+        debug!("Link {} to {} created", file_name, target);
+        Ok(())
     }
 
     ///
@@ -50,7 +164,7 @@ impl FileHandler {
     ///    * `folder_name` - The relative path to the root folder and name of the folder to be created
     ///
     pub async fn create_folder(&self, folder_name: String) -> Result<()> {
-        let path = Path::new(&self.root).join(folder_name);
+        let path = self.write_path(&folder_name)?;
         async_std::fs::create_dir_all(path).await?;
 
         Ok(())
@@ -63,12 +177,43 @@ impl FileHandler {
     ///    * `file_name` - The relative path to the root folder and name of the file to be deleted
     ///
     pub async fn delete_file(&self, file_name: String) -> Result<()> {
-        let path = Path::new(&self.root).join(file_name);
+        let path = self.write_path(&file_name)?;
+        self.versions.preserve(&file_name).await?;
         async_std::fs::remove_file(path).await?;
 
         Ok(())
     }
 
+    ///
+    ///    Deletes a file without keeping a version, its content stays on the peers. Folders left
+    ///    empty are removed too.
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn remove_local_copy(&self, file_name: &str) -> Result<()> {
+        let path = self.write_path(file_name)?;
+        async_std::fs::remove_file(&path).await?;
+        self.remove_empty_parents(file_name).await;
+        Ok(())
+    }
+
+    ///
+    ///    Removes the folders a deleted file leaves empty, up to the root folder
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the deleted file
+    ///
+    pub async fn remove_empty_parents(&self, file_name: &str) {
+        let root = PathBuf::from(&self.root);
+        let path = self.path(file_name);
+        let mut parent = path.parent();
+        while let Some(folder) = parent.filter(|folder| *folder != root) {
+            if async_std::fs::remove_dir(folder).await.is_err() {
+                break;
+            }
+            parent = folder.parent();
+        }
+    }
+
     ///
     ///    Delete a folder in the root folder
     ///    # Arguments
@@ -76,50 +221,287 @@ impl FileHandler {
     ///    * `folder_name` - The relative path to the root folder and name of the folder to be deleted
     ///    
     pub async fn delete_folder(&self, folder_name: String) -> Result<()> {
-        let path = Path::new(&self.root).join(folder_name);
+        let path = self.path(&folder_name);
         async_std::fs::remove_dir_all(path).await?;
 
         Ok(())
     }
 
     ///
-    ///        Writes a random chunk of data to a file
-    ///   # Arguments
-    ///        * `root_folder` - The root folder where the file will be written
-    ///        * `file_name` - The relative path to the root folder and name of the file to be written
-    ///        * `offset` - The offset from where to start writing
-    ///        * `buf` - The buffer where the data will be written
+    ///    Writes a random chunk of data to a file
+    ///    # Arguments
+    ///    * `root_folder` - The root folder where the file will be written
+    ///    * `file_name` - The relative path to the root folder and name of the file to be written
+    ///    * `offset` - The offset from where to start writing
+    ///    * `buf` - The buffer where the data will be written
     ///
     pub async fn write_random(&self, file_name: String, offset: u64, buf: &[u8]) -> Result<()> {
-        let path = Path::new(&self.root).join(file_name);
-        let mut file = File::open(path).await?;
+        let path = self.write_path(&file_name)?;
+        let mut file = OpenOptions::new().write(true).open(path).await?;
         file.seek(SeekFrom::Start(offset)).await?;
-        file.write(buf).await?;
+        file.write_all(buf).await?;
+        file.flush().await?;
         Ok(())
     }
 
     ///
-    ///        Reads a random chunk of data from a file and return true if reached EOF
-    ///        # Arguments
-    ///        * `root_folder` - The root folder where the file will be read
-    ///        * `file_name` - The relative path to the root folder and name of the file to be read
-    ///        * `offset` - The offset from where to start reading
-    ///        * `buf` - The buffer where the data will be read
+    ///    Reads a random chunk of data from a file and return the number of bytes read
+    ///    # Arguments
+    ///    * `root_folder` - The root folder where the file will be read
+    ///    * `file_name` - The relative path to the root folder and name of the file to be read
+    ///    * `offset` - The offset from where to start reading
+    ///    * `buf` - The buffer where the data will be read
     ///
-    ///        # Returns
-    ///        * `bool` - True if reached EOF
+    ///    # Returns
+    ///    * `usize` - Bytes read, 0 if reached EOF
     ///
     pub async fn read_random(
         &self,
-        file_name: &String,
+        file_name: &str,
         offset: u64,
         buf: &mut [u8],
-    ) -> Result<bool> {
-        let path = Path::new(&self.root).join(file_name);
+    ) -> Result<usize> {
+        let path = self.path(file_name);
         let mut file = File::open(path).await?;
         file.seek(SeekFrom::Start(offset)).await?;
         let read_data = file.read(buf).await?;
 
-        Ok(read_data > 0)
+        Ok(read_data)
+    }
+
+    ///
+    ///    Path a new version of a file is written to before it replaces the file
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub fn partial_path(&self, file_name: &str) -> PathBuf {
+        self.path(&format!("{}{}", file_name, PARTIAL_SUFFIX))
+    }
+
+    ///
+    ///    Signs every block of the current version of a file, for a peer to compute a delta against
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `block_size` - Size of every block
+    ///
+    pub async fn block_signatures(&self, file_name: &str, block_size: usize) -> Result<Vec<BlockSignature>> {
+        let path = names::local_path(std::path::Path::new(&self.root), file_name);
+        let hash = self.hash;
+        let signatures = task::spawn_blocking(move || delta::signatures(std::io::BufReader::new(std::fs::File::open(path)?), block_size, hash)).await?;
+        Ok(signatures)
+    }
+
+    ///
+    ///    Compares a file with the signatures of a peer's copy
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `block_size` - Block size the signatures were made with
+    ///    * `signatures` - Signatures of the peer's copy
+    ///
+    ///    # Returns
+    ///    * `Vec<DeltaInstruction>` - What the peer needs to rebuild this version from its copy
+    ///
+    pub async fn delta(&self, file_name: &str, block_size: usize, signatures: Vec<BlockSignature>) -> Result<Vec<DeltaInstruction>> {
+        let path = names::local_path(std::path::Path::new(&self.root), file_name);
+        let hash = self.hash;
+        let instructions = task::spawn_blocking(move || delta::delta(std::io::BufReader::new(std::fs::File::open(path)?), block_size, &signatures, hash)).await?;
+        Ok(instructions)
+    }
+
+    ///
+    ///    Appends the next part of a new version, rebuilt from the current file and the received
+    ///    literals, to the partial file
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `block_size` - Block size the signatures were made with
+    ///    * `instructions` - Next instructions received from the peer
+    ///    * `first` - Starts a new partial file instead of appending to it
+    ///
+    ///    # Returns
+    ///    * `u64` - Bytes written
+    ///
+    pub async fn apply_delta(&self, file_name: &str, block_size: usize, instructions: Vec<DeltaInstruction>, first: bool) -> Result<u64> {
+        let path = names::local_path(std::path::Path::new(&self.root), file_name);
+        self.write_path(file_name)?;
+        let partial_path: std::path::PathBuf = self.partial_path(file_name).into();
+        let written = task::spawn_blocking(move || {
+            use std::io::Write as _;
+            let old = std::fs::File::open(path)?;
+            let out = std::fs::OpenOptions::new().create(true).write(true).append(!first).truncate(first).open(partial_path)?;
+            let mut out = std::io::BufWriter::new(out);
+            let written = delta::apply(old, block_size, &instructions, &mut out)?;
+            out.flush()?;
+            std::io::Result::Ok(written)
+        })
+        .await?;
+        Ok(written)
+    }
+
+    ///
+    ///    Cuts a file into content defined chunks
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn manifest(&self, file_name: &str) -> Result<Vec<Chunk>> {
+        let path = names::local_path(std::path::Path::new(&self.root), file_name);
+        let hash = self.hash;
+        let chunks = task::spawn_blocking(move || chunk::chunks(std::io::BufReader::new(std::fs::File::open(path)?), hash)).await?;
+        Ok(chunks)
+    }
+
+    ///
+    ///    Creates an empty partial file of the final size, chunks are then written to it in any order
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `size` - Size of the new version
+    ///
+    pub async fn start_partial(&self, file_name: &str, size: u64) -> Result<()> {
+        self.write_path(file_name)?;
+        let partial_path = self.partial_path(file_name);
+        if let Some(parent) = partial_path.parent() {
+            async_std::fs::create_dir_all(parent).await?;
+        }
+        let partial = File::create(partial_path).await?;
+        partial.set_len(size).await?;
+        Ok(())
+    }
+
+    ///
+    ///    Copies a chunk of a local file into the partial file of another one
+    ///    # Arguments
+    ///    * `source_name` - The local file that had the chunk when it was indexed
+    ///    * `source_offset` - Where the chunk starts in the local file
+    ///    * `chunk` - The chunk of the new version
+    ///    * `file_name` - The file being built
+    ///
+    ///    # Returns
+    ///    * `bool` - False when the local file no longer holds the chunk, nothing was written
+    ///
+    pub async fn copy_chunk(&self, source_name: &str, source_offset: u64, chunk: &Chunk, file_name: &str) -> Result<bool> {
+        let mut data = vec![0; chunk.size as usize];
+        let read = self.read_random(source_name, source_offset, &mut data).await?;
+        if read < data.len() || self.hash.digest(&data) != chunk.hash {
+            return Ok(false);
+        }
+        self.write_partial(file_name, chunk.offset, &data).await?;
+        Ok(true)
+    }
+
+    ///
+    ///    Size of the partial file of a file, None if there is none
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn partial_size(&self, file_name: &str) -> Option<u64> {
+        async_std::fs::metadata(self.partial_path(file_name)).await.ok().map(|metadata| metadata.len())
+    }
+
+    ///
+    ///    Checks that a chunk written to the partial file of a file has the expected hash
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `chunk` - The chunk as listed in the manifest
+    ///
+    pub async fn verify_partial(&self, file_name: &str, chunk: &Chunk) -> Result<bool> {
+        let mut file = File::open(self.partial_path(file_name)).await?;
+        file.seek(SeekFrom::Start(chunk.offset)).await?;
+        let mut data = vec![0; chunk.size as usize];
+        file.read_exact(&mut data).await?;
+        Ok(self.hash.digest(&data) == chunk.hash)
+    }
+
+    ///
+    ///    Writes received data to the partial file of a file
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `offset` - The offset from where to start writing
+    ///    * `buf` - The data to write
+    ///
+    pub async fn write_partial(&self, file_name: &str, offset: u64, buf: &[u8]) -> Result<()> {
+        self.write_path(file_name)?;
+        let mut file = OpenOptions::new().write(true).open(self.partial_path(file_name)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(buf).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    ///
+    ///    Copies a local file that conflicts with the version of a peer next to it, the sync then
+    ///    replaces the file and the local changes stay in the copy
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `peer_id` - The peer whose version replaces the file
+    ///
+    ///    # Returns
+    ///    * `String` - The relative path of the copy
+    ///
+    pub async fn keep_conflict_copy(&self, file_name: &str, peer_id: &str) -> Result<String> {
+        let detected_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+        let copy_name = conflict_name(file_name, peer_id, detected_at);
+        async_std::fs::copy(self.path(file_name), self.write_path(&copy_name)?).await?;
+        Ok(copy_name)
+    }
+
+    ///
+    ///    Flushes the partial file of a file to the disk, nothing to do when there is none
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///
+    pub async fn sync_partial(&self, file_name: &str) -> Result<()> {
+        match File::open(self.partial_path(file_name)).await {
+            Ok(file) => Ok(file.sync_data().await?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    ///
+    ///    Replaces a file with its partial file when the partial file has the expected SHA
+    ///    # Arguments
+    ///    * `file_name` - The relative path to the root folder and name of the file
+    ///    * `sha` - SHA of the version the peer sent
+    ///
+    ///    # Returns
+    ///    * `bool` - False when the partial file did not match and was removed
+    ///
+    pub async fn finish_partial(&self, file_name: &str, sha: &str) -> Result<bool> {
+        let partial_path = self.partial_path(file_name);
+        let built_sha = crate::io::sha(&partial_path, self.hash).await;
+        if built_sha.as_deref() != Some(sha) {
+            async_std::fs::remove_file(&partial_path).await?;
+            return Ok(false);
+        }
+        let path = self.write_path(file_name)?;
+        self.versions.preserve(file_name).await?;
+        async_std::fs::rename(&partial_path, path).await?;
+        Ok(true)
+    }
+}
+
+/// `dir/report.conflict-node-b-1760000000.pdf` for `dir/report.pdf` replaced by the version of
+/// `node-b`, the extension stays so the copy still opens with the same application
+fn conflict_name(file_name: &str, peer_id: &str, detected_at: u64) -> String {
+    let peer: String = peer_id.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    let (parent, name) = match file_name.rsplit_once('/') {
+        Some((parent, name)) => (format!("{}/", parent), name),
+        None => (String::new(), file_name),
+    };
+    match name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+        Some((stem, extension)) => format!("{}{}.conflict-{}-{}.{}", parent, stem, peer, detected_at, extension),
+        None => format!("{}{}.conflict-{}-{}", parent, name, peer, detected_at),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflict_copies_keep_the_extension() {
+        assert_eq!(conflict_name("dir/report.pdf", "node-b", 7), "dir/report.conflict-node-b-7.pdf");
+        assert_eq!(conflict_name("notes", "node-b", 7), "notes.conflict-node-b-7");
+        assert_eq!(conflict_name("a/.profile", "node-b", 7), "a/.profile.conflict-node-b-7");
+        assert_eq!(conflict_name("x.tar.gz", "node/b c", 7), "x.tar.conflict-node_b_c-7.gz");
     }
 }
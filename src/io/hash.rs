@@ -0,0 +1,122 @@
+use std::{fmt, io::Read};
+
+use data_encoding::HEXUPPER;
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How the files and chunks of a folder are hashed, every peer of the folder has to use the same
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// The only algorithm of older nodes
+    #[default]
+    Sha256,
+    /// Several times faster than SHA-256 on large files
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// A hash being computed over data read in pieces, both states are large enough to box
+pub enum Hasher {
+    Sha256(Box<Context>),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(context) => context.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// The hash in upper case hex
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(context) => HEXUPPER.encode(context.finish().as_ref()),
+            Hasher::Blake3(hasher) => HEXUPPER.encode(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Box::new(Context::new(&SHA256))),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    /// The hash of data already in memory in upper case hex
+    pub fn digest(&self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    ///
+    /// Hashes everything a reader returns, blocks until it is read to the end
+    /// # Returns
+    /// * `Option<String>` - The hash in upper case hex, None when reading failed
+    ///
+    pub fn digest_reader(&self, mut reader: impl Read) -> Option<String> {
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0; HASH_BUFFER_SIZE];
+        loop {
+            let count = reader.read(&mut buffer).ok()?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+        }
+        Some(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_match_the_reference_values() {
+        assert_eq!(
+            HashAlgorithm::Sha256.digest(b"abc"),
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD"
+        );
+        assert_eq!(
+            HashAlgorithm::Blake3.digest(b"abc"),
+            "6437B3AC38465133FFB63B75273A8DB548C558465D79DB03FD359C6CD5BD9D85"
+        );
+    }
+
+    #[test]
+    fn reading_in_pieces_gives_the_same_digest() {
+        let data: Vec<u8> = (0..3 * HASH_BUFFER_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            assert_eq!(algorithm.digest_reader(data.as_slice()), Some(algorithm.digest(&data)));
+        }
+    }
+
+    #[test]
+    fn failed_reads_have_no_digest() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buffer: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("unreadable"))
+            }
+        }
+        assert_eq!(HashAlgorithm::Blake3.digest_reader(Failing), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    os::unix::fs::MetadataExt,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::io::index::modified_nanos;
+
+/// A file written this recently may be written again within the resolution of its modification
+/// time, with the same size, its hash is not cached
+const RACY_WINDOW: Duration = Duration::from_secs(1);
+
+/// What has to stay the same for a cached hash to still be the hash of the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashKey {
+    pub inode: u64,
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch
+    pub modified: u64,
+}
+
+impl HashKey {
+    pub fn of(metadata: &Metadata) -> Self {
+        HashKey {
+            inode: metadata.ino(),
+            size: metadata.len(),
+            modified: metadata.modified().map(modified_nanos).unwrap_or_default(),
+        }
+    }
+
+    /// False while the file may still change without its key changing
+    pub fn is_cacheable(&self) -> bool {
+        let now = modified_nanos(SystemTime::now());
+        now.saturating_sub(self.modified) >= RACY_WINDOW.as_nanos() as u64
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CachedHash {
+    size: u64,
+    modified: u64,
+    sha: String,
+    /// Where the file was last seen, empty in caches of older nodes
+    #[serde(default)]
+    path: String,
+}
+
+/// Hashes of the files of a folder by inode, a renamed file keeps its hash. Stored with the
+/// index.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HashCache {
+    hashes: HashMap<u64, CachedHash>,
+}
+
+impl HashCache {
+    pub fn get(&self, key: &HashKey) -> Option<&str> {
+        self.hashes
+            .get(&key.inode)
+            .filter(|cached| cached.size == key.size && cached.modified == key.modified)
+            .map(|cached| cached.sha.as_str())
+    }
+
+    /// Remembers a hash and the path of the file, true when the cache changed
+    pub fn insert(&mut self, key: HashKey, path: &str, sha: String) -> bool {
+        let cached = CachedHash { size: key.size, modified: key.modified, sha, path: String::from(path) };
+        self.hashes.insert(key.inode, cached.clone()) != Some(cached)
+    }
+
+    /// Follows a file that was renamed since it was hashed, true when the cache changed
+    pub fn moved(&mut self, key: &HashKey, path: &str) -> bool {
+        match self.hashes.get_mut(&key.inode) {
+            Some(cached) if cached.path != path => {
+                cached.path = String::from(path);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets the hashes of files whose path is not kept, true when any was removed
+    pub fn retain_paths(&mut self, keep: impl Fn(&str) -> bool) -> bool {
+        let count = self.hashes.len();
+        self.hashes.retain(|_, cached| keep(&cached.path));
+        self.hashes.len() != count
+    }
+
+    /// Forgets the hashes of files that no longer exist, true when any was removed
+    pub fn retain(&mut self, inodes: &HashSet<u64>) -> bool {
+        let count = self.hashes.len();
+        self.hashes.retain(|inode, _| inodes.contains(inode));
+        self.hashes.len() != count
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(inode: u64, size: u64, modified: u64) -> HashKey {
+        HashKey { inode, size, modified }
+    }
+
+    #[test]
+    fn hashes_are_found_while_the_key_is_the_same() {
+        let mut cache = HashCache::default();
+        assert!(cache.insert(key(1, 10, 100), "a.txt", String::from("AAA")));
+        assert!(!cache.insert(key(1, 10, 100), "a.txt", String::from("AAA")));
+        assert_eq!(cache.get(&key(1, 10, 100)), Some("AAA"));
+        assert_eq!(cache.get(&key(1, 11, 100)), None);
+        assert_eq!(cache.get(&key(1, 10, 101)), None);
+        assert_eq!(cache.get(&key(2, 10, 100)), None);
+        assert!(cache.insert(key(1, 11, 101), "a.txt", String::from("BBB")));
+        assert_eq!(cache.get(&key(1, 11, 101)), Some("BBB"));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn hashes_of_removed_files_are_forgotten() {
+        let mut cache = HashCache::default();
+        cache.insert(key(1, 10, 100), "a.txt", String::from("AAA"));
+        cache.insert(key(2, 10, 100), "b.txt", String::from("BBB"));
+        assert!(!cache.retain(&HashSet::from([1, 2])));
+        assert!(cache.retain(&HashSet::from([2])));
+        assert_eq!(cache.get(&key(1, 10, 100)), None);
+        assert_eq!(cache.get(&key(2, 10, 100)), Some("BBB"));
+    }
+
+    #[test]
+    fn renamed_files_keep_their_hash() {
+        let mut cache = HashCache::default();
+        cache.insert(key(1, 10, 100), "old.txt", String::from("AAA"));
+        assert!(cache.moved(&key(1, 10, 100), "new.txt"));
+        assert!(!cache.moved(&key(1, 10, 100), "new.txt"));
+        assert!(!cache.retain_paths(|path| path == "new.txt"));
+        assert!(cache.retain_paths(|path| path == "old.txt"));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn recently_written_files_are_not_cached() {
+        let now = modified_nanos(SystemTime::now());
+        assert!(!key(1, 10, now).is_cacheable());
+        let before = now - 2 * RACY_WINDOW.as_nanos() as u64;
+        assert!(key(1, 10, before).is_cacheable());
+    }
+}
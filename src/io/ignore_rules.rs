@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{info, warn};
+
+use crate::io::is_internal;
+
+/// Gitignore syntax file at the root of a shared folder, it is never synced itself
+pub const IGNORE_FILE: &str = ".peerignore";
+
+/// Suffix of files still being downloaded
+pub const PARTIAL_SUFFIX: &str = ".peerpart";
+
+/// Junk every OS and editor leaves behind, always ignored before the patterns of `IGNORE_FILE`
+const DEFAULT_PATTERNS: &[&str] = &[
+    IGNORE_FILE,
+    "*.peerpart",
+    ".DS_Store",
+    "._*",
+    ".Spotlight-V100",
+    ".Trashes",
+    ".fseventsd",
+    "Thumbs.db",
+    "ehthumbs.db",
+    "desktop.ini",
+    "$RECYCLE.BIN/",
+    "*.swp",
+    "*.swx",
+    "*~",
+    ".#*",
+    ".~lock.*#",
+];
+
+/// Paths of a shared folder that are neither announced to peers nor accepted from them
+#[derive(Debug)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    ///
+    /// Builds the rules of a folder from the defaults and its `.peerignore`, if there is one
+    /// # Arguments
+    /// * `root` - The root folder the rules belong to
+    ///
+    pub fn load(root: &str) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in DEFAULT_PATTERNS {
+            if let Err(err) = builder.add_line(None, pattern) {
+                warn!("Invalid default ignore pattern {} {}", pattern, err);
+            }
+        }
+        let ignore_file = Path::new(root).join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            info!("Loading ignore patterns from {:?}", ignore_file);
+            if let Some(err) = builder.add(&ignore_file) {
+                // Valid lines are still used, only the broken ones are skipped
+                warn!("Error in {:?} {}", ignore_file, err);
+            }
+        }
+        let matcher = builder.build().unwrap_or_else(|err| {
+            warn!("Cannot build ignore patterns for {} {}", root, err);
+            Gitignore::empty()
+        });
+        IgnoreRules { matcher }
+    }
+
+    ///
+    /// True when a path must not be synced, because it or one of its parent folders matches
+    /// # Arguments
+    /// * `relative_path` - Path relative to the root of the folder
+    /// * `is_dir` - Whether the path is a folder, patterns ending with `/` only match folders
+    ///
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        if is_internal(relative_path) {
+            return true;
+        }
+        self.matcher
+            .matched_path_or_any_parents(relative_path, is_dir)
+            .is_ignore()
+    }
+}
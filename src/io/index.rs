@@ -0,0 +1,250 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::{fs, path::Path};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    io::{chunk::Chunk, hash::HashAlgorithm, hash_cache::{HashCache, HashKey}, STATE_DIR},
+    Result,
+};
+
+const INDEX_FILE: &str = "index.json";
+
+/// Hashes cached beyond twice the number of indexed files plus this many are pruned to those of
+/// indexed files, the cache of files deleted between rescans stays bounded
+const UNINDEXED_HASHES: usize = 256;
+
+/// Whether the content of an indexed file is in the folder
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    #[default]
+    Local,
+    /// Known from a peer, not downloaded because its path is not selected
+    Unselected,
+    /// Known from a peer, an empty file stands in for it until it is fetched
+    Placeholder,
+}
+
+impl Presence {
+    pub fn is_local(&self) -> bool {
+        *self == Presence::Local
+    }
+}
+
+/// What the node last knew about a file, used to find changes the watcher missed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    /// Empty until the file is hashed again after the folder changed its hash algorithm
+    pub sha: String,
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch
+    pub modified: u64,
+    /// Permission bits, None for links and for files indexed before permissions were synced
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Content defined chunks of the file, what peers reuse when they build a file locally
+    #[serde(default)]
+    pub chunks: Vec<Chunk>,
+    #[serde(default, skip_serializing_if = "Presence::is_local")]
+    pub presence: Presence,
+    /// Target of a link that is not in the folder, to create it once it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+/// Every file of a shared folder keyed by its path relative to the root, stored in the state
+/// directory of the folder
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    /// Algorithm of every hash in the index, indexes of older nodes are SHA-256
+    #[serde(default)]
+    hash: HashAlgorithm,
+    files: HashMap<String, IndexEntry>,
+    #[serde(default)]
+    hashes: HashCache,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Index {
+    ///
+    /// Reads the index of a folder, an empty index if it was never saved or cannot be read
+    /// # Arguments
+    /// * `root` - The root folder the index belongs to
+    ///
+    pub async fn load(root: &str) -> Self {
+        let path = Path::new(root).join(STATE_DIR).join(INDEX_FILE);
+        let json = match fs::read_to_string(&path).await {
+            Ok(json) => json,
+            Err(err) => {
+                debug!("No index at {:?} {}", path, err);
+                return Index::default();
+            }
+        };
+        match serde_json::from_str(&json) {
+            Ok(index) => index,
+            Err(err) => {
+                warn!("Ignoring unreadable index {:?} {}", path, err);
+                Index::default()
+            }
+        }
+    }
+
+    ///
+    /// Writes the index if it changed since it was loaded or last saved
+    /// # Arguments
+    /// * `root` - The root folder the index belongs to
+    ///
+    pub async fn save(&mut self, root: &str) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let state_dir = Path::new(root).join(STATE_DIR);
+        fs::create_dir_all(&state_dir).await?;
+        let json = serde_json::to_string(self)?;
+        // Write next to the index and rename, a crash never leaves half an index behind
+        let temp_path = state_dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&temp_path, json).await?;
+        fs::rename(&temp_path, state_dir.join(INDEX_FILE)).await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    ///
+    /// Switches the index to the hash algorithm of the folder, hashes made with another one are
+    /// forgotten and the files hashed again by the next rescan
+    /// # Returns
+    /// * `bool` - True when the algorithm changed
+    ///
+    pub fn use_hash(&mut self, algorithm: HashAlgorithm) -> bool {
+        if self.hash == algorithm {
+            return false;
+        }
+        self.hash = algorithm;
+        self.hashes = HashCache::default();
+        for entry in self.files.values_mut() {
+            entry.sha.clear();
+            entry.chunks.clear();
+        }
+        self.dirty = true;
+        true
+    }
+
+    pub fn get(&self, file: &str) -> Option<&IndexEntry> {
+        self.files.get(file)
+    }
+
+    pub fn insert(&mut self, file: String, entry: IndexEntry) {
+        if self.files.get(&file) != Some(&entry) {
+            self.files.insert(file, entry);
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove(&mut self, file: &str) -> Option<IndexEntry> {
+        let removed = self.files.remove(file);
+        self.dirty |= removed.is_some();
+        removed
+    }
+
+    /// The hash of a file whose inode, size and modification time did not change since it was
+    /// hashed, even under another name
+    pub fn cached_sha(&mut self, key: &HashKey, file: &str) -> Option<String> {
+        let sha = self.hashes.get(key).map(String::from)?;
+        self.dirty |= self.hashes.moved(key, file);
+        Some(sha)
+    }
+
+    pub fn cache_sha(&mut self, key: HashKey, file: &str, sha: String) {
+        self.dirty |= self.hashes.insert(key, file, sha);
+        if self.hashes.len() > 2 * self.files.len() + UNINDEXED_HASHES {
+            let files = &self.files;
+            self.dirty |= self.hashes.retain_paths(|path| files.contains_key(path));
+        }
+    }
+
+    /// Keeps only the cached hashes of the given inodes
+    pub fn retain_hashes(&mut self, inodes: &HashSet<u64>) {
+        self.dirty |= self.hashes.retain(inodes);
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (&String, &IndexEntry)> {
+        self.files.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+pub fn modified_nanos(modified: SystemTime) -> u64 {
+    modified
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sha: &str) -> IndexEntry {
+        IndexEntry {
+            sha: String::from(sha),
+            size: 3,
+            modified: 1,
+            mode: Some(0o644),
+            chunks: vec![Chunk { offset: 0, size: 3, hash: String::from(sha) }],
+            presence: Presence::Local,
+            symlink: None,
+        }
+    }
+
+    #[test]
+    fn switching_the_hash_forgets_every_hash() {
+        let mut index = Index::default();
+        index.insert(String::from("a.txt"), entry("ABC"));
+        index.cache_sha(HashKey { inode: 7, size: 3, modified: 1 }, "a.txt", String::from("ABC"));
+        index.dirty = false;
+
+        assert!(index.use_hash(HashAlgorithm::Blake3));
+        assert!(index.dirty);
+        let kept = index.get("a.txt").unwrap();
+        assert!(kept.sha.is_empty());
+        assert!(kept.chunks.is_empty());
+        assert_eq!(kept.size, 3);
+        assert!(index.hashes.is_empty());
+    }
+
+    #[test]
+    fn keeping_the_hash_changes_nothing() {
+        let mut index = Index::default();
+        index.insert(String::from("a.txt"), entry("ABC"));
+        index.dirty = false;
+
+        assert!(!index.use_hash(HashAlgorithm::Sha256));
+        assert!(!index.dirty);
+        assert_eq!(index.get("a.txt"), Some(&entry("ABC")));
+    }
+
+    #[test]
+    fn hashes_of_files_no_longer_indexed_are_pruned() {
+        let mut index = Index::default();
+        index.insert(String::from("kept.txt"), entry("ABC"));
+        index.cache_sha(HashKey { inode: 0, size: 3, modified: 1 }, "kept.txt", String::from("ABC"));
+        for inode in 1..=(2 + UNINDEXED_HASHES as u64) {
+            index.cache_sha(HashKey { inode, size: 3, modified: 1 }, &format!("{}.txt", inode), String::from("ABC"));
+        }
+        assert_eq!(index.hashes.len(), 1);
+        assert_eq!(index.cached_sha(&HashKey { inode: 0, size: 3, modified: 1 }, "kept.txt").as_deref(), Some("ABC"));
+    }
+}
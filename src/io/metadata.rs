@@ -0,0 +1,208 @@
+use std::{
+    fs::{self, File, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path as StdPath, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use async_std::{path::Path, task};
+use serde::{Deserialize, Serialize};
+
+use crate::{io::index::modified_nanos, Result};
+
+/// Read, write and execute bits, setuid, setgid and sticky bits are never synced
+const PERMISSION_BITS: u32 = 0o777;
+
+/// What peers sync of a file besides its content
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    /// Unix permission bits, None for links and for peers that do not send them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Modification time in nanoseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// Target of a symbolic link, the file is synced as the link itself and not what it points to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+impl FileMetadata {
+    ///
+    /// Reads the metadata of a file without following it when it is a link
+    /// # Returns
+    /// * `Option<FileMetadata>` - None when the file does not exist or is a folder
+    ///
+    pub async fn read(path: &Path) -> Option<Self> {
+        let metadata = async_std::fs::symlink_metadata(path).await.ok()?;
+        if metadata.is_dir() {
+            return None;
+        }
+        let symlink = match metadata.is_symlink() {
+            true => Some(async_std::fs::read_link(path).await.ok()?.to_string_lossy().to_string()),
+            false => None,
+        };
+        Some(FileMetadata { mode: mode(&metadata), modified: metadata.modified().ok().map(modified_nanos), symlink })
+    }
+
+    ///
+    /// Gives a file the permissions and modification time of the version a peer announced,
+    /// links keep their own
+    /// # Arguments
+    /// * `path` - The file, its content is already the announced version
+    ///
+    pub async fn apply(&self, path: &Path) -> Result<()> {
+        if self.symlink.is_some() {
+            return Ok(());
+        }
+        let (path, metadata) = (path.to_path_buf(), self.clone());
+        task::spawn_blocking(move || {
+            // The time first, a file that becomes read only can still be opened
+            if let Some(modified) = metadata.modified {
+                File::open(&path)?.set_modified(UNIX_EPOCH + Duration::from_nanos(modified))?;
+            }
+            if let Some(mode) = metadata.mode {
+                fs::set_permissions(&path, Permissions::from_mode(mode & PERMISSION_BITS))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Permission bits of a file, None for links whose own permissions mean nothing
+pub fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    match metadata.is_symlink() {
+        true => None,
+        false => Some(metadata.permissions().mode() & PERMISSION_BITS),
+    }
+}
+
+///
+/// Checks that a link resolves inside the shared folder. Every name on the way is looked at, a
+/// link already on the way could lead anywhere whatever its name.
+/// # Arguments
+/// * `root` - The root folder
+/// * `link_path` - The link relative to the root of the folder
+/// * `target` - What the link points to, relative to the folder of the link
+///
+/// # Returns
+/// * `bool` - False for absolute targets, targets that leave the root with `..` and targets that
+///   go through an existing link
+///
+pub fn is_link_inside(root: &StdPath, link_path: &str, target: &str) -> bool {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return false,
+    };
+    let parent = StdPath::new(link_path).parent().unwrap_or(StdPath::new(""));
+    let mut resolved = root.clone();
+    for component in parent.components().chain(StdPath::new(target).components()) {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+                if resolved.symlink_metadata().is_ok_and(|metadata| metadata.is_symlink()) {
+                    return false;
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir if resolved != root => {
+                resolved.pop();
+            }
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    !target.is_empty()
+}
+
+///
+/// Finds a link among the folders between the root and a file, what is written to the file
+/// through it could land outside the root
+/// # Arguments
+/// * `root` - The root folder
+/// * `path` - A file below the root
+///
+/// # Returns
+/// * `Option<PathBuf>` - The first folder on the way that is a link
+///
+pub fn linked_parent(root: &StdPath, path: &StdPath) -> Option<PathBuf> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut folder = root.to_path_buf();
+    let names: Vec<Component> = relative.components().collect();
+    for name in names.iter().take(names.len().saturating_sub(1)) {
+        folder.push(name);
+        if folder.symlink_metadata().is_ok_and(|metadata| metadata.is_symlink()) {
+            return Some(folder);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn temp_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("decen-links-{}", Uuid::new_v4()));
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        root
+    }
+
+    #[test]
+    fn targets_inside_the_root() {
+        let root = temp_root();
+        assert!(is_link_inside(&root, "link", "sub/a.txt"));
+        assert!(is_link_inside(&root, "sub/link", "../a.txt"));
+        assert!(is_link_inside(&root, "sub/deeper/link", "./../../sub/a.txt"));
+        assert!(is_link_inside(&root, "sub/link", "missing/a.txt"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn targets_leaving_the_root() {
+        let root = temp_root();
+        assert!(!is_link_inside(&root, "link", ".."));
+        assert!(!is_link_inside(&root, "sub/link", "../../etc/passwd"));
+        assert!(!is_link_inside(&root, "link", "sub/../../a.txt"));
+        assert!(!is_link_inside(&root, "link", ""));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn absolute_targets() {
+        let root = temp_root();
+        assert!(!is_link_inside(&root, "link", "/etc/passwd"));
+        assert!(!is_link_inside(&root, "link", &root.join("sub").to_string_lossy()));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn chained_links() {
+        let root = temp_root();
+        // Inside on its own, points at the root
+        assert!(is_link_inside(&root, "sub/up", ".."));
+        symlink("..", root.join("sub/up")).unwrap();
+        // `sub/up/..` reads as `sub` but resolves above the root
+        assert!(!is_link_inside(&root, "x", "sub/up/.."));
+        assert!(!is_link_inside(&root, "x", "sub/up/a.txt"));
+        assert!(!is_link_inside(&root, "sub/up/x", "a.txt"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn writes_through_linked_folders() {
+        let root = temp_root();
+        symlink("/tmp", root.join("out")).unwrap();
+        symlink("a.txt", root.join("sub/file_link")).unwrap();
+        assert_eq!(linked_parent(&root, &root.join("out/a.txt")), Some(root.join("out")));
+        assert_eq!(linked_parent(&root, &root.join("out/deeper/a.txt")), Some(root.join("out")));
+        // The file itself being a link is up to the caller
+        assert_eq!(linked_parent(&root, &root.join("sub/file_link")), None);
+        assert_eq!(linked_parent(&root, &root.join("sub/deeper/a.txt")), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,33 +1,47 @@
-use async_std::{
-    fs::File,
-    io::{BufReader, ReadExt},
-    path::PathBuf,
-};
-use data_encoding::HEXUPPER;
-use ring::digest::{Context, Digest, SHA256};
+use async_std::{path::PathBuf, task};
+use std::time::Instant;
 
+use crate::{io::hash::HashAlgorithm, metrics::metrics};
+
+pub mod chunk;
+pub mod debounce;
+pub mod delta;
 pub mod file_handler;
+pub mod hash;
+pub mod hash_cache;
+pub mod ignore_rules;
+pub mod index;
+pub mod metadata;
+pub mod names;
+pub mod scan;
+pub mod selection;
+pub mod transfer_state;
+pub mod versions;
 pub mod watch;
 
-async fn sha256_digest(mut reader: BufReader<File>) -> Option<Digest> {
-    let mut context = Context::new(&SHA256);
-    let mut buffer = [0; 1024];
-
-    loop {
-        let count = reader.read(&mut buffer).await.unwrap();
-        if count == 0 {
-            break;
-        }
-        context.update(&buffer[..count]);
-    }
+/// Directory inside every shared folder where the node keeps its own state, never synced
+pub const STATE_DIR: &str = ".peer";
 
-    Some(context.finish())
+/// True when a path relative to the root belongs to the state or the versions directory
+pub fn is_internal(relative_path: &str) -> bool {
+    [STATE_DIR, versions::VERSIONS_DIR]
+        .iter()
+        .any(|dir| relative_path == *dir || relative_path.starts_with(&format!("{}/", dir)))
 }
 
-pub async fn sha(path: &PathBuf) -> Option<String> {
-    let input = File::open(path).await.unwrap();
-    let reader = BufReader::new(input);
-    let digest = sha256_digest(reader).await?;
-
-    std::option::Option::Some(HEXUPPER.encode(digest.as_ref()))
+/// Hashes a file on the blocking thread pool, reading a large file does not hold up the executor
+pub async fn sha(path: &PathBuf, algorithm: HashAlgorithm) -> Option<String> {
+    let path: std::path::PathBuf = path.clone().into();
+    task::spawn_blocking(move || {
+        let started = Instant::now();
+        // A link is synced as the link, its hash covers the target path and not what it points to
+        if path.symlink_metadata().ok()?.is_symlink() {
+            let target = std::fs::read_link(&path).ok()?;
+            return Some(algorithm.digest(target.to_string_lossy().as_bytes()));
+        }
+        let sha = algorithm.digest_reader(std::fs::File::open(&path).ok()?)?;
+        metrics().observe_hash(started.elapsed());
+        Some(sha)
+    })
+    .await
 }
@@ -0,0 +1,307 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::Result;
+
+/// Names Windows cannot create, with or without an extension
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const WINDOWS_FORBIDDEN_CHARS: [char; 9] = ['<', '>', ':', '"', '|', '?', '*', '\\', '/'];
+
+/// Which names the file system of a folder cannot tell apart or cannot create, the platform
+/// defaults can be overridden in the folder config, for FAT or SMB mounts on Linux
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NameRules {
+    /// Names differing only in case are the same file
+    #[serde(default = "native_case_insensitive")]
+    pub case_insensitive: bool,
+    /// Names Windows reserves or cannot store are refused
+    #[serde(default = "native_windows_names")]
+    pub windows_names: bool,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules { case_insensitive: native_case_insensitive(), windows_names: native_windows_names() }
+    }
+}
+
+fn native_case_insensitive() -> bool {
+    cfg!(any(windows, target_os = "macos"))
+}
+
+fn native_windows_names() -> bool {
+    cfg!(windows)
+}
+
+impl NameRules {
+    ///
+    /// Checks that every name of a path received from a peer can be created in the folder
+    /// # Arguments
+    /// * `wire_path` - A path already checked by `check_wire_path`
+    ///
+    pub fn check(&self, wire_path: &str) -> std::result::Result<(), String> {
+        if !self.windows_names {
+            return Ok(());
+        }
+        for name in wire_path.split('/') {
+            if let Some(reason) = windows_problem(name) {
+                return Err(format!("{} cannot be created, {} {}", wire_path, name, reason));
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Finds a local file the path would overwrite or merge with although its name is different,
+    /// a case-only difference on a case insensitive folder
+    /// # Arguments
+    /// * `root` - The root folder
+    /// * `wire_path` - The path a peer announced
+    ///
+    /// # Returns
+    /// * `Option<String>` - The colliding local path in wire format
+    ///
+    pub fn find_collision(&self, root: &Path, wire_path: &str) -> Option<String> {
+        if !self.case_insensitive {
+            return None;
+        }
+        let mut local = root.to_path_buf();
+        let mut wire = vec![];
+        for name in wire_path.split('/') {
+            let folded = fold_case(name);
+            let existing: Vec<String> = fs::read_dir(&local)
+                .ok()?
+                .flatten()
+                .filter_map(|entry| entry.file_name().to_str().map(String::from))
+                .filter(|entry| fold_case(entry) == folded)
+                .collect();
+            match existing.iter().find(|entry| entry.nfc().eq(name.chars())) {
+                Some(same) => {
+                    local.push(same);
+                    wire.push(String::from(name));
+                }
+                None => {
+                    // Nothing below a name that does not exist yet can collide
+                    let other = existing.first()?;
+                    wire.push(other.nfc().collect());
+                    return Some(wire.join("/"));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Why Windows cannot store a name, None when it can
+pub fn windows_problem(name: &str) -> Option<&'static str> {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        return Some("is a reserved name");
+    }
+    if name.chars().any(|char| WINDOWS_FORBIDDEN_CHARS.contains(&char) || char.is_control()) {
+        return Some("contains a character that is not allowed");
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Some("ends with a dot or a space");
+    }
+    None
+}
+
+/// A name in the form compared on case insensitive file systems
+pub fn fold_case(name: &str) -> String {
+    name.nfc().flat_map(char::to_lowercase).collect()
+}
+
+///
+/// Turns a path relative to the root into the form sent to peers, names joined with `/` and
+/// normalized to NFC
+/// # Returns
+/// * `Result<String>` - An error for names that are not valid UTF-8 and paths leaving the root
+///
+pub fn to_wire_path(relative_path: &Path) -> Result<String> {
+    let mut names = vec![];
+    for component in relative_path.components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str().ok_or(format!("{:?} is not valid UTF-8", relative_path))?;
+                names.push(name.nfc().collect::<String>());
+            }
+            Component::CurDir => {}
+            _ => Err(format!("{:?} is not relative to the folder", relative_path))?,
+        }
+    }
+    Ok(names.join("/"))
+}
+
+///
+/// Checks that a path received from a peer is in wire format and stays inside the folder
+/// # Returns
+/// * `Result<(), String>` - Why the path is refused
+///
+pub fn check_wire_path(wire_path: &str) -> std::result::Result<(), String> {
+    if wire_path.is_empty() || wire_path.starts_with('/') {
+        return Err(format!("{:?} is not a relative path", wire_path));
+    }
+    if wire_path.contains('\\') {
+        return Err(format!("{:?} uses \\ as separator", wire_path));
+    }
+    if wire_path.split('/').any(|name| name.is_empty() || name == "." || name == "..") {
+        return Err(format!("{:?} has an empty, . or .. name", wire_path));
+    }
+    if !is_nfc(wire_path) {
+        return Err(format!("{:?} is not normalized to NFC", wire_path));
+    }
+    Ok(())
+}
+
+///
+/// The local file a wire path names, an existing file whose name only differs in its Unicode
+/// normalization, as created on macOS, is used instead of creating a second one
+/// # Arguments
+/// * `root` - The root folder
+/// * `wire_path` - The path in wire format
+///
+pub fn local_path(root: &Path, wire_path: &str) -> PathBuf {
+    let direct = root.join(wire_path);
+    if direct.symlink_metadata().is_ok() {
+        return direct;
+    }
+    let mut path = root.to_path_buf();
+    for name in wire_path.split('/') {
+        let exact = path.join(name);
+        if exact.symlink_metadata().is_ok() {
+            path = exact;
+            continue;
+        }
+        let existing = fs::read_dir(&path).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name())
+                .find(|entry| entry.to_str().is_some_and(|entry| entry.nfc().eq(name.chars())))
+        });
+        path = match existing {
+            Some(existing) => path.join(existing),
+            None => exact,
+        };
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    const NFC: &str = "caf\u{e9}.txt";
+    const NFD: &str = "cafe\u{301}.txt";
+
+    fn temp_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("decen-names-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn wire_paths_use_forward_slashes_and_nfc() {
+        assert_eq!(to_wire_path(Path::new("docs/sub/a.txt")).unwrap(), "docs/sub/a.txt");
+        assert_eq!(to_wire_path(Path::new("./docs//a.txt")).unwrap(), "docs/a.txt");
+        assert_eq!(to_wire_path(&Path::new("docs").join(NFD)).unwrap(), format!("docs/{}", NFC));
+    }
+
+    #[test]
+    fn non_utf8_names_are_errors() {
+        let name = OsStr::from_bytes(b"bad\xff.txt");
+        assert!(to_wire_path(&Path::new("docs").join(name)).is_err());
+    }
+
+    #[test]
+    fn paths_leaving_the_root_are_errors() {
+        assert!(to_wire_path(Path::new("../a.txt")).is_err());
+        assert!(to_wire_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn received_paths_must_be_canonical() {
+        assert!(check_wire_path("docs/a.txt").is_ok());
+        assert!(check_wire_path(NFC).is_ok());
+        assert!(check_wire_path(NFD).is_err());
+        assert!(check_wire_path("").is_err());
+        assert!(check_wire_path("/etc/passwd").is_err());
+        assert!(check_wire_path("docs\\a.txt").is_err());
+        assert!(check_wire_path("docs//a.txt").is_err());
+        assert!(check_wire_path("docs/./a.txt").is_err());
+        assert!(check_wire_path("docs/../../a.txt").is_err());
+    }
+
+    #[test]
+    fn reserved_windows_names() {
+        assert!(windows_problem("con").is_some());
+        assert!(windows_problem("CON.txt").is_some());
+        assert!(windows_problem("lpt1.tar.gz").is_some());
+        assert!(windows_problem("aux .txt").is_some());
+        assert!(windows_problem("a:b.txt").is_some());
+        assert!(windows_problem("what?").is_some());
+        assert!(windows_problem("tab\there").is_some());
+        assert!(windows_problem("trailing.").is_some());
+        assert!(windows_problem("trailing ").is_some());
+        assert!(windows_problem("console.txt").is_none());
+        assert!(windows_problem("com10").is_none());
+        assert!(windows_problem(".hidden").is_none());
+    }
+
+    #[test]
+    fn rules_only_refuse_reserved_names_when_enabled() {
+        let windows = NameRules { case_insensitive: true, windows_names: true };
+        let linux = NameRules { case_insensitive: false, windows_names: false };
+        assert!(windows.check("docs/nul.txt").is_err());
+        assert!(windows.check("docs/null.txt").is_ok());
+        assert!(linux.check("docs/nul.txt").is_ok());
+    }
+
+    #[test]
+    fn case_folding_ignores_case_and_normalization() {
+        assert_eq!(fold_case("README.md"), fold_case("readme.MD"));
+        assert_eq!(fold_case(NFD), fold_case(&NFC.to_uppercase()));
+        assert_ne!(fold_case("a.txt"), fold_case("b.txt"));
+    }
+
+    #[test]
+    fn case_collisions_on_case_insensitive_folders() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("Docs")).unwrap();
+        fs::write(root.join("Docs/README.md"), "").unwrap();
+        let insensitive = NameRules { case_insensitive: true, windows_names: false };
+        let sensitive = NameRules { case_insensitive: false, windows_names: false };
+
+        assert_eq!(insensitive.find_collision(&root, "Docs/readme.md"), Some(String::from("Docs/README.md")));
+        assert_eq!(insensitive.find_collision(&root, "docs/new.md"), Some(String::from("Docs")));
+        assert_eq!(insensitive.find_collision(&root, "Docs/README.md"), None);
+        assert_eq!(insensitive.find_collision(&root, "Docs/other.md"), None);
+        assert_eq!(insensitive.find_collision(&root, "new/README.md"), None);
+        assert_eq!(sensitive.find_collision(&root, "Docs/readme.md"), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn local_paths_reuse_names_in_another_normalization() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs").join(NFD), "").unwrap();
+
+        assert_eq!(local_path(&root, &format!("docs/{}", NFC)), root.join("docs").join(NFD));
+        assert_eq!(local_path(&root, "docs/new.txt"), root.join("docs/new.txt"));
+        assert_eq!(local_path(&root, "new/a.txt"), root.join("new/a.txt"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    os::unix::fs::MetadataExt,
+};
+
+use async_std::{fs, path::PathBuf, prelude::*};
+
+use crate::{
+    io::{ignore_rules::IgnoreRules, index::modified_nanos, metadata::mode, names::{fold_case, to_wire_path, NameRules}},
+    Result,
+};
+
+#[derive(Debug)]
+pub struct ScannedFile {
+    /// Path relative to the root in wire format
+    pub relative_path: String,
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch
+    pub modified: u64,
+    pub mode: Option<u32>,
+    /// Key of the cached hash of the file
+    pub inode: u64,
+}
+
+/// The files of a folder, and the ones that cannot be synced with the reason
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub files: Vec<ScannedFile>,
+    pub problems: Vec<String>,
+}
+
+///
+/// Walks the whole folder and lists every regular file and link in it, ignored paths excluded
+/// # Arguments
+/// * `root` - The root folder to scan
+/// * `ignore_rules` - Ignored folders are not even entered
+/// * `name_rules` - Files whose names the peers could not tell apart are left out
+///
+/// # Returns
+/// * `Scan` - Every file with its path relative to the root
+///
+pub async fn scan_folder(root: &str, ignore_rules: &IgnoreRules, name_rules: &NameRules) -> Result<Scan> {
+    let root = PathBuf::from(root);
+    let mut scan = Scan::default();
+    let mut folders = vec![root.clone()];
+
+    while let Some(folder) = folders.pop() {
+        let mut entries = fs::read_dir(&folder).await?;
+        while let Some(entry) = entries.next().await {
+            let path = entry?.path();
+            let relative_path = match to_wire_path(path.strip_prefix(&root)?.as_ref()) {
+                Ok(relative_path) => relative_path,
+                Err(err) => {
+                    scan.problems.push(format!("Not syncing {}", err));
+                    continue;
+                }
+            };
+            let metadata = fs::symlink_metadata(&path).await?;
+            if ignore_rules.is_ignored(&relative_path, metadata.is_dir()) {
+                continue;
+            }
+            if metadata.is_dir() {
+                folders.push(path);
+            } else if metadata.is_file() || metadata.is_symlink() {
+                scan.files.push(ScannedFile {
+                    relative_path,
+                    size: metadata.len(),
+                    modified: modified_nanos(metadata.modified()?),
+                    mode: mode(&metadata),
+                    inode: metadata.ino(),
+                });
+            }
+        }
+    }
+
+    // Names in another Unicode normalization, or case on case insensitive folders, would be
+    // the same file on the peers
+    let key = |file: &ScannedFile| match name_rules.case_insensitive {
+        true => fold_case(&file.relative_path),
+        false => file.relative_path.clone(),
+    };
+    let mut by_key: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in &scan.files {
+        by_key.entry(key(file)).or_default().push(file.relative_path.clone());
+    }
+    let mut colliding = HashSet::new();
+    for (key, paths) in by_key.into_iter().filter(|(_, paths)| paths.len() > 1) {
+        scan.problems.push(format!("Not syncing {} files whose names collide as {}: {}", paths.len(), key, paths.join(", ")));
+        colliding.insert(key);
+    }
+    scan.files.retain(|file| !colliding.contains(&key(file)));
+    Ok(scan)
+}
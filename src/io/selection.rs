@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::io::names::{check_wire_path, to_wire_path};
+
+/// Which paths of a folder this node downloads, the rest is only known from the index. Every
+/// path selects itself and everything below it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    /// Only these paths are downloaded, every path when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Never downloaded, also below an included path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+impl Selection {
+    ///
+    /// Builds a selection from paths given by the user
+    /// # Arguments
+    /// * `include` - Paths relative to the root of the folder, every path when empty
+    /// * `exclude` - Paths relative to the root of the folder
+    ///
+    /// # Returns
+    /// * `Result<Selection, String>` - The paths in wire format, an error for paths outside the folder
+    ///
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> std::result::Result<Self, String> {
+        Ok(Selection { include: wire_paths(include)?, exclude: wire_paths(exclude)? })
+    }
+
+    /// True when a file is downloaded
+    pub fn is_selected(&self, file_path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|path| is_below(file_path, path)))
+            && !self.exclude.iter().any(|path| is_below(file_path, path))
+    }
+}
+
+fn wire_paths(paths: Vec<String>) -> std::result::Result<Vec<String>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            let wire_path = to_wire_path(Path::new(path)).map_err(|err| err.to_string())?;
+            check_wire_path(&wire_path)?;
+            Ok(wire_path)
+        })
+        .collect()
+}
+
+/// True when `file_path` is `path` or inside it
+pub fn is_below(file_path: &str, path: &str) -> bool {
+    file_path.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(include: &[&str], exclude: &[&str]) -> Selection {
+        let paths = |paths: &[&str]| paths.iter().map(|path| String::from(*path)).collect();
+        Selection::new(paths(include), paths(exclude)).unwrap()
+    }
+
+    #[test]
+    fn paths_select_themselves_and_what_is_below() {
+        assert!(is_below("a", "a"));
+        assert!(is_below("a/b.txt", "a"));
+        assert!(is_below("a/b/c.txt", "a/b"));
+        assert!(!is_below("ab", "a"));
+        assert!(!is_below("ab/c.txt", "a"));
+        assert!(!is_below("a", "a/b"));
+    }
+
+    #[test]
+    fn every_path_without_includes() {
+        let selection = Selection::default();
+        assert!(selection.is_selected("a.txt"));
+        assert!(selection.is_selected("docs/a.txt"));
+    }
+
+    #[test]
+    fn only_included_paths() {
+        let selection = select(&["docs", "photos/2024"], &[]);
+        assert!(selection.is_selected("docs/a.txt"));
+        assert!(selection.is_selected("photos/2024/b.jpg"));
+        assert!(!selection.is_selected("docs2/a.txt"));
+        assert!(!selection.is_selected("photos/2023/b.jpg"));
+        assert!(!selection.is_selected("a.txt"));
+    }
+
+    #[test]
+    fn excluded_inside_included() {
+        let selection = select(&["docs"], &["docs/drafts"]);
+        assert!(selection.is_selected("docs/a.txt"));
+        assert!(selection.is_selected("docs/drafts2/a.txt"));
+        assert!(!selection.is_selected("docs/drafts"));
+        assert!(!selection.is_selected("docs/drafts/a.txt"));
+        // Exclusion wins over inclusion
+        assert!(!select(&["docs/drafts"], &["docs"]).is_selected("docs/drafts/a.txt"));
+    }
+
+    #[test]
+    fn user_paths_are_wire_paths() {
+        assert_eq!(select(&["./docs//sub"], &[]).include, ["docs/sub"]);
+        assert!(Selection::new(vec![String::from("../outside")], vec![]).is_err());
+    }
+}
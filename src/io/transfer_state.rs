@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use async_std::{fs, path::Path};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    io::{chunk::Chunk, file_handler::FileHandler, STATE_DIR},
+    Result,
+};
+
+const TRANSFERS_FILE: &str = "transfers.json";
+
+/// A chunk download that continues from its partial file after a disconnect or a restart. Whole
+/// and delta downloads are not recorded, they start over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferState {
+    pub sha: String,
+    pub size: u64,
+    /// The peer the download started from, asked again first
+    pub peer_id: String,
+    pub chunks: Vec<Chunk>,
+    /// Offsets of the chunks already in the partial file with the right hash
+    pub verified: BTreeSet<u64>,
+}
+
+impl TransferState {
+    /// Bytes of the file already verified
+    pub fn verified_size(&self) -> u64 {
+        self.chunks.iter().filter(|chunk| self.verified.contains(&chunk.offset)).map(|chunk| chunk.size).sum()
+    }
+}
+
+/// Unfinished chunk downloads of a shared folder keyed by the path of the file, stored in the
+/// state directory of the folder next to the index
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TransferStates {
+    files: HashMap<String, TransferState>,
+    #[serde(skip)]
+    dirty: bool,
+    /// Files whose partial file got chunks since the last save, flushed before the save
+    #[serde(skip)]
+    unsynced: HashSet<String>,
+    #[serde(skip)]
+    saved_at: Option<Instant>,
+}
+
+impl TransferStates {
+    ///
+    /// Reads the unfinished downloads of a folder, none if they were never saved or cannot be read
+    /// # Arguments
+    /// * `root` - The root folder the downloads belong to
+    ///
+    pub async fn load(root: &str) -> Self {
+        let path = Path::new(root).join(STATE_DIR).join(TRANSFERS_FILE);
+        let json = match fs::read_to_string(&path).await {
+            Ok(json) => json,
+            Err(err) => {
+                debug!("No transfer state at {:?} {}", path, err);
+                return TransferStates::default();
+            }
+        };
+        match serde_json::from_str(&json) {
+            Ok(states) => states,
+            Err(err) => {
+                warn!("Ignoring unreadable transfer state {:?} {}", path, err);
+                TransferStates::default()
+            }
+        }
+    }
+
+    ///
+    /// Writes the unfinished downloads if they changed since they were loaded or last saved, after
+    /// their partial files so that a crash never leaves a chunk recorded but not on the disk
+    /// # Arguments
+    /// * `root` - The root folder the downloads belong to
+    /// * `file_handler` - The file handler of the folder, to flush the partial files
+    ///
+    pub async fn save(&mut self, root: &str, file_handler: &FileHandler) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        for file in &self.unsynced {
+            file_handler.sync_partial(file).await?;
+        }
+        self.unsynced.clear();
+        let state_dir = Path::new(root).join(STATE_DIR);
+        fs::create_dir_all(&state_dir).await?;
+        let json = serde_json::to_string(self)?;
+        let temp_path = state_dir.join(format!("{}.tmp", TRANSFERS_FILE));
+        fs::write(&temp_path, json).await?;
+        fs::rename(&temp_path, state_dir.join(TRANSFERS_FILE)).await?;
+        self.dirty = false;
+        self.saved_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Saves unless the last save is more recent than `interval`, chunks arrive too often to save
+    /// after every one
+    pub async fn save_every(&mut self, root: &str, file_handler: &FileHandler, interval: Duration) -> Result<()> {
+        match self.saved_at {
+            Some(saved_at) if saved_at.elapsed() < interval => Ok(()),
+            _ => self.save(root, file_handler).await,
+        }
+    }
+
+    pub fn get(&self, file: &str) -> Option<&TransferState> {
+        self.files.get(file)
+    }
+
+    pub fn insert(&mut self, file: String, state: TransferState) {
+        self.unsynced.insert(file.clone());
+        self.files.insert(file, state);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, file: &str) -> Option<TransferState> {
+        let removed = self.files.remove(file);
+        self.unsynced.remove(file);
+        self.dirty |= removed.is_some();
+        removed
+    }
+
+    /// Records that the chunk at `offset` of a file was written and verified
+    pub fn verified(&mut self, file: &str, offset: u64) {
+        if let Some(state) = self.files.get_mut(file) {
+            if state.verified.insert(offset) {
+                self.unsynced.insert(String::from(file));
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Forgets every unfinished download
+    pub fn clear(&mut self) {
+        self.dirty |= !self.files.is_empty();
+        self.files.clear();
+        self.unsynced.clear();
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (&String, &TransferState)> {
+        self.files.iter()
+    }
+}
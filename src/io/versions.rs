@@ -0,0 +1,366 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::task;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{io::ignore_rules::PARTIAL_SUFFIX, Result};
+
+/// Directory inside every shared folder where replaced and deleted files are kept, never synced
+pub const VERSIONS_DIR: &str = ".peerversions";
+
+const DEFAULT_KEEP: usize = 10;
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How many old versions of a file are kept and for how long, stored with the folder in the
+/// node config
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct VersioningConfig {
+    /// Versions kept of every file, the newest first, none at all when 0
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// Versions older than this are removed even when fewer than `keep` are left
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+}
+
+impl Default for VersioningConfig {
+    fn default() -> Self {
+        VersioningConfig { keep: DEFAULT_KEEP, max_age_days: None }
+    }
+}
+
+fn default_keep() -> usize {
+    DEFAULT_KEEP
+}
+
+/// A previous version of a file kept in the versions directory
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileVersion {
+    pub file_path: String,
+    /// Path of the version inside the versions directory, names it when restoring
+    pub version: String,
+    pub size: u64,
+    /// Milliseconds since the epoch when the file was replaced or deleted
+    pub saved_at: u64,
+}
+
+/// The versions directory of a shared folder
+#[derive(Debug)]
+pub struct Versions {
+    root: PathBuf,
+    config: VersioningConfig,
+}
+
+impl Versions {
+    pub fn new(root: &str, config: VersioningConfig) -> Self {
+        Versions { root: PathBuf::from(root), config }
+    }
+
+    ///
+    /// Keeps the current content of a file before the sync replaces or deletes it. The version
+    /// is a hard link where the file system allows it, the file itself stays in place.
+    /// # Arguments
+    /// * `file_name` - The relative path to the root folder and name of the file
+    ///
+    /// # Returns
+    /// * `Option<String>` - The new version, None when the file does not exist, is empty or
+    ///   versioning is off
+    ///
+    pub async fn preserve(&self, file_name: &str) -> Result<Option<String>> {
+        let (root, config, file_name) = (self.root.clone(), self.config, String::from(file_name));
+        task::spawn_blocking(move || preserve(&root, config, &file_name, now_millis())).await
+    }
+
+    ///
+    /// Lists the kept versions, newest first for every file
+    /// # Arguments
+    /// * `file_name` - Only the versions of this file, every file when None
+    ///
+    pub async fn list(&self, file_name: Option<&str>) -> Result<Vec<FileVersion>> {
+        let (root, file_name) = (self.root.clone(), file_name.map(String::from));
+        task::spawn_blocking(move || {
+            let versions = list(&root)?;
+            Ok(versions.into_iter().filter(|version| file_name.as_ref().is_none_or(|file_name| *file_name == version.file_path)).collect())
+        })
+        .await
+    }
+
+    ///
+    /// Puts a version back in place of its file, the content it replaces is kept as a version too
+    /// # Arguments
+    /// * `version` - Path of the version inside the versions directory
+    ///
+    /// # Returns
+    /// * `String` - The restored file relative to the root folder
+    ///
+    pub async fn restore(&self, version: &str) -> Result<String> {
+        let (root, config, version) = (self.root.clone(), self.config, String::from(version));
+        task::spawn_blocking(move || restore(&root, config, &version)).await
+    }
+
+    /// Removes the versions the retention policy no longer keeps, returns how many
+    pub async fn prune(&self) -> Result<usize> {
+        let (root, config) = (self.root.clone(), self.config);
+        task::spawn_blocking(move || prune(&root, config, None, now_millis())).await
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or_default()
+}
+
+/// `dir/report~1760000000000.pdf` for `dir/report.pdf`, the extension stays so the version
+/// still opens with the same application
+fn version_name(file_name: &str, saved_at: u64) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}~{}.{}", stem, saved_at, extension.to_string_lossy()),
+        None => format!("{}~{}", stem, saved_at),
+    };
+    match path.parent().map(|parent| parent.to_string_lossy().to_string()).filter(|parent| !parent.is_empty()) {
+        Some(parent) => format!("{}/{}", parent, name),
+        None => name,
+    }
+}
+
+/// The file a version belongs to and when it was saved, None for anything else in the directory
+fn parse_version(version: &str) -> Option<(String, u64)> {
+    let (parent, name) = match version.rsplit_once('/') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, version),
+    };
+    let (stem, rest) = name.rsplit_once('~')?;
+    let (saved_at, extension) = match rest.split_once('.') {
+        Some((saved_at, extension)) => (saved_at, Some(extension)),
+        None => (rest, None),
+    };
+    let saved_at = saved_at.parse().ok()?;
+    let name = match extension {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => String::from(stem),
+    };
+    match parent {
+        Some(parent) => Some((format!("{}/{}", parent, name), saved_at)),
+        None => Some((name, saved_at)),
+    }
+}
+
+/// Only plain relative paths, a version never points outside the versions directory
+fn is_relative_inside(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn preserve(root: &Path, config: VersioningConfig, file_name: &str, saved_at: u64) -> Result<Option<String>> {
+    let path = root.join(file_name);
+    // An empty file, a placeholder among them, leaves nothing worth restoring
+    if config.keep == 0 || !fs::metadata(&path).is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0) {
+        return Ok(None);
+    }
+    let versions_dir = root.join(VERSIONS_DIR);
+    // Two versions within the same millisecond get different names
+    let mut saved_at = saved_at;
+    while versions_dir.join(version_name(file_name, saved_at)).exists() {
+        saved_at += 1;
+    }
+    let version = version_name(file_name, saved_at);
+    let version_path = versions_dir.join(&version);
+    if let Some(parent) = version_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::hard_link(&path, &version_path).is_err() {
+        fs::copy(&path, &version_path)?;
+    }
+    debug!("Kept {} as {}", file_name, version);
+    prune(root, config, Some(file_name), saved_at)?;
+    Ok(Some(version))
+}
+
+fn list(root: &Path) -> Result<Vec<FileVersion>> {
+    let versions_dir = root.join(VERSIONS_DIR);
+    let mut versions = vec![];
+    let mut folders = vec![versions_dir.clone()];
+    while let Some(folder) = folders.pop() {
+        let entries = match fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.metadata()?.is_dir() {
+                folders.push(entry.path());
+                continue;
+            }
+            versions.extend(read_version(&versions_dir, &entry)?);
+        }
+    }
+    versions.sort_by(|a, b| a.file_path.cmp(&b.file_path).then(b.saved_at.cmp(&a.saved_at)));
+    Ok(versions)
+}
+
+/// The versions of one file newest first, only its own folder in the versions directory is read
+fn list_file(root: &Path, file_name: &str) -> Result<Vec<FileVersion>> {
+    let versions_dir = root.join(VERSIONS_DIR);
+    let folder = match Path::new(file_name).parent() {
+        Some(parent) => versions_dir.join(parent),
+        None => versions_dir.clone(),
+    };
+    let entries = match fs::read_dir(&folder) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut versions = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.metadata()?.is_file() {
+            versions.extend(read_version(&versions_dir, &entry)?.filter(|version| version.file_path == file_name));
+        }
+    }
+    versions.sort_by_key(|version| std::cmp::Reverse(version.saved_at));
+    Ok(versions)
+}
+
+/// The version a file in the versions directory is, None for anything else
+fn read_version(versions_dir: &Path, entry: &fs::DirEntry) -> Result<Option<FileVersion>> {
+    let version = entry.path().strip_prefix(versions_dir)?.to_string_lossy().replace('\\', "/");
+    let (file_path, saved_at) = match parse_version(&version) {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    Ok(Some(FileVersion { file_path, version, size: entry.metadata()?.len(), saved_at }))
+}
+
+fn restore(root: &Path, config: VersioningConfig, version: &str) -> Result<String> {
+    if !is_relative_inside(version) {
+        Err(format!("Invalid version {}", version))?;
+    }
+    let (file_path, _) = parse_version(version).ok_or(format!("{} is not a version", version))?;
+    let version_path = root.join(VERSIONS_DIR).join(version);
+    if !version_path.is_file() {
+        Err(format!("No version {}", version))?;
+    }
+    let path = root.join(&file_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Copied next to the file and renamed, the watcher only sees the complete file
+    let partial_path = root.join(format!("{}{}", file_path, PARTIAL_SUFFIX));
+    fs::copy(&version_path, &partial_path)?;
+    // Restoring overwrites the file like a remote change would, keeping the current content may
+    // prune the version being restored, it was copied already
+    preserve(root, config, &file_path, now_millis())?;
+    fs::rename(&partial_path, &path)?;
+    info!("Restored {} from {}", file_path, version);
+    Ok(file_path)
+}
+
+///
+/// Removes old versions beyond `keep` and older than `max_age_days`
+/// # Arguments
+/// * `file_name` - Only the versions of this file, every file when None
+/// * `now` - Milliseconds since the epoch the age is measured against
+///
+fn prune(root: &Path, config: VersioningConfig, file_name: Option<&str>, now: u64) -> Result<usize> {
+    let versions_dir = root.join(VERSIONS_DIR);
+    let versions = match file_name {
+        Some(file_name) => list_file(root, file_name)?,
+        None => list(root)?,
+    };
+    let mut by_file: HashMap<String, Vec<FileVersion>> = HashMap::new();
+    for version in versions {
+        by_file.entry(version.file_path.clone()).or_default().push(version);
+    }
+    let max_age = config.max_age_days.map(|days| days * MILLIS_PER_DAY);
+    let mut removed = 0;
+    for versions in by_file.values() {
+        // Newest first, as listed
+        for (position, version) in versions.iter().enumerate() {
+            let too_old = max_age.is_some_and(|max_age| now.saturating_sub(version.saved_at) > max_age);
+            if position < config.keep && !too_old {
+                continue;
+            }
+            let version_path = versions_dir.join(&version.version);
+            fs::remove_file(&version_path)?;
+            removed += 1;
+            remove_empty_parents(&versions_dir, &version_path);
+        }
+    }
+    if removed > 0 {
+        debug!("Removed {} old versions in {:?}", removed, root);
+    }
+    Ok(removed)
+}
+
+fn remove_empty_parents(versions_dir: &Path, path: &Path) {
+    let mut parent = path.parent();
+    while let Some(folder) = parent.filter(|folder| *folder != versions_dir) {
+        if fs::remove_dir(folder).is_err() {
+            break;
+        }
+        parent = folder.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("decen-versions-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        root
+    }
+
+    fn saved(root: &Path, file_name: &str) -> Vec<u64> {
+        list_file(root, file_name).unwrap().iter().map(|version| version.saved_at).collect()
+    }
+
+    #[test]
+    fn version_names_are_parsed_back() {
+        assert_eq!(version_name("dir/report.pdf", 7), "dir/report~7.pdf");
+        assert_eq!(version_name("notes", 7), "notes~7");
+        assert_eq!(version_name("x.tar.gz", 7), "x.tar~7.gz");
+        for file_name in ["dir/report.pdf", "notes", "x.tar.gz", "a/b/c~d.txt"] {
+            assert_eq!(parse_version(&version_name(file_name, 7)), Some((String::from(file_name), 7)));
+        }
+        assert_eq!(parse_version("dir/report.pdf"), None);
+        assert_eq!(parse_version("report~soon.pdf"), None);
+    }
+
+    #[test]
+    fn only_the_newest_versions_are_kept() {
+        let root = test_root();
+        let config = VersioningConfig { keep: 2, max_age_days: None };
+        for (saved_at, content) in [(1, "one"), (2, "two"), (3, "three")] {
+            fs::write(root.join("dir/a.txt"), content).unwrap();
+            preserve(&root, config, "dir/a.txt", saved_at).unwrap();
+        }
+        fs::write(root.join("b.txt"), "b").unwrap();
+        preserve(&root, config, "b.txt", 1).unwrap();
+        assert_eq!(saved(&root, "dir/a.txt"), vec![3, 2]);
+        assert_eq!(saved(&root, "b.txt"), vec![1]);
+        assert_eq!(fs::read_to_string(root.join(VERSIONS_DIR).join("dir/a~3.txt")).unwrap(), "three");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn old_versions_are_pruned() {
+        let root = test_root();
+        let config = VersioningConfig { keep: 10, max_age_days: Some(1) };
+        for saved_at in [MILLIS_PER_DAY, 2 * MILLIS_PER_DAY] {
+            fs::write(root.join("dir/a.txt"), "a").unwrap();
+            preserve(&root, config, "dir/a.txt", saved_at).unwrap();
+        }
+        assert_eq!(prune(&root, config, None, 3 * MILLIS_PER_DAY).unwrap(), 1);
+        assert_eq!(saved(&root, "dir/a.txt"), vec![2 * MILLIS_PER_DAY]);
+        assert_eq!(prune(&root, config, None, 5 * MILLIS_PER_DAY).unwrap(), 1);
+        assert!(!root.join(VERSIONS_DIR).join("dir").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
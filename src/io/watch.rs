@@ -1,127 +1,231 @@
-use std::path::Path;
+use std::{os::unix::fs::MetadataExt, path::Path, time::{Duration, Instant}};
 
 use log::{debug, error, info, warn};
-use notify::{Config, Error, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config, Error, Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
 use uuid::Uuid;
 
-use crate::{io::sha, InternalMessage, Result, Sender, broker::InternalToExternal};
+use std::sync::Arc;
+
+use crate::{folder::SharedFolder, io::{debounce::Debouncer, ignore_rules::{IgnoreRules, IGNORE_FILE}, names::{local_path, to_wire_path}}, metrics::metrics, shutdown::ShutdownListener, InternalMessage, Result, Sender, broker::InternalToExternal};
 use futures::{
     channel::mpsc::{channel, Receiver},
-    SinkExt, StreamExt,
+    select, FutureExt, SinkExt, StreamExt,
 };
 
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    /// How long a path must see no events, and keep the same size and mtime, before it is synced
+    pub quiet_period: Duration,
+    /// Poll the folder at this interval instead of using OS notifications, for network filesystems
+    pub poll_interval: Option<Duration>,
+    /// Compare the whole folder with the index at this interval
+    pub rescan_interval: Option<Duration>,
+}
 
-
-
-pub async fn async_watch(path: &Path, mut sender: Sender<InternalMessage>) -> Result<()> {
-    let (mut watcher, mut rx) = async_watcher()?;
+///
+/// Watches one shared folder and sends its changes to the broker, tagged with the folder id
+/// # Arguments
+/// * `folder` - The folder to watch, its ignore rules are reloaded when `.peerignore` changes
+/// * `shutdown` - Stops the watcher, on shutdown or when the folder is no longer shared
+///
+pub async fn async_watch(
+    folder: Arc<SharedFolder>,
+    config: WatchConfig,
+    mut sender: Sender<InternalMessage>,
+    shutdown: ShutdownListener,
+) -> Result<()> {
+    let (mut watcher, rx) = async_watcher(&config)?;
+    let path = Path::new(folder.root());
+    let folder_id = folder.id();
 
     watcher.watch(path, RecursiveMode::Recursive)?;
 
-    while let Some(res) = rx.next().await {
-        match res {
-            Ok(event) => handle_event(event, &mut sender, path).await?,
-            Err(e) => handle_error(e).await,
+    let mut debouncer = Debouncer::new(config.quiet_period);
+    let mut next_tick = Instant::now() + debouncer.tick_interval();
+    // Changes made while the node was not running are only found by a rescan
+    let mut rescan_requested = true;
+    let mut next_rescan = config.rescan_interval.map(|interval| Instant::now() + interval);
+    let mut rx = rx.fuse();
+    let mut shutdown = shutdown.fuse();
+    loop {
+        let tick = async_std::task::sleep(next_tick.saturating_duration_since(Instant::now())).fuse();
+        futures::pin_mut!(tick);
+        select! {
+            res = rx.next() => match res {
+                Some(Ok(event)) => {
+                    metrics().watcher_event();
+                    if event.need_rescan() {
+                        warn!("Watcher lost events for {:?}, rescanning", event.paths);
+                        rescan_requested = true;
+                    }
+                    if let Some(event) = debouncer.push(event).await {
+                        if let Err(err) = handle_event(event, &mut sender, &folder).await {
+                            warn!("{}, rescanning", err);
+                            rescan_requested = true;
+                        }
+                    }
+                },
+                Some(Err(e)) => {
+                    handle_error(e).await;
+                    rescan_requested = true;
+                },
+                None => break,
+            },
+            _ = tick => {
+                let now = Instant::now();
+                next_tick = now + debouncer.tick_interval();
+                for event in debouncer.ready(now).await {
+                    // The file may be unreadable or deleted meanwhile, the rescan finds what is left
+                    if let Err(err) = handle_event(event, &mut sender, &folder).await {
+                        warn!("{}, rescanning", err);
+                        rescan_requested = true;
+                    }
+                }
+                if next_rescan.is_some_and(|next_rescan| now >= next_rescan) {
+                    rescan_requested = true;
+                    next_rescan = config.rescan_interval.map(|interval| now + interval);
+                }
+                if rescan_requested {
+                    rescan_requested = false;
+                    sender.send(InternalMessage::Rescan { id: Uuid::new_v4(), folder_id: String::from(folder_id) }).await?;
+                }
+            },
+            _ = shutdown => {
+                info!("Stop watching {:?}", path);
+                break;
+            },
         }
     }
+    drop(watcher);
 
     Ok(())
 }
 
-fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
+type BoxedWatcher = Box<dyn Watcher + Send>;
+
+fn async_watcher(config: &WatchConfig) -> notify::Result<(BoxedWatcher, Receiver<notify::Result<Event>>)> {
     let (mut tx, rx) = channel(1);
 
-    let watcher = RecommendedWatcher::new(
-        move |res| {
-            futures::executor::block_on(async {
-                tx.send(res).await.unwrap();
-            })
-        },
-        Config::default(),
-    )?;
+    let event_handler = move |res| {
+        futures::executor::block_on(async {
+            // Fails once the folder stopped being watched, the event is no longer needed
+            let _ = tx.send(res).await;
+        })
+    };
+    let watcher: BoxedWatcher = match config.poll_interval {
+        Some(poll_interval) => {
+            info!("Polling for changes every {:?}", poll_interval);
+            Box::new(PollWatcher::new(event_handler, Config::default().with_poll_interval(poll_interval))?)
+        }
+        None => Box::new(RecommendedWatcher::new(event_handler, Config::default())?),
+    };
 
     Ok((watcher, rx))
 }
 
-async fn handle_event(
-    event: Event,
-    sender: &mut Sender<InternalMessage>,
-    absolute_root: &Path,
-) -> Result<()> {
+async fn handle_event(event: Event, sender: &mut Sender<InternalMessage>, folder: &SharedFolder) -> Result<()> {
+    let (folder_id, absolute_root, ignore_rules) = (folder.id(), Path::new(folder.root()), &folder.ignore_rules);
     let event_id = Uuid::new_v4();
     debug!("{:?} :: Event : {:?}", event_id, event);
-    debug!(
-        "{:?} :: Create event for file {:?} kind :: {:?}",
-        event_id, event.paths, event.kind
-    );
-    let path = event.paths.get(0).unwrap();
-    if !path.exists() {
-        debug!("{:?} File {:?} already deleted ", event_id, path);
+    let path = match event.paths.first() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let relative_path = match to_wire_path(get_relative_path(absolute_root, path)) {
+        Ok(relative_path) => relative_path,
+        Err(err) => {
+            // Reported as a sync error by the next rescan
+            warn!("{:?} Not syncing {}", event_id, err);
+            return Ok(());
+        }
+    };
+    if relative_path.is_empty() {
+        return Ok(());
+    }
+    if is_other_file(&local_path(absolute_root, &relative_path), path) {
+        // Another file has the same name in another Unicode normalization
+        warn!("{:?} Not syncing {:?}, its name collides with another file as {}", event_id, path, relative_path);
+        return Ok(());
+    }
+    if relative_path == IGNORE_FILE && !matches!(event.kind, notify::EventKind::Access(_)) {
+        info!("{:?} changed, reloading ignore patterns", path);
+        *ignore_rules.write().await = IgnoreRules::load(&absolute_root.to_string_lossy());
+        // Paths that are no longer ignored are picked up by the next rescan
+        sender.send(InternalMessage::Rescan { id: event_id, folder_id: String::from(folder_id) }).await?;
+        return Ok(());
+    }
+    // Links are synced as links, never followed
+    let metadata = path.symlink_metadata().ok();
+    if ignore_rules.read().await.is_ignored(&relative_path, metadata.as_ref().is_some_and(|metadata| metadata.is_dir())) {
+        debug!("{:?} Ignoring {:?}", event_id, path);
         return Ok(());
     }
-    let asyn_buf = async_std::path::PathBuf::from(path);
-    let sha = sha(&asyn_buf).await.unwrap();
 
-    match event.kind {
-        notify::EventKind::Create(kind) => match kind {
-            notify::event::CreateKind::File => {
-                let path = event.paths.get(0).unwrap();
+    if let notify::EventKind::Remove(kind) = event.kind {
+        if metadata.is_some() {
+            debug!("{:?} {:?} was removed and created again", event_id, path);
+            return Ok(());
+        }
+        let message = match kind {
+            notify::event::RemoveKind::Folder => InternalToExternal::FolderDeleted { id: event_id, file: relative_path, sha: String::new() },
+            _ => InternalToExternal::FileDeleted { id: event_id, folder: relative_path, sha: String::new() },
+        };
+        sender.send(InternalMessage::InternalToExternal { folder_id: String::from(folder_id), message }).await?;
+        return Ok(());
+    }
 
-                let relative_path = get_relative_path(absolute_root, path).to_str().unwrap();
-                
-                let message = InternalToExternal::FileCreated {
-                    id: event_id,
-                    file: String::from(relative_path),
-                    sha,
-                };
-                sender.send(InternalMessage::InternalToExternal { message }).await?;
-            }
-            notify::event::CreateKind::Folder => {
-                let relative_path = get_relative_path(absolute_root, event.paths.get(0).unwrap())
-                    .to_str()
-                    .unwrap();
-                let message = InternalToExternal::FolderCreated {
-                    id: event_id,
-                    folder: String::from(relative_path),
-                    sha,
-                };
-                sender.send(InternalMessage::InternalToExternal { message }).await?;
-            }
-            notify::event::CreateKind::Other => todo!(),
-            notify::event::CreateKind::Any => todo!(),
-        },
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => {
+            debug!("{:?} File {:?} already deleted ", event_id, path);
+            return Ok(());
+        }
+    };
+    if metadata.is_dir() {
+        if let notify::EventKind::Create(_) = event.kind {
+            let message = InternalToExternal::FolderCreated {
+                id: event_id,
+                folder: relative_path,
+                sha: String::new(),
+            };
+            sender.send(InternalMessage::InternalToExternal { folder_id: String::from(folder_id), message }).await?;
+        }
+        return Ok(());
+    }
+
+    match event.kind {
+        notify::EventKind::Create(_) => {
+            let sha = folder.file_sha(&relative_path).await.ok_or(format!("Cannot hash {:?}", path))?;
+            let message = InternalToExternal::FileCreated {
+                id: event_id,
+                file: relative_path,
+                sha,
+                size: metadata.len(),
+            };
+            sender.send(InternalMessage::InternalToExternal { folder_id: String::from(folder_id), message }).await?;
+        }
         notify::EventKind::Modify(kind) => match kind {
-            notify::event::ModifyKind::Data(_data) => {
-                let relative_path = get_relative_path(absolute_root, event.paths.get(0).unwrap())
-                    .to_str()
-                    .unwrap();
+            // The polling watcher only reports Any, it compares modification times
+            notify::event::ModifyKind::Data(_) | notify::event::ModifyKind::Any | notify::event::ModifyKind::Other => {
+                let sha = folder.file_sha(&relative_path).await.ok_or(format!("Cannot hash {:?}", path))?;
                 let message = InternalToExternal::FileModified {
                     id: event_id,
-                    file: String::from(relative_path),
+                    file: relative_path,
                     sha,
+                    size: metadata.len(),
                 };
-                sender.send(InternalMessage::InternalToExternal { message }).await?;
+                sender.send(InternalMessage::InternalToExternal { folder_id: String::from(folder_id), message }).await?;
             }
             notify::event::ModifyKind::Name(_name) => {
-                let _path = event.paths.get(0).unwrap();
-                let _relative_path = get_relative_path(absolute_root, event.paths.get(0).unwrap())
-                    .to_str()
-                    .unwrap();
+                debug!("TODO ignore rename for now {:?}", path);
             }
-            notify::event::ModifyKind::Other | notify::event::ModifyKind::Any => {
-                debug!("TODO ignore update for now {:?}",path);
-            },
-            notify::event::ModifyKind::Metadata(_metadata) => {
-                debug!("TODO ignore update for now {:?}",path);
+            // Permissions or times, the broker compares them with the index
+            notify::event::ModifyKind::Metadata(_) => {
+                let message = InternalToExternal::MetadataModified { id: event_id, file: relative_path };
+                sender.send(InternalMessage::InternalToExternal { folder_id: String::from(folder_id), message }).await?;
             },
         },
-        notify::EventKind::Remove(kind) => match kind {
-            notify::event::RemoveKind::File => {}
-            notify::event::RemoveKind::Folder => todo!(),
-            notify::event::RemoveKind::Any | notify::event::RemoveKind::Other => {
-                info!("Remove event for file {:?} kind :: {:?}", event.paths, kind);
-            }
-        },
+        notify::EventKind::Remove(_) => {}
         notify::EventKind::Other => {
             warn!("Other event {:?}", event);
         }
@@ -129,14 +233,22 @@ async fn handle_event(
             warn!("Unknown event {:?}", event);
         }
         notify::EventKind::Access(kind) => {
-            info!("Access event for file {:?} kind :: {:?}", event.paths, kind);
+            debug!("Access event for file {:?} kind :: {:?}", event.paths, kind);
         }
     }
     Ok(())
 }
 
+/// True when both paths exist and are different files
+fn is_other_file(path: &Path, other: &Path) -> bool {
+    match (path.symlink_metadata(), other.symlink_metadata()) {
+        (Ok(metadata), Ok(other)) => (metadata.dev(), metadata.ino()) != (other.dev(), other.ino()),
+        _ => false,
+    }
+}
+
 fn get_relative_path<'a>(root: &Path, path: &'a Path) -> &'a Path {
-    path.strip_prefix(root).unwrap()
+    path.strip_prefix(root).unwrap_or(path)
 }
 
 async fn handle_error(error: Error) {
@@ -1,14 +1,19 @@
 pub mod cmd;
+pub mod config;
+pub mod control;
 pub mod core;
+pub mod folder;
 pub mod io;
+pub mod limit;
+pub mod metrics;
 pub mod peer;
 pub mod rendezvous;
 pub mod server;
 pub mod broker;
+pub mod shutdown;
 
-use async_std::{prelude::*, task};
+use async_std::task;
 use broker::{InternalMessage, ExternalToInternal};
-use futures::{channel::mpsc, SinkExt};
 use log::{debug, info, warn};
 use std::{
     future::Future,
@@ -16,10 +21,10 @@ use std::{
 
 
 
-use crate::peer::{Command, Event, PeerMessage};
+use crate::{folder::Folders, peer::{compression::decompress_message, Command, Event, PeerMessage}};
 
-pub type Sender<T> = mpsc::UnboundedSender<T>;
-pub type Receiver<T> = mpsc::UnboundedReceiver<T>;
+pub type Sender<T> = async_std::channel::Sender<T>;
+pub type Receiver<T> = async_std::channel::Receiver<T>;
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 
@@ -38,26 +43,50 @@ where
 
 #[derive(Debug)]
 pub struct PeerMessageHandler {
+    folders: Folders,
 }
 
 impl PeerMessageHandler {
     
-    pub fn new() -> Self {
-        PeerMessageHandler {}
+    pub fn new(folders: Folders) -> Self {
+        PeerMessageHandler { folders }
     }
 
 }
 
 impl PeerMessageHandler {
     
-    pub async fn handle_peer_message(&self, line: String, broker: &mut Sender<InternalMessage>) -> Result<()> {
+    ///
+    /// Parses a line a peer sent and hands it to the broker
+    /// # Arguments
+    /// * `line` - The message as received
+    /// * `peer_id` - Who the peer said it was when the connection was set up, messages claiming
+    ///   to come from anyone else are dropped
+    /// * `broker` - Sender to the broker
+    ///
+    pub async fn handle_peer_message(&self, line: String, peer_id: &str, broker: &mut Sender<InternalMessage>) -> Result<()> {
         let message: PeerMessage = serde_json::from_str(&line)?;
-        let _result = match message {
+        let message = match message {
+            PeerMessage::Compressed { compression, data } => {
+                let line = task::spawn_blocking(move || decompress_message(compression, &data)).await?;
+                serde_json::from_str(&line)?
+            }
+            message => message,
+        };
+        let sender = match &message {
+            PeerMessage::PeerCommand { command } => Some(command.sender()),
+            PeerMessage::PeerEvent { event } => Some(event.sender()),
+            PeerMessage::Compressed { .. } => None,
+        };
+        if let Some(sender) = sender.filter(|sender| *sender != peer_id) {
+            warn!("Dropping a message of {} that claims to come from {}", peer_id, sender);
+            return Ok(());
+        }
+        match message {
             PeerMessage::PeerCommand { command } => self.handle_command(command, broker).await,
             PeerMessage::PeerEvent { event } => self.handle_event(event, broker).await,
-        };
-    
-        Ok(())
+            PeerMessage::Compressed { .. } => Err("Compressed message inside a compressed message")?,
+        }
     }
 
     async fn handle_command(&self,command: Command, broker: &mut Sender<InternalMessage>) -> Result<()> {
@@ -66,6 +95,7 @@ impl PeerMessageHandler {
                 id,
                 client_id,
                 port: _,
+                compression: _,
             } => {
                 //this should never happen, in this place
                 warn!("Peer {} Connect command, id {} ", client_id, id);
@@ -75,20 +105,92 @@ impl PeerMessageHandler {
                     "Received Peer leave command id::{} client::{}",
                     id, client_id
                 );
-                broker
-                    .send(InternalMessage::LeavePeer {
-                        id,
-                        peer_id: client_id,
-                    })
-                    .await
-                    .unwrap();
+                let leave = InternalMessage::LeavePeer { id, peer_id: client_id.clone() };
+                if broker.send(leave).await.is_err() {
+                    debug!("Not reporting that {} left, the broker stopped", client_id);
+                }
+            },
+            Command::ModifyFile { id, peer_id, folder_id, file_path, sha, size, metadata } => {
+                info!(
+                    "id :: {} Recevied ModifyFile command for {} file in {} from {}",
+                    id, file_path, folder_id, peer_id
+                );
+                // Also sent for send only folders, the broker reports the file as drift
+                let message = ExternalToInternal::FileModify { id, peer_id, file_path, sha, size, metadata };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::ModifyMetadata { id, peer_id, folder_id, file_path, sha, metadata } => {
+                debug!(
+                    "id :: {} Recevied ModifyMetadata command for {} file in {} from {}",
+                    id, file_path, folder_id, peer_id
+                );
+                if !self.receives(&folder_id).await {
+                    debug!("Ignoring metadata of {} in send only folder {}", file_path, folder_id);
+                    return Ok(());
+                }
+                let message = ExternalToInternal::MetadataModify { id, peer_id, file_path, sha, metadata };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::DeleteFile { id, peer_id, folder_id, file_path, sha } => {
+                info!(
+                    "id :: {} Recevied DeleteFile command for {} file in {} from {}",
+                    id, file_path, folder_id, peer_id
+                );
+                if !self.receives(&folder_id).await {
+                    debug!("Ignoring the deletion of {} in send only folder {}", file_path, folder_id);
+                    return Ok(());
+                }
+                let message = ExternalToInternal::FileDelete { id, peer_id, file_path, sha };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::SignatureCommand { id, peer_id, folder_id, file_path, block_size, signatures } => {
+                debug!(
+                    "id :: {} Recevied {} block signatures for {} file in {} from {}",
+                    id, signatures.len(), file_path, folder_id, peer_id
+                );
+                let message = ExternalToInternal::Signatures { id, peer_id, file_path, block_size, signatures };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
             },
-            Command::ModifyFile { id: _, peer_id: _, file_path: _ } => {
-                debug!("Not Implemented yet");
+            Command::DeltaCommand { id, peer_id, folder_id, file_path, sha, block_size, instructions, last } => {
+                debug!(
+                    "id :: {} Recevied {} delta instructions for {} file in {} from {}",
+                    id, instructions.len(), file_path, folder_id, peer_id
+                );
+                if !self.receives(&folder_id).await {
+                    warn!("Refusing delta for {} in send only folder {} from {}", file_path, folder_id, peer_id);
+                    return Ok(());
+                }
+                let message = ExternalToInternal::Delta { id, peer_id, file_path, sha, block_size, instructions, last };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::ManifestRequestCommand { id, peer_id, folder_id, file_path } => {
+                let message = ExternalToInternal::ManifestRequest { id, peer_id, file_path };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::ManifestCommand { id, peer_id, folder_id, file_path, sha, size, chunks } => {
+                debug!(
+                    "id :: {} Recevied {} chunks of {} file in {} from {}",
+                    id, chunks.len(), file_path, folder_id, peer_id
+                );
+                if !self.receives(&folder_id).await {
+                    warn!("Refusing manifest of {} in send only folder {} from {}", file_path, folder_id, peer_id);
+                    return Ok(());
+                }
+                let message = ExternalToInternal::Manifest { id, peer_id, file_path, sha, size, chunks };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::ChunkRequestCommand { id, peer_id, folder_id, file_path, chunks } => {
+                let message = ExternalToInternal::ChunkRequest { id, peer_id, file_path, chunks };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
             },
-            Command::DataRequestCommand { id, peer_id, file_path } => {
+            Command::DataRequestCommand { id, peer_id, folder_id, file_path } => {
                 let message = ExternalToInternal::DataRequest { id, peer_id, file_path };
-                broker.send(InternalMessage::ExternalToInternal { message }).await.unwrap();
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
+            },
+            Command::ContentUnavailable { id, peer_id, folder_id, file_path } => {
+                debug!("id :: {} {} only has a placeholder of {} in {}", id, peer_id, file_path, folder_id);
+                let message = ExternalToInternal::ContentUnavailable { id, peer_id, file_path };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
             },
             Command::Test {
                 id,
@@ -100,18 +202,28 @@ impl PeerMessageHandler {
                     peer_id, message, id
                 );
             }
+            Command::HashAlgorithms { id, peer_id, hashes } => {
+                debug!("id :: {} Received hash algorithms {:?} from {}", id, hashes, peer_id);
+                if broker.send(InternalMessage::PeerHashes { peer_id: peer_id.clone(), hashes }).await.is_err() {
+                    debug!("Not passing on the hash algorithms of {}, the broker stopped", peer_id);
+                }
+            }
             Command::CreateNewFile {
                 id,
                 file_path,
                 peer_id,
+                folder_id,
                 sha,
+                size,
+                metadata,
             } => {
                 info!(
-                    "id :: {} Recevied CreateNewFile command for {} file from {}",
-                    id, file_path, peer_id
+                    "id :: {} Recevied CreateNewFile command for {} file in {} from {}",
+                    id, file_path, folder_id, peer_id
                 );
-                let message = ExternalToInternal::NewFileCreate { id, peer_id, file_path, sha };
-                broker.send(InternalMessage::ExternalToInternal { message }).await.unwrap();
+                // Also sent for send only folders, the broker reports the file as drift
+                let message = ExternalToInternal::NewFileCreate { id, peer_id, file_path, sha, size, metadata };
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
                 
                 
             }
@@ -119,32 +231,53 @@ impl PeerMessageHandler {
                 id,
                 folder_path,
                 peer_id,
+                folder_id,
             } => {
                 info!(
-                    "id :: {} Recevied CreateFolder command for {} file from {}",
-                    id, folder_path, peer_id
+                    "id :: {} Recevied CreateFolder command for {} in {} from {}",
+                    id, folder_path, folder_id, peer_id
                 );
+                if !self.receives(&folder_id).await {
+                    debug!("Ignoring new folder {} in send only folder {}", folder_path, folder_id);
+                }
             }
-            Command::WriteDataCommand { id, peer_id, file_path, offset, data } => {
+            Command::WriteDataCommand { id, peer_id, folder_id, file_path, offset, data } => {
                 debug!(
-                    "id :: {} Recevied Write Data command for {} file from {}",
-                    id, file_path, peer_id
+                    "id :: {} Recevied Write Data command for {} file in {} from {}",
+                    id, file_path, folder_id, peer_id
                 );
+                // Data is never requested for a send only folder, do not let a peer push it anyway
+                if !self.receives(&folder_id).await {
+                    warn!("Refusing data for {} in send only folder {} from {}", file_path, folder_id, peer_id);
+                    return Ok(());
+                }
                 let message = ExternalToInternal::DataWrite { id, peer_id, file_path, offset, data };
-                broker.send(InternalMessage::ExternalToInternal { message }).await.unwrap();
+                to_broker(broker, InternalMessage::ExternalToInternal { folder_id, message }).await?;
             },
         }
         Ok(())
     }
 
-    async fn handle_event(&self, event: Event, _broker: &mut Sender<InternalMessage>) -> Result<()> {
+    /// False for send only folders, unknown folders are left to the broker to report
+    async fn receives(&self, folder_id: &str) -> bool {
+        match self.folders.read().await.get(folder_id) {
+            Some(folder) => folder.mode().receives(),
+            None => true,
+        }
+    }
+
+    async fn handle_event(&self, event: Event, broker: &mut Sender<InternalMessage>) -> Result<()> {
         match event {
             Event::Connected {
-                id: _,
-                client_id: _,
+                id,
+                client_id,
                 port: _,
-            } => todo!(),
-            Event::Left { id: _, client_id: _ } => todo!(),
+                compression,
+            } => {
+                debug!("Peer {} accepted the connection id {} with compression {:?}", client_id, id, compression);
+                to_broker(broker, InternalMessage::PeerCompression { peer_id: client_id, compression }).await?;
+            }
+            Event::Left { id, client_id } => warn!("Unexpected Left event id {} from {}", id, client_id),
         }
     
         Ok(())
@@ -152,13 +285,19 @@ impl PeerMessageHandler {
 
 }
 
+/// Passes a message of a peer to the broker, fails once the broker stopped so the connection ends
+async fn to_broker(broker: &mut Sender<InternalMessage>, message: InternalMessage) -> Result<()> {
+    if broker.send(message).await.is_err() {
+        debug!("Not passing on a peer message, the broker stopped");
+        Err("The broker stopped")?
+    }
+    Ok(())
+}
+
 pub fn get_available_port() -> Option<u16> {
     (8000..9000).find(|port| port_is_available(*port))
 }
 
 fn port_is_available(port: u16) -> bool {
-    match std::net::TcpListener::bind(("127.0.0.1", port)) {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    std::net::TcpListener::bind(("127.0.0.1", port)).is_ok()
 }
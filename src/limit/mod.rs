@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use serde::{Deserialize, Serialize};
+
+use crate::folder::ANY_PEER;
+
+static LIMITER: LazyLock<Limiter> = LazyLock::new(Limiter::default);
+
+/// Upload and download limits in bytes per second, None for unlimited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rates {
+    #[serde(default)]
+    pub upload: Option<u64>,
+    #[serde(default)]
+    pub download: Option<u64>,
+}
+
+impl Rates {
+    /// These rates with the limits that were given replaced, 0 lifts a limit
+    pub fn changed(self, upload: Option<u64>, download: Option<u64>) -> Rates {
+        let change = |rate: Option<u64>, new: Option<u64>| match new {
+            Some(0) => None,
+            Some(new) => Some(new),
+            None => rate,
+        };
+        Rates { upload: change(self.upload, upload), download: change(self.download, download) }
+    }
+}
+
+/// Bandwidth the node may use for sync traffic, stored in the node config
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BandwidthLimits {
+    /// Shared by every peer
+    #[serde(default)]
+    pub global: Rates,
+    /// Limits of single peers by peer id, `*` for every peer without its own entry
+    #[serde(default)]
+    pub peers: HashMap<String, Rates>,
+    /// Peers on a private or local network are not limited
+    #[serde(default)]
+    pub lan_unlimited: bool,
+}
+
+impl BandwidthLimits {
+    /// The limits of a peer, its own entry or the `*` entry
+    pub fn peer(&self, peer_id: &str) -> Rates {
+        self.peers.get(peer_id).or_else(|| self.peers.get(ANY_PEER)).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Refills `rate` tokens per second up to one second worth of them. Taking more tokens than
+/// there are leaves a debt the caller waits off, so a message larger than the bucket still goes
+/// out at the configured rate.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        TokenBucket { rate, tokens: rate as f64, updated: Instant::now() }
+    }
+
+    /// Like `take`, starting over with a full bucket when the limit was changed
+    fn take_at(&mut self, rate: u64, bytes: u64) -> Duration {
+        if self.rate != rate.max(1) {
+            *self = TokenBucket::new(rate);
+        }
+        self.take(bytes)
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait before sending them
+    fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64);
+        self.updated = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    global: Option<TokenBucket>,
+    peers: HashMap<String, TokenBucket>,
+}
+
+impl Buckets {
+    fn take(&mut self, global: Option<u64>, peer: Option<u64>, peer_id: &str, bytes: u64) -> Duration {
+        let global_wait = match global {
+            Some(rate) => self.global.get_or_insert_with(|| TokenBucket::new(rate)).take_at(rate, bytes),
+            None => Duration::ZERO,
+        };
+        let peer_wait = match peer {
+            Some(rate) => self.peers.entry(String::from(peer_id)).or_insert_with(|| TokenBucket::new(rate)).take_at(rate, bytes),
+            None => Duration::ZERO,
+        };
+        global_wait.max(peer_wait)
+    }
+}
+
+/// Process wide token buckets for the traffic to and from peers
+#[derive(Debug, Default)]
+pub struct Limiter {
+    limits: Mutex<BandwidthLimits>,
+    upload: Mutex<Buckets>,
+    download: Mutex<Buckets>,
+}
+
+pub fn limiter() -> &'static Limiter {
+    &LIMITER
+}
+
+impl Limiter {
+    pub fn limits(&self) -> BandwidthLimits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    pub fn set_limits(&self, limits: BandwidthLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    ///
+    /// Waits until `bytes` may be sent to or were read from a peer without going over the global
+    /// limit or the limit of the peer
+    /// # Arguments
+    /// * `peer_id` - The other end of the connection
+    /// * `address` - Address of the peer, None when unknown
+    /// * `bytes` - Size of the message
+    ///
+    pub async fn acquire(&self, direction: Direction, peer_id: &str, address: Option<IpAddr>, bytes: u64) {
+        let wait = self.reserve(direction, peer_id, address, bytes);
+        if !wait.is_zero() {
+            task::sleep(wait).await;
+        }
+    }
+
+    fn reserve(&self, direction: Direction, peer_id: &str, address: Option<IpAddr>, bytes: u64) -> Duration {
+        let limits = self.limits.lock().unwrap();
+        if limits.lan_unlimited && address.is_some_and(is_lan) {
+            return Duration::ZERO;
+        }
+        let peer = limits.peer(peer_id);
+        match direction {
+            Direction::Upload => self.upload.lock().unwrap().take(limits.global.upload, peer.upload, peer_id, bytes),
+            Direction::Download => self.download.lock().unwrap().take(limits.global.download, peer.download, peer_id, bytes),
+        }
+    }
+}
+
+/// Loopback, private and link local addresses
+pub fn is_lan(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_loopback() || address.is_private() || address.is_link_local(),
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => is_lan(IpAddr::V4(address)),
+            // Unique local fc00::/7 and link local fe80::/10
+            None => address.is_loopback() || (address.segments()[0] & 0xfe00) == 0xfc00 || (address.segments()[0] & 0xffc0) == 0xfe80,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn close_to(wait: Duration, millis: u64) -> bool {
+        wait <= Duration::from_millis(millis) && wait + Duration::from_millis(20) > Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn full_bucket_sends_a_second_of_data() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(close_to(bucket.take(500), 500));
+        // The debt is waited off before anything else goes out
+        assert!(close_to(bucket.take(500), 1000));
+    }
+
+    #[test]
+    fn messages_larger_than_the_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        assert!(close_to(bucket.take(3000), 2000));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_a_second() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.take(1000);
+        bucket.updated -= Duration::from_millis(500);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        bucket.updated -= Duration::from_secs(10);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(close_to(bucket.take(100), 100));
+    }
+
+    #[test]
+    fn changed_rate_starts_a_full_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.take(5000);
+        assert_eq!(bucket.take_at(2000, 2000), Duration::ZERO);
+        assert!(close_to(bucket.take_at(2000, 1000), 500));
+        // No rate of 0, it would never refill
+        assert!(close_to(TokenBucket::new(0).take(2), 1000));
+    }
+
+    #[test]
+    fn given_limits_change() {
+        let rates = Rates { upload: Some(10), download: Some(20) };
+        assert_eq!(rates.changed(Some(30), None), Rates { upload: Some(30), download: Some(20) });
+        assert_eq!(rates.changed(None, Some(0)), Rates { upload: Some(10), download: None });
+        assert_eq!(rates.changed(None, None), rates);
+    }
+
+    #[test]
+    fn lan_addresses() {
+        for lan in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.0.1", "::1", "fd00::1", "fe80::1", "::ffff:192.168.1.1"] {
+            assert!(is_lan(lan.parse().unwrap()), "{}", lan);
+        }
+        for wan in ["8.8.8.8", "172.32.0.1", "100.64.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_lan(wan.parse().unwrap()), "{}", wan);
+        }
+        assert!(is_lan(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!is_lan(IpAddr::V6(Ipv6Addr::UNSPECIFIED)));
+    }
+}
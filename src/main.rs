@@ -1,66 +1,131 @@
 use std::{
     env,
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH}, rc::Rc,
+    process,
+    time::Duration, sync::Arc,
 };
 
-use async_std::task;
+use async_std::{sync::RwLock, task};
 use clap::Parser;
 use decen_peer::{
-    broker::Broker, cmd::CmdArgs, get_available_port, io::{watch::async_watch, file_handler::FileHandler},
+    broker::Broker, cmd::{CmdArgs, CmdCommand}, config::NodeConfig, control::{self, ControlServer}, folder::{FolderMode, Folders, SharedFolder}, get_available_port, limit::limiter, metrics, io::{hash::HashAlgorithm, watch::WatchConfig},
     rendezvous::Server, server::PeerServer, peer::client::ClientConnectionHandler, PeerMessageHandler,
+    shutdown::{shutdown_channel, wait_for_signal},
 };
-use futures::channel::mpsc;
+use log::{info, warn};
 
 
 fn main() {
-    let _args: Vec<String> = env::args().collect();
-    log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
     let cmds = CmdArgs::parse_from(env::args_os());
-    let (broker_sender, broker_receiver) = mpsc::unbounded();
+    match cmds.command {
+        CmdCommand::Run { ref config, ref folder, ref metrics_addr, quiet_period_ms, poll_interval_secs, rescan_interval_secs } => {
+            let watch_config = WatchConfig {
+                quiet_period: Duration::from_millis(quiet_period_ms),
+                poll_interval: poll_interval_secs.map(Duration::from_secs),
+                rescan_interval: Some(rescan_interval_secs).filter(|secs| *secs > 0).map(Duration::from_secs),
+            };
+            let node_config = match task::block_on(node_config(config, folder)) {
+                Ok(node_config) => node_config,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            run(node_config, config.clone(), cmds.control_socket.clone(), metrics_addr.clone(), watch_config)
+        }
+        ref command => {
+            let control_command = command.control_command().unwrap();
+            match task::block_on(control::call(&cmds.control_socket, &control_command)) {
+                Ok(result) if cmds.json => println!("{}", result),
+                Ok(result) => println!("{}", command.render(&result)),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
 
-    let available_port = match get_available_port() {
-        Some(available_port) => available_port,
-        None => 9000,
-    };
+///
+/// Loads the node config and adds the folders given on the command line that it does not share yet
+/// # Arguments
+/// * `config_path` - The JSON file of the node config
+/// * `folders` - Paths given with `--folder`
+///
+async fn node_config(config_path: &str, folders: &[String]) -> decen_peer::Result<NodeConfig> {
+    let mut config = NodeConfig::load(config_path).await?;
+    let mut changed = false;
+    for folder in folders {
+        let path = async_std::fs::canonicalize(folder).await?;
+        if config.folder(&path.to_string_lossy()).is_none() {
+            config.add_folder(folder, None, vec![], FolderMode::default(), HashAlgorithm::default(), false).await?;
+            changed = true;
+        }
+    }
+    if changed {
+        config.save(config_path).await?;
+    }
+    Ok(config)
+}
+
+fn run(node_config: NodeConfig, config_path: String, control_socket: String, metrics_addr: Option<String>, watch_config: WatchConfig) {
+    log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
+    let (broker_sender, broker_receiver) = async_std::channel::unbounded();
+    let (shutdown_trigger, shutdown_listener) = shutdown_channel();
 
-    let folder = cmds.folder.clone();
-    let path = Path::new(folder.as_str());
+    let available_port = get_available_port().unwrap_or(9000);
 
-    let peer_message_hander = Rc::new(PeerMessageHandler::new());
+    if node_config.folders.is_empty() {
+        warn!("No shared folders, add one with `folders add <path>`");
+    }
+    limiter().set_limits(node_config.limits.clone());
+    let folders = task::block_on(futures::future::join_all(node_config.folders.iter().cloned().map(SharedFolder::open)));
+    let folders: Folders = Arc::new(RwLock::new(
+        folders.into_iter().map(|folder| (String::from(folder.id()), Arc::new(folder))).collect(),
+    ));
+
+    let peer_message_hander = Arc::new(PeerMessageHandler::new(folders.clone()));
     
     let client_handler = ClientConnectionHandler::new(peer_message_hander.clone());
     let server = Server::new(client_handler);
-    let peer_id = peer_id();
+    let peer_id = node_config.node_id.clone();
     let rendezvous_server_connection_hander = server.server_connection_loop(
         "127.0.0.1:8080",
         &peer_id,
         broker_sender.clone(),
         available_port.into(),
+        shutdown_listener.clone(),
     );
 
     let accept_address = format!("127.0.0.1:{}", available_port);
-    let peer_server = PeerServer::new(peer_message_hander.clone());
-    let server_handler =  peer_server.accept_loop(accept_address.as_str(), broker_sender.clone());
+    let peer_server = PeerServer::new(peer_id.clone(), peer_message_hander.clone());
+    let server_handler =  peer_server.accept_loop(accept_address.as_str(), broker_sender.clone(), shutdown_listener.clone());
     
-    let file_watch_handler = async_watch(path, broker_sender.clone());
-    let file_handler = FileHandler::new(cmds.folder);
-    let broker = Broker::new(peer_id.clone(),file_handler);
+    let control_server = ControlServer::new(control_socket);
+    let control_handler = control_server.accept_loop(broker_sender.clone(), shutdown_listener.clone());
+
+    let metrics_sender = broker_sender.clone();
+    let metrics_shutdown = shutdown_listener.clone();
+    let metrics_handler = async move {
+        match metrics_addr {
+            Some(metrics_addr) => metrics::serve(metrics_addr, metrics_sender, metrics_shutdown).await,
+            None => Ok(()),
+        }
+    };
+
+    let signal_handler = wait_for_signal(shutdown_trigger, broker_sender.clone());
+    let broker = Broker::new(node_config, config_path, folders, watch_config, broker_sender.clone());
     let broker_handle = broker.broker_loop(broker_receiver);
-    let joined_futures = futures::future::join4(
-        rendezvous_server_connection_hander,
-        server_handler,
-        broker_handle,
-        file_watch_handler,
+    let joined_futures = futures::future::join3(
+        futures::future::join4(
+            rendezvous_server_connection_hander,
+            server_handler,
+            broker_handle,
+            signal_handler,
+        ),
+        control_handler,
+        metrics_handler,
     );
     let _result = task::block_on(joined_futures);
-}
-
-fn peer_id() -> String {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    let peer_id = format!("client_123_{}", since_the_epoch.subsec_millis());
-    peer_id
+    info!("Peer {} stopped", peer_id);
 }
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    prelude::*,
+};
+use futures::{select, FutureExt};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{shutdown::ShutdownListener, spawn_and_log_error, InternalMessage, Result, Sender};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Process wide counters and gauges, rendered in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_sent: Mutex<HashMap<String, u64>>,
+    bytes_received: Mutex<HashMap<String, u64>>,
+    files_synced: AtomicU64,
+    hash_micros: AtomicU64,
+    hash_count: AtomicU64,
+    hash_cache_hits: AtomicU64,
+    watcher_events: AtomicU64,
+    broker_queue_depth: AtomicI64,
+    transfer_failures: AtomicU64,
+    active_connections: AtomicI64,
+    compression_input_bytes: AtomicU64,
+    compression_output_bytes: AtomicU64,
+    compression_micros: AtomicU64,
+    decompression_micros: AtomicU64,
+}
+
+/// Totals of the messages compressed for peers
+#[derive(Debug, Serialize)]
+pub struct CompressionStats {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Output bytes per input byte, 1 before anything was compressed
+    pub ratio: f64,
+    pub compress_seconds: f64,
+    pub decompress_seconds: f64,
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    pub fn add_bytes_sent(&self, peer_id: &str, bytes: u64) {
+        add_for_peer(&self.bytes_sent, peer_id, bytes);
+    }
+
+    pub fn add_bytes_received(&self, peer_id: &str, bytes: u64) {
+        add_for_peer(&self.bytes_received, peer_id, bytes);
+    }
+
+    pub fn file_synced(&self) {
+        self.files_synced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_hash(&self, elapsed: Duration) {
+        self.hash_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.hash_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hash_cache_hit(&self) {
+        self.hash_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn watcher_event(&self) {
+        self.watcher_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_broker_queue_depth(&self, depth: usize) {
+        self.broker_queue_depth.store(depth as i64, Ordering::Relaxed);
+    }
+
+    pub fn transfer_failed(&self) {
+        self.transfer_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_active_connections(&self, connections: usize) {
+        self.active_connections.store(connections as i64, Ordering::Relaxed);
+    }
+
+    /// Counts a message compressed for a peer, also when it was sent uncompressed because it did not get smaller
+    pub fn observe_compression(&self, input_bytes: u64, output_bytes: u64, elapsed: Duration) {
+        self.compression_input_bytes.fetch_add(input_bytes, Ordering::Relaxed);
+        self.compression_output_bytes.fetch_add(output_bytes, Ordering::Relaxed);
+        self.compression_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_decompression(&self, elapsed: Duration) {
+        self.decompression_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn compression(&self) -> CompressionStats {
+        let input_bytes = self.compression_input_bytes.load(Ordering::Relaxed);
+        let output_bytes = self.compression_output_bytes.load(Ordering::Relaxed);
+        CompressionStats {
+            input_bytes,
+            output_bytes,
+            ratio: if input_bytes == 0 { 1.0 } else { output_bytes as f64 / input_bytes as f64 },
+            compress_seconds: self.compression_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            decompress_seconds: self.decompression_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_per_peer(&mut out, "decen_peer_bytes_sent_total", "Bytes sent to a peer", &self.bytes_sent);
+        render_per_peer(&mut out, "decen_peer_bytes_received_total", "Bytes received from a peer", &self.bytes_received);
+        render_metric(&mut out, "decen_peer_files_synced_total", "Files fully received from peers", "counter", self.files_synced.load(Ordering::Relaxed));
+        render_metric(
+            &mut out,
+            "decen_peer_hash_seconds_total",
+            "Time spent hashing files",
+            "counter",
+            self.hash_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        );
+        render_metric(&mut out, "decen_peer_hashed_files_total", "Files hashed", "counter", self.hash_count.load(Ordering::Relaxed));
+        render_metric(
+            &mut out,
+            "decen_peer_hash_cache_hits_total",
+            "Hashes taken from the cache instead of reading the file",
+            "counter",
+            self.hash_cache_hits.load(Ordering::Relaxed),
+        );
+        render_metric(&mut out, "decen_peer_watcher_events_total", "Filesystem events received from the watcher", "counter", self.watcher_events.load(Ordering::Relaxed));
+        render_metric(&mut out, "decen_peer_broker_queue_depth", "Messages waiting in the broker channel", "gauge", self.broker_queue_depth.load(Ordering::Relaxed));
+        render_metric(&mut out, "decen_peer_transfer_failures_total", "Transfer messages that could not be handled", "counter", self.transfer_failures.load(Ordering::Relaxed));
+        render_metric(&mut out, "decen_peer_active_connections", "Connected peers", "gauge", self.active_connections.load(Ordering::Relaxed));
+        let compression = self.compression();
+        render_metric(&mut out, "decen_peer_compression_input_bytes_total", "Bytes of messages before compression", "counter", compression.input_bytes);
+        render_metric(&mut out, "decen_peer_compression_output_bytes_total", "Bytes of messages after compression", "counter", compression.output_bytes);
+        render_metric(&mut out, "decen_peer_compression_ratio", "Compressed size per uncompressed byte", "gauge", compression.ratio);
+        render_metric(&mut out, "decen_peer_compression_seconds_total", "Time spent compressing messages", "counter", compression.compress_seconds);
+        render_metric(&mut out, "decen_peer_decompression_seconds_total", "Time spent decompressing messages", "counter", compression.decompress_seconds);
+        out
+    }
+}
+
+fn add_for_peer(values: &Mutex<HashMap<String, u64>>, peer_id: &str, bytes: u64) {
+    let mut values = values.lock().unwrap();
+    match values.get_mut(peer_id) {
+        Some(value) => *value += bytes,
+        None => {
+            values.insert(String::from(peer_id), bytes);
+        }
+    }
+}
+
+fn render_metric(out: &mut String, name: &str, help: &str, kind: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+}
+
+fn render_per_peer(out: &mut String, name: &str, help: &str, values: &Mutex<HashMap<String, u64>>) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (peer_id, value) in values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{peer=\"{}\"}} {}", name, peer_id.replace('\\', "\\\\").replace('"', "\\\""), value);
+    }
+}
+
+///
+/// Serves `GET /metrics` over plain HTTP until shutdown
+/// # Arguments
+/// * `addr` - Local address to listen on
+/// * `broker_sender` - Only used to read how many messages wait for the broker, also updated by
+///   the broker for every message it takes
+///
+pub async fn serve(addr: impl ToSocketAddrs, broker_sender: Sender<InternalMessage>, shutdown: ShutdownListener) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics listening on {}", listener.local_addr()?);
+    let mut incoming = listener.incoming().fuse();
+    let mut shutdown = shutdown.fuse();
+    loop {
+        let stream = select! {
+            stream = incoming.next().fuse() => match stream {
+                Some(stream) => stream?,
+                None => break,
+            },
+            _ = shutdown => break,
+        };
+        metrics().set_broker_queue_depth(broker_sender.len());
+        spawn_and_log_error(metrics_connection(stream));
+    }
+    Ok(())
+}
+
+async fn metrics_connection(stream: TcpStream) -> Result<()> {
+    let mut lines = BufReader::new(&stream).lines();
+    let request_line = match lines.next().await {
+        Some(line) => line?,
+        None => return Ok(()),
+    };
+    // Headers are not needed, read them so the client sees a complete exchange
+    while let Some(line) = lines.next().await {
+        if line?.is_empty() {
+            break;
+        }
+    }
+
+    let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", ..] => {
+            let body = metrics().render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => {
+            warn!("Unexpected metrics request {}", request_line);
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        }
+    };
+    (&stream).write_all(response.as_bytes()).await?;
+    Ok(())
+}
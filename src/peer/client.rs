@@ -1,27 +1,25 @@
 extern crate async_std;
 extern crate futures;
-use crate::{PeerMessageHandler, InternalMessage, Result, Sender};
-use crate::{peer::Command, peer::PeerMessage};
+use crate::{limit::{limiter, Direction}, metrics::metrics, PeerMessageHandler, InternalMessage, Result, Sender};
+use crate::{peer::{compression::SUPPORTED, read_message, Command, PeerMessage}};
 use async_std::{
-    io::{stdin, BufReader},
+    io::BufReader,
     net::{TcpStream, ToSocketAddrs},
     prelude::*,
 };
-use futures::{select, FutureExt, SinkExt};
-use std::rc::Rc;
+use log::debug;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 
 #[derive(Debug)]
 pub struct ClientConnectionHandler {
-    peer_message_hander: Rc<PeerMessageHandler>,
+    peer_message_hander: Arc<PeerMessageHandler>,
 }
 
 impl ClientConnectionHandler {
     
-    pub fn new(peer_message_hander: Rc<PeerMessageHandler>) -> Self {
+    pub fn new(peer_message_hander: Arc<PeerMessageHandler>) -> Self {
         ClientConnectionHandler{peer_message_hander}
     }
 
@@ -29,27 +27,31 @@ impl ClientConnectionHandler {
 
 impl ClientConnectionHandler {
     
+    ///
+    /// Connects to a peer announced by the rendezvous server and handles its messages until it disconnects
+    /// # Arguments
+    /// * `addr` - Address of the remote peer
+    /// * `my_peer_id` - Id of this node, sent in the Connect command
+    /// * `remote_peer_id` - Id the remote peer registered with the rendezvous server
+    /// * `broker_sender` - Sender to the broker
+    ///
     pub async fn client_connection(
         &self,
         addr: impl ToSocketAddrs,
+        my_peer_id: String,
+        remote_peer_id: String,
         mut broker_sender: Sender<InternalMessage>,
     ) -> Result<()> {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let peer_id = format!("client_123_{}", since_the_epoch.subsec_millis());
-    
         let stream = TcpStream::connect(addr).await?;
         let stream = Arc::new(stream);
         let (reader, mut writer) = (&*stream.clone(), &*stream.clone()); // 1
-        let mut lines_from_server = BufReader::new(reader).lines().fuse();
-        let mut lines_from_stdin = BufReader::new(stdin()).lines().fuse();
+        let mut reader = BufReader::new(reader);
     
         let connect_command = Command::Connect {
             id: Uuid::new_v4(),
-            client_id: peer_id.clone(),
+            client_id: my_peer_id.clone(),
             port: 123,
+            compression: SUPPORTED.to_vec(),
         };
         let pessage = PeerMessage::PeerCommand {
             command: connect_command,
@@ -62,37 +64,28 @@ impl ClientConnectionHandler {
         broker_sender
             .send(InternalMessage::NewPeer {
                 id: Uuid::new_v4(),
-                peer_id: peer_id.clone(),
+                peer_id: remote_peer_id.clone(),
                 address: String::from("123"),
                 port: 0,
                 stream: Arc::clone(&stream),
+                // Sent uncompressed until the peer answers with the codec to use
+                compression: None,
             })
             .await
-            .unwrap();
-    
-        loop {
-            select! { // 3
-                line = lines_from_server.next().fuse() => match line {
-                    Some(line) => {
-                        let line = line?;
-                        self.peer_message_hander.handle_peer_message(line, &mut broker_sender).await?;
-                    },
-                    None => break,
-                },
-                line = lines_from_stdin.next().fuse() => match line {
-                    Some(line) => {
-                        let line = line?;
+            .map_err(|_| "The broker stopped")?;
     
-                        let test_command = Command::Test{id: Uuid::new_v4(),peer_id: peer_id.clone(), message: String::from(line)};
-                        let test_message = PeerMessage::PeerCommand{ command:  test_command};
-                        let command_json = serde_json::to_string(&test_message).unwrap();
-                        // send_message
-                        writer.write_all(command_json.as_bytes()).await?;
-                        writer.write_all(b"\n").await?;
-                    }
-                    None => break,
-                }
-            }
+        let address = stream.peer_addr().ok().map(|address| address.ip());
+        while let Some(line) = read_message(&mut reader).await {
+            let line = line?;
+            metrics().add_bytes_received(&remote_peer_id, line.len() as u64 + 1);
+            // Reading slower makes TCP slow the peer down
+            limiter().acquire(Direction::Download, &remote_peer_id, address, line.len() as u64 + 1).await;
+            self.peer_message_hander.handle_peer_message(line, &remote_peer_id, &mut broker_sender).await?;
+        }
+
+        let leave = InternalMessage::LeavePeer { id: Uuid::new_v4(), peer_id: remote_peer_id.clone() };
+        if broker_sender.send(leave).await.is_err() {
+            debug!("Not reporting that {} left, the broker stopped", remote_peer_id);
         }
         Ok(())
     }
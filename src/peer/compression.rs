@@ -0,0 +1,149 @@
+use std::time::Instant;
+
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::metrics, peer::{PeerMessage, MAX_MESSAGE_SIZE}, Result};
+
+/// Messages shorter than this are sent as they are, compressing them saves nothing
+const MIN_COMPRESS_SIZE: usize = 512;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codecs for message bodies, a connection uses one both ends support
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+/// Every codec this node supports, the preferred one first
+pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+///
+/// Picks the codec for a connection
+/// # Arguments
+/// * `offered` - Codecs the other peer advertised in its handshake
+///
+/// # Returns
+/// * `Option<Compression>` - The preferred codec both peers support, None to send uncompressed
+///
+pub fn negotiate(offered: &[Compression]) -> Option<Compression> {
+    SUPPORTED.into_iter().find(|compression| offered.contains(compression))
+}
+
+///
+/// Wraps a serialized `PeerMessage` in a `PeerMessage::Compressed` if that makes it smaller
+/// # Arguments
+/// * `compression` - Codec negotiated for the connection
+/// * `message_json` - The message as it would be sent uncompressed
+///
+/// # Returns
+/// * `String` - The line to send
+///
+pub fn compress_message(compression: Compression, message_json: String) -> String {
+    if message_json.len() < MIN_COMPRESS_SIZE {
+        return message_json;
+    }
+    let started = Instant::now();
+    let compressed = match compression {
+        Compression::Zstd => match zstd::bulk::compress(message_json.as_bytes(), ZSTD_LEVEL) {
+            Ok(compressed) => compressed,
+            Err(_) => return message_json,
+        },
+        Compression::Lz4 => lz4_flex::compress_prepend_size(message_json.as_bytes()),
+    };
+    let data = BASE64.encode(&compressed);
+    metrics().observe_compression(message_json.len() as u64, data.len() as u64, started.elapsed());
+    if data.len() >= message_json.len() {
+        return message_json;
+    }
+    match serde_json::to_string(&PeerMessage::Compressed { compression, data }) {
+        Ok(compressed_json) => compressed_json,
+        Err(_) => message_json,
+    }
+}
+
+///
+/// Restores the message a peer compressed
+/// # Arguments
+/// * `data` - Base64 of the compressed message
+///
+/// # Returns
+/// * `String` - The message as it was before compression, an error when it would be larger than
+///   `MAX_MESSAGE_SIZE`
+///
+pub fn decompress_message(compression: Compression, data: &str) -> Result<String> {
+    decompress_limited(compression, data, MAX_MESSAGE_SIZE)
+}
+
+/// Decompresses at most `limit` bytes, a few bytes from a peer could otherwise fill the memory
+fn decompress_limited(compression: Compression, data: &str, limit: usize) -> Result<String> {
+    let started = Instant::now();
+    let compressed = BASE64.decode(data.as_bytes())?;
+    let message = match compression {
+        Compression::Zstd => zstd::bulk::decompress(&compressed, limit)?,
+        Compression::Lz4 => {
+            let size = compressed.get(..4).ok_or("LZ4 message without its size")?;
+            let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+            if size > limit {
+                Err(format!("LZ4 message of {} bytes is larger than {} bytes", size, limit))?;
+            }
+            lz4_flex::decompress_size_prepended(&compressed)?
+        }
+    };
+    metrics().observe_decompression(started.elapsed());
+    Ok(String::from_utf8(message)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(size: usize) -> String {
+        format!("{{\"data\":\"{}\"}}", "abc".repeat(size / 3))
+    }
+
+    fn compressed_data(line: &str) -> (Compression, String) {
+        match serde_json::from_str(line).unwrap() {
+            PeerMessage::Compressed { compression, data } => (compression, data),
+            _ => panic!("not compressed"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for compression in SUPPORTED {
+            let original = message(10_000);
+            let line = compress_message(compression, original.clone());
+            assert!(line.len() < original.len());
+            let (used, data) = compressed_data(&line);
+            assert_eq!(used, compression);
+            assert_eq!(decompress_message(compression, &data).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn small_messages_stay_uncompressed() {
+        let original = message(100);
+        assert_eq!(compress_message(Compression::Zstd, original.clone()), original);
+    }
+
+    #[test]
+    fn output_is_limited() {
+        for compression in SUPPORTED {
+            let (_, data) = compressed_data(&compress_message(compression, message(10_000)));
+            assert!(decompress_limited(compression, &data, 20_000).is_ok());
+            assert!(decompress_limited(compression, &data, 5_000).is_err());
+        }
+    }
+
+    #[test]
+    fn lz4_size_is_checked_before_decompressing() {
+        let mut bomb = (u32::MAX).to_le_bytes().to_vec();
+        bomb.extend_from_slice(&[0; 16]);
+        let error = decompress_message(Compression::Lz4, &BASE64.encode(&bomb)).unwrap_err();
+        assert!(error.to_string().contains("larger than"));
+        assert!(decompress_message(Compression::Lz4, &BASE64.encode(&[1, 2])).is_err());
+    }
+}
@@ -1,15 +1,79 @@
 pub mod client;
+pub mod compression;
 
-use async_std::net::TcpStream;
+use async_std::{
+    io::{self, BufRead},
+    net::TcpStream,
+    prelude::*,
+};
+use crate::{
+    io::{
+        chunk::Chunk,
+        delta::{BlockSignature, DeltaInstruction},
+        hash::HashAlgorithm,
+        metadata::FileMetadata,
+    },
+    Sender,
+};
+use compression::Compression;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use uuid::Uuid;
 
+/// Largest message a peer may send, the manifest of a file of tens of gigabytes still fits
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+///
+/// Reads the next line a peer sent, without buffering more than `MAX_MESSAGE_SIZE` of it
+/// # Arguments
+/// * `reader` - Reader of the connection
+///
+/// # Returns
+/// * `Option<io::Result<String>>` - None once the peer disconnected, `InvalidData` for a line that
+///   is too long or not UTF-8, after which the connection is out of step and must be dropped
+///
+pub async fn read_message<R: BufRead + Unpin>(reader: &mut R) -> Option<io::Result<String>> {
+    read_line_limited(reader, MAX_MESSAGE_SIZE).await
+}
+
+async fn read_line_limited<R: BufRead + Unpin>(reader: &mut R, limit: usize) -> Option<io::Result<String>> {
+    let mut line = vec![];
+    match reader.take(limit as u64 + 1).read_until(b'\n', &mut line).await {
+        Ok(0) => return None,
+        Ok(_) => (),
+        Err(err) => return Some(Err(err)),
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > limit {
+        let err = format!("Message longer than {} bytes", limit);
+        return Some(Err(io::Error::new(io::ErrorKind::InvalidData, err)));
+    }
+    Some(String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)))
+}
+
 pub struct Peer {
     pub peer_id: String,
     pub address: String,
     pub port: i32,
     pub stream: Arc<TcpStream>,
+    /// Codec for the messages sent to the peer, None until both ends agreed on one
+    pub compression: Option<Compression>,
+    /// Messages for the writer task of the peer that do not wait for the upload limit
+    pub control: Sender<Outgoing>,
+    /// File data for the writer task of the peer, sent within the upload limit
+    pub data: Sender<String>,
+}
+
+/// A message the writer task of a peer sends before any file data
+#[derive(Debug)]
+pub enum Outgoing {
+    Message(String),
+    /// Sent last, the connection is closed after it
+    Leave(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,6 +82,9 @@ pub enum Command {
         id: Uuid,
         client_id: String,
         port: i32,
+        /// Codecs the connecting peer can decompress, none for peers without compression
+        #[serde(default)]
+        compression: Vec<Compression>,
     },
     Leave {
         id: Uuid,
@@ -26,27 +93,98 @@ pub enum Command {
     CreateNewFile {
         id: Uuid,
         peer_id: String,
+        folder_id: String,
         file_path: String,
         sha: String,
+        /// Missing from peers older than the control API
+        #[serde(default)]
+        size: u64,
+        /// Empty from peers that only sync content
+        #[serde(default)]
+        metadata: FileMetadata,
     },
     CreateFolder {
         id: Uuid,
         peer_id: String,
+        folder_id: String,
         folder_path: String,
     },
     ModifyFile {
         id: Uuid,
         peer_id: String,
+        folder_id: String,
         file_path: String,
+        /// Both missing from peers older than the control API
+        #[serde(default)]
+        sha: String,
+        #[serde(default)]
+        size: u64,
+        #[serde(default)]
+        metadata: FileMetadata,
+    },
+    /// Permissions or modification time of a file changed, its content `sha` did not
+    ModifyMetadata {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        sha: String,
+        metadata: FileMetadata,
+    },
+    /// Asks for a delta against the receiver's copy of a modified file
+    SignatureCommand {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        block_size: usize,
+        signatures: Vec<BlockSignature>,
+    },
+    /// Next instructions to rebuild a modified file, `last` once the whole file was described
+    DeltaCommand {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        sha: String,
+        block_size: usize,
+        instructions: Vec<DeltaInstruction>,
+        last: bool,
+    },
+    /// Asks for the chunks of a file, to build it from local chunks where possible
+    ManifestRequestCommand {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+    },
+    ManifestCommand {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        sha: String,
+        size: u64,
+        chunks: Vec<Chunk>,
+    },
+    /// Asks for the data of the chunks the receiver does not have, answered with `WriteDataCommand`s
+    ChunkRequestCommand {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        chunks: Vec<Chunk>,
     },
     DataRequestCommand {
         id: Uuid,
         peer_id: String,
+        folder_id: String,
         file_path: String,
     },
     WriteDataCommand {
         id: Uuid,
         peer_id: String,
+        folder_id: String,
         file_path: String,
         offset: u64,
         data: Vec<u8>,
@@ -56,14 +194,65 @@ pub enum Command {
         peer_id: String,
         message: String,
     },
+    /// A file or every file below a folder was deleted, `sha` is the version the sender last had.
+    /// A receiver whose copy changed since keeps it.
+    DeleteFile {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+        sha: String,
+    },
+    /// Answers a request for the content of a file the sender only has a placeholder of, the
+    /// receiver asks another peer
+    ContentUnavailable {
+        id: Uuid,
+        peer_id: String,
+        folder_id: String,
+        file_path: String,
+    },
+    /// How the sender hashes every folder it shares with the receiver, sent before anything else
+    /// on a new connection and again when a folder is added
+    HashAlgorithms {
+        id: Uuid,
+        peer_id: String,
+        hashes: HashMap<String, HashAlgorithm>,
+    },
+}
+
+impl Command {
+    /// The peer the command says it comes from
+    pub fn sender(&self) -> &str {
+        match self {
+            Command::Connect { client_id, .. } | Command::Leave { client_id, .. } => client_id,
+            Command::CreateNewFile { peer_id, .. }
+            | Command::CreateFolder { peer_id, .. }
+            | Command::ModifyFile { peer_id, .. }
+            | Command::ModifyMetadata { peer_id, .. }
+            | Command::SignatureCommand { peer_id, .. }
+            | Command::DeltaCommand { peer_id, .. }
+            | Command::ManifestRequestCommand { peer_id, .. }
+            | Command::ManifestCommand { peer_id, .. }
+            | Command::ChunkRequestCommand { peer_id, .. }
+            | Command::DataRequestCommand { peer_id, .. }
+            | Command::WriteDataCommand { peer_id, .. }
+            | Command::Test { peer_id, .. }
+            | Command::DeleteFile { peer_id, .. }
+            | Command::ContentUnavailable { peer_id, .. }
+            | Command::HashAlgorithms { peer_id, .. } => peer_id,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Event {
+    /// Answers `Connect` with the codec both peers use from now on
     Connected {
         id: Uuid,
         client_id: String,
         port: i32,
+        #[serde(default)]
+        compression: Option<Compression>,
     },
     Left {
         id: Uuid,
@@ -71,9 +260,51 @@ pub enum Event {
     },
 }
 
+impl Event {
+    /// The peer the event says it comes from
+    pub fn sender(&self) -> &str {
+        match self {
+            Event::Connected { client_id, .. } | Event::Left { client_id, .. } => client_id,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "peer_message")]
 pub enum PeerMessage {
     PeerCommand { command: Command },
     PeerEvent { event: Event },
+    /// Another message compressed with the codec of the connection, its data in base64
+    Compressed { compression: Compression, data: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{io::{BufReader, Cursor}, task};
+
+    #[test]
+    fn messages_are_read_line_by_line() {
+        task::block_on(async {
+            let mut reader = BufReader::new(Cursor::new(b"first\nsecond\r\nlast".to_vec()));
+            assert_eq!(read_message(&mut reader).await.unwrap().unwrap(), "first");
+            assert_eq!(read_message(&mut reader).await.unwrap().unwrap(), "second");
+            assert_eq!(read_message(&mut reader).await.unwrap().unwrap(), "last");
+            assert!(read_message(&mut reader).await.is_none());
+        });
+    }
+
+    #[test]
+    fn messages_longer_than_the_limit_are_refused() {
+        task::block_on(async {
+            let mut reader = BufReader::new(Cursor::new(b"abcd\nabcde\n".to_vec()));
+            assert_eq!(read_line_limited(&mut reader, 4).await.unwrap().unwrap(), "abcd");
+            let err = read_line_limited(&mut reader, 4).await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            let mut reader = BufReader::new(Cursor::new(vec![0xff, b'\n']));
+            let err = read_message(&mut reader).await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
 }
@@ -1,18 +1,21 @@
 use std::sync::Arc;
 
 use async_std::{
-    io::{stdin, BufReader},
+    io::BufReader,
     net::{TcpStream, ToSocketAddrs},
     prelude::*,
 };
 
-use futures::{select, FutureExt, SinkExt};
+use futures::{select, FutureExt};
 
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{peer::client::ClientConnectionHandler, InternalMessage, Result, Sender};
+use crate::{
+    peer::client::ClientConnectionHandler, shutdown::ShutdownListener, spawn_and_log_error,
+    InternalMessage, Result, Sender,
+};
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientCommand {
@@ -63,14 +66,15 @@ impl Server {
     pub async fn server_connection_loop(
         &self,
         addr: impl ToSocketAddrs,
-        client_id: &String,
-        mut broker_sender: Sender<InternalMessage>,
+        client_id: &str,
+        broker_sender: Sender<InternalMessage>,
         available_port: i32,
+        shutdown: ShutdownListener,
     ) -> Result<()> {
         let stream = TcpStream::connect(addr).await?;
         let join_client_command = ClientCommand::ConnectClient {
             id: Uuid::new_v4().to_string(),
-            client_id: client_id.clone(),
+            client_id: client_id.to_string(),
             port: available_port,
         };
         let event_json = serde_json::to_string(&join_client_command)?;
@@ -79,9 +83,8 @@ impl Server {
         send_event(event_json, &mut writer).await?;
         
         let mut lines_from_server = BufReader::new(reader).lines().fuse();
-        let mut lines_from_stdin = BufReader::new(stdin()).lines().fuse();
+        let mut shutdown = shutdown.fuse();
 
-        // let client = self.client.clone();
         loop {
             select! {
                 line = lines_from_server.next().fuse() => match line {
@@ -100,12 +103,16 @@ impl Server {
                                             let client = self.client.clone();
                                             info!("Received peer: {}",peer.peer_id);
                                             let peer_address = format!("{}:{}",peer.address,peer.port);
-                                            client.clone().client_connection(peer_address, broker_sender.clone()).await.unwrap();
+                                            let my_peer_id = client_id.to_string();
+                                            let broker_sender = broker_sender.clone();
+                                            spawn_and_log_error(async move {
+                                                client.client_connection(peer_address, my_peer_id, peer.peer_id, broker_sender).await
+                                            });
                                         }
                                     },
                                     ClientEvent::ClientLeft{id,client_id} => {
                                         info!("Received peer leave event for peer: {}",client_id);
-                                        let peer_leave_message = InternalMessage::LeavePeer{id: id.clone(),peer_id:client_id};
+                                        let peer_leave_message = InternalMessage::LeavePeer{id,peer_id:client_id};
                                         broker_sender.send(peer_leave_message).await.unwrap();
                                     },
                                 }
@@ -114,16 +121,10 @@ impl Server {
                     },
                     None => break,
                 },
-                line = lines_from_stdin.next().fuse() => match line {
-                    Some(line) => {
-                        let line = line?;
-                        if line.eq("EXIT") {
-                            send_exit_event(client_id.clone(), stream.clone()).await?;
-                            break;
-                        }
-                    }
-                    None => break,
-                }
+                _ = shutdown => {
+                    send_exit_event(client_id.to_string(), stream.clone()).await?;
+                    break;
+                },
             }
         }
         Ok(())
@@ -136,7 +137,7 @@ async fn send_exit_event(client_id: String, stream: TcpStream) -> Result<()> {
     let id = Uuid::new_v4();
     let leave_command = ClientCommand::LeaveClient {
         id: id.to_string(),
-        client_id: client_id.clone(),
+        client_id: client_id.to_string(),
     };
     let event_json = serde_json::to_string(&leave_command)?;
 
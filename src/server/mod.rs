@@ -1,41 +1,52 @@
-use std::{sync::Arc, rc::Rc};
+use std::{io::ErrorKind, net::IpAddr, sync::Arc};
 
 use async_std::{
     io::BufReader,
     net::{TcpListener, TcpStream, ToSocketAddrs},
     prelude::*,
 };
-use futures::SinkExt;
+use futures::{select, FutureExt};
 use log::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::peer::PeerMessage;
+use crate::peer::{compression::{negotiate, Compression}, read_message, Event, PeerMessage};
 
 use super::peer::Command;
-use crate::{PeerMessageHandler, InternalMessage, Result, Sender};
+use crate::{limit::{limiter, Direction}, metrics::metrics, shutdown::ShutdownListener, spawn_and_log_error, PeerMessageHandler, InternalMessage, Result, Sender};
 
 pub struct PeerServer {
-    peer_message_hander: Rc<PeerMessageHandler>,
+    my_peer_id: String,
+    peer_message_hander: Arc<PeerMessageHandler>,
 }
 
 impl PeerServer {
-    pub fn new(peer_message_hander: Rc<PeerMessageHandler>) -> Self {
-        PeerServer { peer_message_hander }
+    pub fn new(my_peer_id: String, peer_message_hander: Arc<PeerMessageHandler>) -> Self {
+        PeerServer { my_peer_id, peer_message_hander }
     }
 }
 
 impl PeerServer {
     
-    pub async fn accept_loop(self,addr: impl ToSocketAddrs, broker_sender: Sender<InternalMessage>) -> Result<()> {
+    pub async fn accept_loop(self,addr: impl ToSocketAddrs, broker_sender: Sender<InternalMessage>, shutdown: ShutdownListener) -> Result<()> {
         info!("Start accepting incomming connections");
         let listener = TcpListener::bind(addr).await?;
-        let mut incoming = listener.incoming();
+        let mut incoming = listener.incoming().fuse();
+        let mut shutdown = shutdown.fuse();
         let arc_self = Arc::new(self);
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
+        loop {
+            let stream = select! {
+                stream = incoming.next().fuse() => match stream {
+                    Some(stream) => stream?,
+                    None => break,
+                },
+                _ = shutdown => {
+                    info!("Stop accepting incomming connections");
+                    break;
+                },
+            };
             info!("Accepting from: {}", stream.peer_addr()?);
             let arc_self = arc_self.clone();
-            PeerServer::connection_loop(arc_self, broker_sender.clone(), stream).await.unwrap();
+            spawn_and_log_error(PeerServer::connection_loop(arc_self, broker_sender.clone(), stream));
         }
         drop(broker_sender);
         Ok(())
@@ -44,11 +55,9 @@ impl PeerServer {
     async fn connection_loop(peer_server: Arc<PeerServer>,broker: Sender<InternalMessage>, stream: TcpStream) -> Result<()> {
         let stream = Arc::new(stream);
         let addr = stream.peer_addr();
-        let reader = BufReader::new(&*stream);
-    
-        let mut lines = reader.lines();
+        let mut reader = BufReader::new(&*stream);
     
-        let message = match lines.next().await {
+        let message = match read_message(&mut reader).await {
             None => Err("peer disconnected immediately")?,
             Some(line) => line?,
         };
@@ -57,10 +66,17 @@ impl PeerServer {
             Err(..) => Err("Cannot get peer address")?,
             Ok(address) => address.ip().to_string(),
         };
-        let (id, client_id, port) = peer_server.extract_first_message(&message)?;
+        let (id, client_id, port, offered) = peer_server.extract_first_message(&message)?;
     
         debug!("Receive new ConnectClient id :{:?} peer:{:}", id, client_id);
-        let mut connection_broker = broker.clone();
+        let compression = negotiate(&offered);
+        let connected = PeerMessage::PeerEvent {
+            event: Event::Connected { id, client_id: peer_server.my_peer_id.clone(), port: 0, compression },
+        };
+        let connected_json = serde_json::to_string(&connected)?;
+        (&*stream).write_all(connected_json.as_bytes()).await?;
+        (&*stream).write_all(b"\n").await?;
+        let connection_broker = broker.clone();
         connection_broker
             .send(InternalMessage::NewPeer {
                 id,
@@ -68,33 +84,35 @@ impl PeerServer {
                 address: addr,
                 port,
                 stream: Arc::clone(&stream),
+                compression,
             })
             .await
-            .unwrap();
+            .map_err(|_| "The broker stopped")?;
     
         let error_threshold = 10;
     
-        peer_server.accept_new_messages(lines, client_id.clone(), error_threshold, broker).await?;
+        let address = stream.peer_addr().ok().map(|address| address.ip());
+        peer_server.accept_new_messages(reader, client_id.clone(), address, error_threshold, broker).await?;
     
-        connection_broker
-            .send(InternalMessage::LeavePeer {
-                id: Uuid::new_v4(),
-                peer_id: client_id.clone(),
-            })
-            .await
-            .unwrap();
+        let leave = InternalMessage::LeavePeer { id: Uuid::new_v4(), peer_id: client_id.clone() };
+        if connection_broker.send(leave).await.is_err() {
+            debug!("Not reporting that {} left, the broker stopped", client_id);
+        }
         Ok(())
     }
     
     async fn accept_new_messages(&self,
-        mut lines: async_std::io::Lines<BufReader<&TcpStream>>,
+        mut reader: BufReader<&TcpStream>,
         client_id: String,
+        address: Option<IpAddr>,
         error_threshold: i32,
         mut broker: Sender<InternalMessage>,
     ) -> Result<()> {
         let mut error_count = 0;
-        Ok(while let Some(line) = lines.next().await {
+        while let Some(line) = read_message(&mut reader).await {
             let line = match line {
+                // The rest of the line would be read as the next messages
+                Err(err) if err.kind() == ErrorKind::InvalidData => Err(format!("Dropping {}: {}", client_id, err))?,
                 Err(err) => {
                     warn!("Error {:?} reading line from {:?}", err, client_id);
                     error_count += 1;
@@ -109,13 +127,17 @@ impl PeerServer {
                     message
                 }
             };
+            metrics().add_bytes_received(&client_id, line.len() as u64 + 1);
+            // Reading slower makes TCP slow the peer down
+            limiter().acquire(Direction::Download, &client_id, address, line.len() as u64 + 1).await;
     
-            self.peer_message_hander.handle_peer_message(line, &mut broker).await?;
-        })
+            self.peer_message_hander.handle_peer_message(line, &client_id, &mut broker).await?;
+        }
+        Ok(())
     }
     
-    fn extract_first_message(&self,message: &String) -> Result<(Uuid, String, i32)> {
-        let command = match serde_json::from_str(&message) {
+    fn extract_first_message(&self,message: &str) -> Result<(Uuid, String, i32, Vec<Compression>)> {
+        let command = match serde_json::from_str(message) {
             Err(err) => Err(err)?,
             Ok(message) => match message {
                 PeerMessage::PeerCommand { command } => command,
@@ -123,15 +145,16 @@ impl PeerServer {
             },
         };
     
-        let (id, client_id, port) = match command {
+        let (id, client_id, port, compression) = match command {
             Command::Connect {
                 id,
                 client_id,
                 port,
-            } => (id, client_id, port),
+                compression,
+            } => (id, client_id, port, compression),
             _ => Err("First event wasn't a Connect command")?,
         };
-        Ok((id, client_id, port))
+        Ok((id, client_id, port, compression))
     }
 
 
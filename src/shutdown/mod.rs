@@ -0,0 +1,46 @@
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+    StreamExt,
+};
+use log::info;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use uuid::Uuid;
+
+use crate::{InternalMessage, Result, Sender};
+
+/// Resolves once the node starts shutting down. Cheap to clone, every loop gets its own copy.
+pub type ShutdownListener = Shared<oneshot::Receiver<()>>;
+
+pub type ShutdownTrigger = oneshot::Sender<()>;
+
+pub fn shutdown_channel() -> (ShutdownTrigger, ShutdownListener) {
+    let (trigger, listener) = oneshot::channel();
+    (trigger, listener.shared())
+}
+
+///
+/// Waits for SIGINT or SIGTERM and starts the shutdown sequence
+/// # Arguments
+/// * `trigger` - Fired first, so the accept loop, the watcher and the rendezvous connection stop
+/// * `broker_sender` - Receives `InternalMessage::Shutdown` after every message already queued
+///
+pub async fn wait_for_signal(
+    trigger: ShutdownTrigger,
+    broker_sender: Sender<InternalMessage>,
+) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let handle = signals.handle();
+
+    if let Some(signal) = signals.next().await {
+        info!("Received signal {}, shutting down", signal);
+    }
+    handle.close();
+
+    let _ = trigger.send(());
+    broker_sender
+        .send(InternalMessage::Shutdown { id: Uuid::new_v4() })
+        .await?;
+    Ok(())
+}